@@ -0,0 +1,61 @@
+use std::{env, fmt::Write as _, fs, path::Path};
+
+type Subsystem = (u16, u16, String);
+type Device = (u16, String, Vec<Subsystem>);
+type Vendor = (u16, String, Vec<Device>);
+
+// Turns src/pci/pci.ids into a table of vendors, their devices and their subsystems, sorted by ID for binary search
+fn main() {
+	let source = "src/pci/pci.ids";
+	println!("cargo:rerun-if-changed={}", source);
+	let ids = fs::read_to_string(source).expect("failed to read the PCI ID database");
+
+	let mut vendors: Vec<Vendor> = Vec::new();
+	for (number, line) in ids.lines().enumerate() {
+		if line.trim().is_empty() || line.starts_with('#') {
+			continue;
+		}
+		//the class section that follows the vendors isn't used
+		if line.starts_with("C ") {
+			break;
+		}
+		let error = || -> ! { panic!("{}:{}: malformed line {:?}", source, number + 1, line) };
+		let hex = |id: &str| u16::from_str_radix(id, 16).unwrap_or_else(|_| error());
+		if let Some(subsystem) = line.strip_prefix("\t\t") {
+			let mut fields = subsystem.splitn(3, ' ');
+			let (vendor, device, name) = match (fields.next(), fields.next(), fields.next()) {
+				(Some(vendor), Some(device), Some(name)) => (hex(vendor), hex(device), name.trim().to_string()),
+				_ => error(),
+			};
+			let device_entry = vendors.last_mut().and_then(|vendor| vendor.2.last_mut()).unwrap_or_else(|| error());
+			device_entry.2.push((vendor, device, name));
+		} else if let Some(device) = line.strip_prefix('\t') {
+			let (id, name) = device.split_at(device.find(' ').unwrap_or_else(|| error()));
+			let vendor_entry = vendors.last_mut().unwrap_or_else(|| error());
+			vendor_entry.2.push((hex(id), name.trim().to_string(), Vec::new()));
+		} else {
+			let (id, name) = line.split_at(line.find(' ').unwrap_or_else(|| error()));
+			vendors.push((hex(id), name.trim().to_string(), Vec::new()));
+		}
+	}
+
+	vendors.sort_by_key(|vendor| vendor.0);
+	let mut table = String::from("&[\n");
+	for (id, name, mut devices) in vendors {
+		devices.sort_by_key(|device| device.0);
+		writeln!(table, "\tVendor {{ id: 0x{:04X}, name: {:?}, devices: &[", id, name).unwrap();
+		for (id, name, mut subsystems) in devices {
+			subsystems.sort_by_key(|subsystem| (subsystem.0, subsystem.1));
+			write!(table, "\t\tDevice {{ id: 0x{:04X}, name: {:?}, subsystems: &[", id, name).unwrap();
+			for (vendor, device, name) in subsystems {
+				write!(table, "Subsystem {{ vendor: 0x{:04X}, device: 0x{:04X}, name: {:?} }}, ", vendor, device, name).unwrap();
+			}
+			writeln!(table, "] }},").unwrap();
+		}
+		writeln!(table, "\t] }},").unwrap();
+	}
+	table.push(']');
+
+	let out = Path::new(&env::var("OUT_DIR").unwrap()).join("pci_ids.rs");
+	fs::write(out, table).expect("failed to write the PCI ID table");
+}
@@ -1,41 +1,91 @@
-use x86_64::{VirtAddr, PhysAddr};
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
 use spin::{RwLock};
 
+pub mod aml;
+pub mod gas;
+pub mod power;
+pub mod sci;
 pub mod sdt;
-use sdt::{FADT,MADT,HPET};
+use sdt::FADT;
 
+/// Every table found through the RSDT or XSDT, plus the root table itself and the DSDT
 pub struct Acpi {
-	pub rsdt: RwLock<Option<&'static RSDT>>,
-	pub fadt: RwLock<Option<&'static FADT>>,
-	pub madt: RwLock<Option<&'static MADT>>,
-	pub hpet: RwLock<Option<&'static HPET>>,
+	tables: RwLock<Vec<&'static ACPISDTHeader>>,
 }
 impl core::fmt::Display for Acpi {
 	fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-		writeln!(f, "ACPI table:")?;
-		if let Some(ptr) = *(self.rsdt.read()) {
-			writeln!(f, "rsdt: {:p}", ptr)?;
-		}
-		if let Some(ptr) = *(self.fadt.read()) {
-			writeln!(f, "fadt: {:p}", ptr)?;
-		}
-		if let Some(ptr) = *(self.madt.read()) {
-			writeln!(f, "madt: {:p}", ptr)?;
-		}
-		if let Some(ptr) = *(self.hpet.read()) {
-			writeln!(f, "hpet: {:p}", ptr)?;
+		writeln!(f, "ACPI tables:")?;
+		for header in self.tables.read().iter() {
+			writeln!(f, "{:p} {}", *header, header)?;
 		}
 		Ok(())
 	}
 }
 pub static ACPI: Acpi = Acpi{
-	rsdt: RwLock::new(None),
-	fadt: RwLock::new(None),
-	madt: RwLock::new(None),
-	hpet: RwLock::new(None),
+	tables: RwLock::new(Vec::new()),
 };
 
-#[repr(C)]
+/// A System Description Table that can be looked up by its signature
+/// # Safety
+/// - The implementor must be `repr(C)` or `repr(C, packed)` and start with an `ACPISDTHeader`
+/// - Older table revisions can be shorter than the struct, fields past `header().length()` must not be read
+pub unsafe trait Sdt: Sized + 'static {
+	const SIGNATURE: &'static [u8;4];
+	/// The shortest table that is handed out, tables with older and shorter revisions lower this
+	const MIN_LENGTH: usize = core::mem::size_of::<Self>();
+	fn header(&self) -> &ACPISDTHeader {
+		unsafe {&*(self as *const Self as *const ACPISDTHeader)}
+	}
+}
+
+/// Returns the first table with `T`'s signature
+pub fn find_table<T: Sdt>() -> Option<&'static T> {
+	find_tables::<T>().next()
+}
+
+/// Iterates over every table with `T`'s signature, in the order the firmware lists them
+///
+/// Tables too short to hold a `T` are skipped.
+pub fn find_tables<T: Sdt>() -> impl Iterator<Item = &'static T> {
+	let mut index = 0;
+	//the lock is only held within each step, so the caller can register tables while iterating
+	core::iter::from_fn(move || {
+		let tables = ACPI.tables.read();
+		while let Some(header) = tables.get(index) {
+			index += 1;
+			if let Some(table) = cast_table(header) {
+				return Some(table);
+			}
+		}
+		None
+	})
+}
+
+// Checks that the table is a `T` and long enough to be one
+fn cast_table<T: Sdt>(header: &'static ACPISDTHeader) -> Option<&'static T> {
+	if &header.signature != T::SIGNATURE || header.length() < T::MIN_LENGTH {
+		return None;
+	}
+	Some(unsafe {&*(header as *const ACPISDTHeader as *const T)})
+}
+
+/// Returns the header of every registered table
+pub fn tables() -> Vec<&'static ACPISDTHeader> {
+	ACPI.tables.read().clone()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+	/// Neither the EBDA nor the BIOS area holds an RSDP with a valid checksum
+	RsdpNotFound,
+	/// The table with this signature failed its checksum
+	InvalidChecksum([u8;4]),
+	/// The table with this signature is shorter than its own header
+	InvalidLength([u8;4]),
+}
+
+#[repr(C, packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct RSDPDescriptor {
 	// 8-byte string, MUST equal "RSD PTR "
@@ -47,7 +97,21 @@ pub struct RSDPDescriptor {
 	// If 0, ACPI version 1.0 is used, the value 2 is used for ACPI version 2 to 6.1
 	pub revision: u8,
 	// The physical address to the RSDT table
-	pub rsdt_address: PhysAddr,
+	pub rsdt_address: u32,
+}
+
+/// The RSDP as extended by ACPI 2.0, only valid when `revision` is 2 or higher
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug)]
+pub struct RSDPDescriptor20 {
+	pub first_part: RSDPDescriptor,
+	// The length of the whole structure, including `first_part`
+	pub length: u32,
+	// The physical address to the XSDT table, which should be used instead of the RSDT
+	pub xsdt_address: u64,
+	// Checksum of the whole structure, the first part keeps its own checksum too
+	pub extended_checksum: u8,
+	reserved: [u8;3],
 }
 
 #[repr(C)]
@@ -58,6 +122,9 @@ pub struct RSDT {
 	/// QEMU has three SDT: FACP, APIC and HPET
 	pointer_to_other_sdt: u32,
 }
+unsafe impl Sdt for RSDT {
+	const SIGNATURE: &'static [u8;4] = b"RSDT";
+}
 impl RSDT {
 	pub fn get_num_sdt(&self) -> usize {
 		(self.header.length as usize - core::mem::size_of::<ACPISDTHeader>()) / 4
@@ -67,10 +134,28 @@ impl RSDT {
 	}
 }
 
+/// The ACPI 2.0 replacement for the RSDT, with 64-bit pointers
+#[repr(C)]
+#[derive(Debug)]
+pub struct XSDT {
+	header: ACPISDTHeader,
+	/// An array of u64 in memory, only 4-byte aligned
+	pointer_to_other_sdt: [u32; 2],
+}
+unsafe impl Sdt for XSDT {
+	const SIGNATURE: &'static [u8;4] = b"XSDT";
+}
+impl XSDT {
+	pub fn get_num_sdt(&self) -> usize {
+		(self.header.length as usize - core::mem::size_of::<ACPISDTHeader>()) / 8
+	}
+	pub fn get_sdt_addresses(&self) -> impl Iterator<Item = u64> + '_ {
+		let base = &self.pointer_to_other_sdt as *const [u32; 2] as *const u64;
+		(0..self.get_num_sdt()).map(move |i| unsafe {core::ptr::read_unaligned(base.add(i))})
+	}
+}
+
 const SIGNATURE_RSDP: &[u8;8] = b"RSD PTR ";
-const SIGNATURE_FADT: &[u8;4] = b"FACP";
-const SIGNATURE_MADT: &[u8;4] = b"APIC";
-const SIGNATURE_HPET: &[u8;4] = b"HPET";
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -89,68 +174,146 @@ impl ACPISDTHeader {
 	pub fn as_string(&self) -> &str{
 		core::str::from_utf8(&self.signature).expect("Failed to parse")
 	}
+	/// The length of the whole table, including the header
+	pub fn length(&self) -> usize {
+		self.length as usize
+	}
+	pub fn revision(&self) -> u8 {
+		self.revision
+	}
+	pub fn oem_id(&self) -> &str {
+		ascii(&self.oem_id)
+	}
+	pub fn oem_table_id(&self) -> &str {
+		ascii(&self.oem_table_id)
+	}
+	pub fn oem_revision(&self) -> u32 {
+		self.oem_revision
+	}
+	/// The vendor of the tool that built the table, usually four ASCII characters
+	pub fn creator_id(&self) -> u32 {
+		self.creator_id
+	}
+	pub fn creator_revision(&self) -> u32 {
+		self.creator_revision
+	}
+	/// The bytes that follow the header
+	pub fn data(&self) -> &[u8] {
+		let size = core::mem::size_of::<ACPISDTHeader>();
+		unsafe {core::slice::from_raw_parts((self as *const Self as *const u8).add(size), self.length() - size)}
+	}
+}
+impl core::fmt::Display for ACPISDTHeader {
+	fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+		let creator = self.creator_id.to_le_bytes();
+		write!(f, "{} rev {} length {} OEM {:<6} {:<8} rev {:#x} creator {} rev {:#x}",
+			ascii(&self.signature), self.revision, self.length, self.oem_id(), self.oem_table_id(),
+			self.oem_revision, ascii(&creator), self.creator_revision)
+	}
 }
 
-pub fn get_rsdp(physical_memory_offset: VirtAddr) {
-	//the RSDP is either located in the first 1KB of the EBDA, or in the memory region below 1MiB
-	//we have to search for the "RSD PTR " string (including the trailing space), which is the signature of the RSDPDescriptor struct
-	//this signature is guaranteed to be on a 16-byte boundary
-	for i in (0..0x0010_0000).step_by(16) {
-		let descriptor_ptr: *const RSDPDescriptor = (physical_memory_offset + (i as u64)).as_ptr();
-		let descriptor = unsafe{*descriptor_ptr};
-		let mut success = true;
-		for (i, byte) in SIGNATURE_RSDP.iter().enumerate() {
-			if *byte != descriptor.signature[i] {
-				success = false;
-				break;
+// OEM strings are space or NUL padded, and not guaranteed to be ASCII
+fn ascii(bytes: &[u8]) -> &str {
+	core::str::from_utf8(bytes).map(|s| s.trim_end_matches(&[' ', '\0'][..])).unwrap_or("?")
+}
+
+/// Finds the RSDP and registers every table the RSDT or XSDT points to
+pub fn get_rsdp(physical_memory_offset: VirtAddr) -> Result<(), AcpiError> {
+	let rsdp_addr = find_rsdp(physical_memory_offset).ok_or(AcpiError::RsdpNotFound)?;
+	let descriptor = unsafe {*(rsdp_addr.as_ptr::<RSDPDescriptor>())};
+	let mut tables = Vec::new();
+	let xsdt_address = if descriptor.revision >= 2 {
+		unsafe {*(rsdp_addr.as_ptr::<RSDPDescriptor20>())}.xsdt_address
+	}
+	else {
+		0
+	};
+	//a corrupt XSDT isn't fatal while the RSDT still lists the same tables
+	let xsdt = if xsdt_address != 0 {
+		match validate_sdt(physical_memory_offset + xsdt_address) {
+			Ok(header) => Some(unsafe {&*(header as *const ACPISDTHeader as *const XSDT)}),
+			Err(err) => {
+				crate::println!("Falling back to the RSDT: {:?}", err);
+				None
 			}
 		}
-		if success && rsdp_descriptor_checksum(unsafe{*(descriptor_ptr as *const [u8; 20])}) {
-			let rsdt_header_addr = VirtAddr::new(physical_memory_offset.as_u64() + descriptor.rsdt_address.as_u64());
-			let rsdt_header_ptr: *const RSDT = rsdt_header_addr.as_ptr();
-			let rsdt = unsafe {&*rsdt_header_ptr};
-			{
-				let mut rsdt_lock = ACPI.rsdt.write();
-				*rsdt_lock = Some(rsdt);
-			}
-			if !unsafe{sdt_checksum(rsdt_header_addr, rsdt.header.length as usize)} {
-				crate::println!("Checksum failed!");
-			}
-			for sdt_address in rsdt.get_sdt_addresses() {
-				let sdt_addr = VirtAddr::new(*sdt_address as u64 + physical_memory_offset.as_u64());
-				let sdt_header_ptr: *const ACPISDTHeader = sdt_addr.as_ptr();
-				let header = unsafe{*sdt_header_ptr};
-				match &header.signature {
-					SIGNATURE_HPET => {
-						let sdt_ptr: *const sdt::HPET = sdt_addr.as_ptr();
-						let mut hpet_lock = ACPI.hpet.write();
-						*hpet_lock = Some(unsafe {&*sdt_ptr});
-					},
-					SIGNATURE_FADT => {
-						let sdt_ptr: *const sdt::FADT = sdt_addr.as_ptr();
-						let mut fadt_lock = ACPI.fadt.write();
-						*fadt_lock = Some(unsafe {&*sdt_ptr});
-					},
-					SIGNATURE_MADT => {
-						let sdt_ptr: *const sdt::MADT = sdt_addr.as_ptr();
-						let mut madt_lock = ACPI.madt.write();
-						*madt_lock = Some(unsafe {&*sdt_ptr});
-					},
-					_ => crate::println!("Found unknown SDT: {}", header.as_string())
-				}
-			}
-			return;
+	}
+	else {
+		None
+	};
+	if let Some(xsdt) = xsdt {
+		tables.push(xsdt.header());
+		for sdt_address in xsdt.get_sdt_addresses() {
+			register_sdt(&mut tables, physical_memory_offset + sdt_address);
+		}
+	}
+	else {
+		let rsdt = unsafe {&*(validate_sdt(physical_memory_offset + descriptor.rsdt_address as u64)? as *const ACPISDTHeader as *const RSDT)};
+		tables.push(rsdt.header());
+		for sdt_address in rsdt.get_sdt_addresses() {
+			register_sdt(&mut tables, physical_memory_offset + *sdt_address as u64);
 		}
 	}
-	panic!("No RSDP signature found!")
+
+	//the DSDT isn't listed in the root table, only the FADT points to it
+	let dsdt_address = tables.iter()
+		.find(|header| &header.signature == FADT::SIGNATURE)
+		.map(|header| unsafe {&*(*header as *const ACPISDTHeader as *const FADT)}.get_dsdt_address());
+	if let Some(dsdt_address) = dsdt_address {
+		register_sdt(&mut tables, physical_memory_offset + dsdt_address);
+	}
+	*ACPI.tables.write() = tables;
+	Ok(())
 }
 
-fn rsdp_descriptor_checksum(table_bytes: [u8; 20]) -> bool {
-	let mut sum = 0u32;
-	for byte in table_bytes.iter() {
-		sum += *byte as u32;
+// Adds a table to the registry, skipping it with a warning if it is corrupt
+fn register_sdt(tables: &mut Vec<&'static ACPISDTHeader>, sdt_addr: VirtAddr) {
+	match validate_sdt(sdt_addr) {
+		Ok(header) => tables.push(header),
+		Err(err) => crate::println!("Skipping ACPI table: {:?}", err),
 	}
-	sum.trailing_zeros() >= 8
+}
+
+// Checks the length and checksum of the table at `sdt_addr`
+fn validate_sdt(sdt_addr: VirtAddr) -> Result<&'static ACPISDTHeader, AcpiError> {
+	let header = unsafe {&*(sdt_addr.as_ptr::<ACPISDTHeader>())};
+	if header.length() < core::mem::size_of::<ACPISDTHeader>() {
+		return Err(AcpiError::InvalidLength(header.signature));
+	}
+	if !unsafe{sdt_checksum(sdt_addr, header.length())} {
+		return Err(AcpiError::InvalidChecksum(header.signature));
+	}
+	Ok(header)
+}
+
+/// Returns the virtual address of a valid RSDP
+fn find_rsdp(physical_memory_offset: VirtAddr) -> Option<VirtAddr> {
+	//the RSDP is either located in the first 1KB of the EBDA, or in the BIOS area from 0xE0000 to 0xFFFFF
+	//the BDA holds the EBDA's real mode segment at 0x40E
+	let ebda_segment = unsafe {*((physical_memory_offset + 0x40Eu64).as_ptr::<u16>())};
+	let ebda = (ebda_segment as u64) << 4;
+	let ebda_region = if ebda != 0 {Some((ebda, ebda + 0x400))} else {None};
+	ebda_region.into_iter()
+		.chain(core::iter::once((0xE_0000, 0x10_0000)))
+		.flat_map(|(start, end)| (start..end).step_by(16))
+		.map(|addr| physical_memory_offset + addr)
+		.find(|addr| unsafe {is_valid_rsdp(*addr)})
+}
+
+/// Checks the signature and checksums of a potential RSDP
+/// # Safety
+/// - It must be valid to read 20 bytes from `addr`, and 36 if they hold a revision 2 RSDP
+unsafe fn is_valid_rsdp(addr: VirtAddr) -> bool {
+	//the "RSD PTR " signature, including the trailing space, is guaranteed to be on a 16-byte boundary
+	let descriptor = *(addr.as_ptr::<RSDPDescriptor>());
+	if &descriptor.signature != SIGNATURE_RSDP || !sdt_checksum(addr, core::mem::size_of::<RSDPDescriptor>()) {
+		return false;
+	}
+	if descriptor.revision >= 2 {
+		let length = (*(addr.as_ptr::<RSDPDescriptor20>())).length as usize;
+		return length >= core::mem::size_of::<RSDPDescriptor20>() && sdt_checksum(addr, length);
+	}
+	true
 }
 
 /// Validates the checksum of a System Description Table
@@ -163,4 +326,31 @@ unsafe fn sdt_checksum(table_bytes_base_addr: VirtAddr, size: usize) -> bool {
 		sum += (*byte) as u32;
 	}
 	sum.trailing_zeros() >= 8
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_cast_table() {
+	serial_print!("test_cast_table... ");
+	#[repr(C)]
+	struct Table {
+		header: ACPISDTHeader,
+		data: [u8; 80],
+	}
+	let table = |signature: &[u8;4], length: u32| -> &'static ACPISDTHeader {
+		let header = ACPISDTHeader {
+			signature: *signature, length, revision: 1, checksum: 0, oem_id: [0; 6],
+			oem_table_id: [0; 8], oem_revision: 0, creator_id: 0, creator_revision: 0,
+		};
+		&alloc::boxed::Box::leak(alloc::boxed::Box::new(Table {header, data: [0; 80]})).header
+	};
+	assert!(cast_table::<sdt::HPET>(table(b"HPET", 56)).is_some());
+	assert!(cast_table::<sdt::HPET>(table(b"HPET", 40)).is_none());
+	assert!(cast_table::<sdt::HPET>(table(b"APIC", 56)).is_none());
+	//an ACPI 1.0 FADT is shorter than the struct but still handed out
+	assert!(cast_table::<FADT>(table(b"FACP", 116)).is_some());
+	assert!(cast_table::<FADT>(table(b"FACP", 100)).is_none());
+	serial_println!("[ok]");
+}
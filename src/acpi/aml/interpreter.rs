@@ -0,0 +1,1340 @@
+use super::{AmlError, Handler, PciConfigAddress, opcode::*};
+use super::namespace::{self, NameString, Namespace};
+use super::value::{self, AmlValue, FieldKind, FieldUnit, RegionSpace, Target};
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+
+// Deepest method call nesting before we assume runaway recursion
+const MAX_CALL_DEPTH: usize = 64;
+// While loops that spin this often are assumed to be waiting on hardware that will never answer
+const MAX_LOOP_ITERATIONS: usize = 1_000_000;
+// Bytes in a buffer or field and elements in a package, sizes past this come from corrupt tables
+const MAX_OBJECT_SIZE: u64 = 64 * 1024;
+
+// Checks a size taken from AML before anything is allocated for it
+fn object_size(size: u64) -> Result<usize, AmlError> {
+	if size > MAX_OBJECT_SIZE {
+		Err(AmlError::TooLarge(size))
+	}
+	else {
+		Ok(size as usize)
+	}
+}
+
+fn byte_length(bit_length: u64) -> Result<usize, AmlError> {
+	object_size(bit_length / 8 + (bit_length % 8 != 0) as u64)
+}
+
+// A position inside a block of AML
+struct Cursor {
+	code: &'static [u8],
+	pos: usize,
+	end: usize,
+}
+impl Cursor {
+	fn new(code: &'static [u8]) -> Self {
+		Cursor {code, pos: 0, end: code.len()}
+	}
+	// A cursor over pos..end of the same code
+	fn sub(&self, end: usize) -> Self {
+		Cursor {code: self.code, pos: self.pos, end}
+	}
+	fn at_end(&self) -> bool {
+		self.pos >= self.end
+	}
+	fn peek(&self) -> Result<u8, AmlError> {
+		if self.at_end() {
+			return Err(AmlError::UnexpectedEnd);
+		}
+		Ok(self.code[self.pos])
+	}
+	fn peek_at(&self, offset: usize) -> Result<u8, AmlError> {
+		if self.pos + offset >= self.end {
+			return Err(AmlError::UnexpectedEnd);
+		}
+		Ok(self.code[self.pos + offset])
+	}
+	fn next(&mut self) -> Result<u8, AmlError> {
+		let byte = self.peek()?;
+		self.pos += 1;
+		Ok(byte)
+	}
+	fn take(&mut self, count: usize) -> Result<&'static [u8], AmlError> {
+		if self.pos + count > self.end {
+			return Err(AmlError::UnexpectedEnd);
+		}
+		let bytes = &self.code[self.pos..self.pos + count];
+		self.pos += count;
+		Ok(bytes)
+	}
+	// The bytes up to `end`, which whatever was read since the PkgLength must not have run past
+	fn take_until(&mut self, end: usize) -> Result<&'static [u8], AmlError> {
+		if self.pos > end {
+			return Err(AmlError::UnexpectedEnd);
+		}
+		self.take(end - self.pos)
+	}
+	fn integer(&mut self, size: usize) -> Result<u64, AmlError> {
+		let bytes = self.take(size)?;
+		Ok(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u64))
+	}
+	// The top two bits of the lead byte count the bytes that follow, the length includes the PkgLength itself
+	fn pkg_length_value(&mut self) -> Result<usize, AmlError> {
+		let lead = self.next()?;
+		let follow = (lead >> 6) as usize;
+		if follow == 0 {
+			return Ok((lead & 0x3F) as usize);
+		}
+		let mut length = (lead & 0x0F) as usize;
+		for i in 0..follow {
+			length |= (self.next()? as usize) << (4 + 8 * i);
+		}
+		Ok(length)
+	}
+	// Returns the position where the object that the PkgLength belongs to ends
+	fn pkg_length(&mut self) -> Result<usize, AmlError> {
+		let start = self.pos;
+		let end = start + self.pkg_length_value()?;
+		if end > self.end {
+			return Err(AmlError::UnexpectedEnd);
+		}
+		Ok(end)
+	}
+	fn name_segment(&mut self) -> Result<[u8;4], AmlError> {
+		let bytes = self.take(4)?;
+		let valid = bytes.iter().enumerate().all(|(i, byte)| match byte {
+			b'A'..=b'Z' | b'_' => true,
+			b'0'..=b'9' => i > 0,
+			_ => false,
+		});
+		if !valid {
+			return Err(AmlError::InvalidName);
+		}
+		Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+	}
+	fn name_string(&mut self) -> Result<NameString, AmlError> {
+		let mut name = NameString {root: false, parents: 0, segments: Vec::new()};
+		if self.peek()? == ROOT_CHAR {
+			self.next()?;
+			name.root = true;
+		}
+		else {
+			while self.peek()? == PARENT_PREFIX {
+				self.next()?;
+				name.parents += 1;
+			}
+		}
+		let count = match self.peek()? {
+			ZERO_OP => {
+				self.next()?;
+				0
+			}
+			DUAL_NAME_PREFIX => {
+				self.next()?;
+				2
+			}
+			MULTI_NAME_PREFIX => {
+				self.next()?;
+				self.next()? as usize
+			}
+			_ => 1,
+		};
+		for _ in 0..count {
+			name.segments.push(self.name_segment()?);
+		}
+		Ok(name)
+	}
+}
+
+fn is_name_start(byte: u8) -> bool {
+	matches!(byte, b'A'..=b'Z' | b'_' | ROOT_CHAR | PARENT_PREFIX | DUAL_NAME_PREFIX | MULTI_NAME_PREFIX)
+}
+
+// What a term did to the flow of control
+enum Flow {
+	Next,
+	Return(AmlValue),
+	Break,
+	Continue,
+}
+
+// The state of one method invocation, or of a table being loaded
+struct Frame {
+	scope: String,
+	args: Vec<AmlValue>,
+	locals: Vec<AmlValue>,
+	// Objects created by the method, which are destroyed when it returns
+	created: Option<Vec<String>>,
+}
+impl Frame {
+	fn load() -> Self {
+		Frame {scope: String::from("\\"), args: Vec::new(), locals: vec![AmlValue::Uninitialized; 8], created: None}
+	}
+	fn method(scope: String, args: Vec<AmlValue>) -> Self {
+		Frame {scope, args, locals: vec![AmlValue::Uninitialized; 8], created: Some(Vec::new())}
+	}
+}
+
+/// Loads AML definition blocks into a namespace and evaluates the objects in it
+pub struct Interpreter {
+	namespace: Namespace,
+	handler: Box<dyn Handler + Send>,
+	// Integers are 32 bits wide when the DSDT revision is below 2
+	integer_mask: u64,
+	depth: usize,
+}
+
+impl Interpreter {
+	pub fn new(handler: Box<dyn Handler + Send>) -> Self {
+		Interpreter {
+			namespace: Namespace::new(),
+			handler,
+			integer_mask: u64::MAX,
+			depth: 0,
+		}
+	}
+	pub fn namespace(&self) -> &Namespace {
+		&self.namespace
+	}
+
+	/// Runs the top-level code of a DSDT or SSDT body, adding the objects it defines to the namespace
+	///
+	/// `revision` is the table header's revision; a DSDT below revision 2 makes every integer 32 bits wide.
+	pub fn load_table(&mut self, aml: &'static [u8], revision: u8, is_dsdt: bool) -> Result<(), AmlError> {
+		if is_dsdt && revision < 2 {
+			self.integer_mask = 0xFFFF_FFFF;
+		}
+		let mut frame = Frame::load();
+		self.run_term_list(&mut Cursor::new(aml), &mut frame)?;
+		Ok(())
+	}
+
+	/// Evaluates the object at `path`, running it if it is a method
+	pub fn evaluate(&mut self, path: &str, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+		let path = namespace::normalize(path);
+		match self.resolve_alias(&path)? {
+			(path, AmlValue::Method {..}) => self.invoke(&path, args),
+			(path, _) => self.read_named(&path),
+		}
+	}
+
+	fn resolve_alias(&self, path: &str) -> Result<(String, AmlValue), AmlError> {
+		let mut path = String::from(path);
+		for _ in 0..MAX_CALL_DEPTH {
+			match self.namespace.get(&path) {
+				Some(AmlValue::Alias(target)) => path = target.clone(),
+				Some(value) => return Ok((path, value.clone())),
+				None => return Err(AmlError::NotFound(path)),
+			}
+		}
+		Err(AmlError::NotFound(path))
+	}
+
+	fn invoke(&mut self, path: &str, args: Vec<AmlValue>) -> Result<AmlValue, AmlError> {
+		if path == "\\_OSI" {
+			return self.osi(&args);
+		}
+		let code = match self.namespace.get(path) {
+			Some(AmlValue::Method {code, ..}) => *code,
+			_ => return Err(AmlError::TypeMismatch),
+		};
+		if self.depth >= MAX_CALL_DEPTH {
+			return Err(AmlError::RecursionLimit);
+		}
+		self.depth += 1;
+		let mut frame = Frame::method(String::from(path), args);
+		let result = self.run_term_list(&mut Cursor::new(code), &mut frame);
+		self.depth -= 1;
+		for created in frame.created.take().unwrap_or_default() {
+			self.namespace.remove(&created);
+		}
+		match result? {
+			Flow::Return(value) => Ok(value),
+			_ => Ok(AmlValue::Integer(0)),
+		}
+	}
+
+	// We claim to be a recent Windows, since that is the only OS firmware is reliably tested against
+	fn osi(&self, args: &[AmlValue]) -> Result<AmlValue, AmlError> {
+		let interface = args.first().ok_or(AmlError::TypeMismatch)?.as_string()?;
+		let supported = interface.starts_with("Windows") || interface == "Module Device" || interface == "Processor Device"
+			|| interface == "3.0 Thermal Model" || interface == "Extended Address Space Descriptor";
+		Ok(AmlValue::Integer(if supported {self.ones()} else {0}))
+	}
+
+	fn ones(&self) -> u64 {
+		self.integer_mask
+	}
+	fn boolean(&self, value: bool) -> AmlValue {
+		AmlValue::Integer(if value {self.ones()} else {0})
+	}
+
+	fn add_object(&mut self, frame: &mut Frame, path: String, value: AmlValue) -> Result<(), AmlError> {
+		self.namespace.add(path.clone(), value)?;
+		if let Some(created) = frame.created.as_mut() {
+			created.push(path);
+		}
+		Ok(())
+	}
+
+	fn run_term_list(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<Flow, AmlError> {
+		while !cursor.at_end() {
+			match self.run_term(cursor, frame)? {
+				Flow::Next => {}
+				flow => return Ok(flow),
+			}
+		}
+		Ok(Flow::Next)
+	}
+
+	// Runs the body of a Scope, Device or similar with `path` as the current scope
+	fn run_scope(&mut self, cursor: &mut Cursor, end: usize, frame: &mut Frame, path: String) -> Result<Flow, AmlError> {
+		let outer = core::mem::replace(&mut frame.scope, path);
+		let result = self.run_term_list(&mut cursor.sub(end), frame);
+		frame.scope = outer;
+		cursor.pos = end;
+		result
+	}
+
+	fn run_term(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<Flow, AmlError> {
+		match cursor.peek()? {
+			SCOPE_OP => {
+				cursor.next()?;
+				let end = cursor.pkg_length()?;
+				let name = cursor.name_string()?;
+				let path = match self.namespace.lookup(&frame.scope, &name) {
+					Some(path) => path,
+					None => {
+						let path = self.namespace.resolve(&frame.scope, &name);
+						self.add_object(frame, path.clone(), AmlValue::Scope)?;
+						path
+					}
+				};
+				self.run_scope(cursor, end, frame, path)
+			}
+			NAME_OP => {
+				cursor.next()?;
+				let name = cursor.name_string()?;
+				let value = self.eval(cursor, frame)?;
+				let path = self.namespace.resolve(&frame.scope, &name);
+				self.add_object(frame, path, value)?;
+				Ok(Flow::Next)
+			}
+			METHOD_OP => {
+				cursor.next()?;
+				let end = cursor.pkg_length()?;
+				let name = cursor.name_string()?;
+				let flags = cursor.next()?;
+				let code = cursor.take_until(end)?;
+				let path = self.namespace.resolve(&frame.scope, &name);
+				self.add_object(frame, path, AmlValue::Method {flags, code})?;
+				Ok(Flow::Next)
+			}
+			ALIAS_OP => {
+				cursor.next()?;
+				let source = cursor.name_string()?;
+				let alias = cursor.name_string()?;
+				let source = self.namespace.lookup(&frame.scope, &source).unwrap_or_else(|| self.namespace.resolve(&frame.scope, &source));
+				let path = self.namespace.resolve(&frame.scope, &alias);
+				self.add_object(frame, path, AmlValue::Alias(source))?;
+				Ok(Flow::Next)
+			}
+			EXTERNAL_OP => {
+				//only a hint for the compiler: the object type and argument count follow the name
+				cursor.next()?;
+				cursor.name_string()?;
+				cursor.take(2)?;
+				Ok(Flow::Next)
+			}
+			IF_OP => {
+				cursor.next()?;
+				let end = cursor.pkg_length()?;
+				let predicate = self.eval_integer(cursor, frame)? != 0;
+				let mut body = cursor.sub(end);
+				cursor.pos = end;
+				let mut else_body = None;
+				if !cursor.at_end() && cursor.peek()? == ELSE_OP {
+					cursor.next()?;
+					let else_end = cursor.pkg_length()?;
+					else_body = Some(cursor.sub(else_end));
+					cursor.pos = else_end;
+				}
+				if predicate {
+					self.run_term_list(&mut body, frame)
+				}
+				else if let Some(mut else_body) = else_body {
+					self.run_term_list(&mut else_body, frame)
+				}
+				else {
+					Ok(Flow::Next)
+				}
+			}
+			WHILE_OP => {
+				cursor.next()?;
+				let end = cursor.pkg_length()?;
+				let start = cursor.pos;
+				let mut iterations = 0;
+				let flow = loop {
+					cursor.pos = start;
+					if self.eval_integer(cursor, frame)? == 0 {
+						break Flow::Next;
+					}
+					match self.run_term_list(&mut cursor.sub(end), frame)? {
+						Flow::Break => break Flow::Next,
+						Flow::Return(value) => break Flow::Return(value),
+						Flow::Next | Flow::Continue => {}
+					}
+					iterations += 1;
+					if iterations >= MAX_LOOP_ITERATIONS {
+						return Err(AmlError::LoopTimeout);
+					}
+				};
+				cursor.pos = end;
+				Ok(flow)
+			}
+			RETURN_OP => {
+				cursor.next()?;
+				let value = self.eval(cursor, frame)?;
+				Ok(Flow::Return(value))
+			}
+			BREAK_OP => {
+				cursor.next()?;
+				Ok(Flow::Break)
+			}
+			CONTINUE_OP => {
+				cursor.next()?;
+				Ok(Flow::Continue)
+			}
+			NOOP_OP | BREAKPOINT_OP => {
+				cursor.next()?;
+				Ok(Flow::Next)
+			}
+			NOTIFY_OP => {
+				cursor.next()?;
+				let target = self.target(cursor, frame)?;
+				let value = self.eval_integer(cursor, frame)?;
+				if let Target::Name(path) = target {
+					self.handler.notify(&path, value);
+				}
+				Ok(Flow::Next)
+			}
+			CREATE_BIT_FIELD_OP | CREATE_BYTE_FIELD_OP | CREATE_WORD_FIELD_OP | CREATE_DWORD_FIELD_OP | CREATE_QWORD_FIELD_OP => {
+				let op = cursor.next()?;
+				let buffer = self.buffer_source(cursor, frame)?;
+				let index = self.eval_integer(cursor, frame)?;
+				let (bit_offset, bit_length) = match op {
+					CREATE_BIT_FIELD_OP => (index, 1),
+					CREATE_BYTE_FIELD_OP => (index * 8, 8),
+					CREATE_WORD_FIELD_OP => (index * 8, 16),
+					CREATE_DWORD_FIELD_OP => (index * 8, 32),
+					_ => (index * 8, 64),
+				};
+				let name = cursor.name_string()?;
+				let path = self.namespace.resolve(&frame.scope, &name);
+				self.add_object(frame, path, AmlValue::BufferField {buffer, bit_offset, bit_length})?;
+				Ok(Flow::Next)
+			}
+			EXT_OP_PREFIX => self.run_ext_term(cursor, frame),
+			_ => {
+				self.eval(cursor, frame)?;
+				Ok(Flow::Next)
+			}
+		}
+	}
+
+	fn run_ext_term(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<Flow, AmlError> {
+		let op = cursor.peek_at(1)?;
+		match op {
+			DEVICE_OP | THERMAL_ZONE_OP | PROCESSOR_OP | POWER_RES_OP => {
+				cursor.take(2)?;
+				let end = cursor.pkg_length()?;
+				let name = cursor.name_string()?;
+				let value = match op {
+					DEVICE_OP => AmlValue::Device,
+					THERMAL_ZONE_OP => AmlValue::ThermalZone,
+					PROCESSOR_OP => {
+						let id = cursor.next()?;
+						//the processor block address and length
+						cursor.take(5)?;
+						AmlValue::Processor {id}
+					}
+					_ => {
+						//the system level and resource order
+						cursor.take(3)?;
+						AmlValue::PowerResource
+					}
+				};
+				let path = self.namespace.resolve(&frame.scope, &name);
+				self.add_object(frame, path.clone(), value)?;
+				self.run_scope(cursor, end, frame, path)
+			}
+			OP_REGION_OP => {
+				cursor.take(2)?;
+				let name = cursor.name_string()?;
+				let space = RegionSpace::from(cursor.next()?);
+				let offset = self.eval_integer(cursor, frame)?;
+				let length = self.eval_integer(cursor, frame)?;
+				let path = self.namespace.resolve(&frame.scope, &name);
+				self.add_object(frame, path, AmlValue::OperationRegion {space, offset, length})?;
+				Ok(Flow::Next)
+			}
+			FIELD_OP => {
+				cursor.take(2)?;
+				let end = cursor.pkg_length()?;
+				let region = cursor.name_string()?;
+				let region = self.namespace.lookup(&frame.scope, &region).ok_or_else(|| AmlError::NotFound(self.namespace.resolve(&frame.scope, &region)))?;
+				self.field_list(cursor, end, frame, FieldKind::Region(region))?;
+				Ok(Flow::Next)
+			}
+			INDEX_FIELD_OP => {
+				cursor.take(2)?;
+				let end = cursor.pkg_length()?;
+				let index = cursor.name_string()?;
+				let data = cursor.name_string()?;
+				let index = self.namespace.lookup(&frame.scope, &index).ok_or_else(|| AmlError::NotFound(self.namespace.resolve(&frame.scope, &index)))?;
+				let data = self.namespace.lookup(&frame.scope, &data).ok_or_else(|| AmlError::NotFound(self.namespace.resolve(&frame.scope, &data)))?;
+				self.field_list(cursor, end, frame, FieldKind::Index {index, data})?;
+				Ok(Flow::Next)
+			}
+			BANK_FIELD_OP => {
+				//banked fields are rare enough that we leave them undefined rather than fail the whole table
+				cursor.take(2)?;
+				cursor.pos = cursor.pkg_length()?;
+				Ok(Flow::Next)
+			}
+			MUTEX_OP | EVENT_OP => {
+				cursor.take(2)?;
+				let name = cursor.name_string()?;
+				let value = if op == MUTEX_OP {
+					//the sync level
+					cursor.next()?;
+					AmlValue::Mutex
+				}
+				else {
+					AmlValue::Event
+				};
+				let path = self.namespace.resolve(&frame.scope, &name);
+				self.add_object(frame, path, value)?;
+				Ok(Flow::Next)
+			}
+			CREATE_FIELD_OP => {
+				cursor.take(2)?;
+				let buffer = self.buffer_source(cursor, frame)?;
+				let bit_offset = self.eval_integer(cursor, frame)?;
+				let bit_length = self.eval_integer(cursor, frame)?;
+				let name = cursor.name_string()?;
+				let path = self.namespace.resolve(&frame.scope, &name);
+				self.add_object(frame, path, AmlValue::BufferField {buffer, bit_offset, bit_length})?;
+				Ok(Flow::Next)
+			}
+			SLEEP_OP | STALL_OP => {
+				cursor.take(2)?;
+				let amount = self.eval_integer(cursor, frame)?;
+				if op == SLEEP_OP {
+					self.handler.sleep(amount);
+				}
+				else {
+					self.handler.stall(amount);
+				}
+				Ok(Flow::Next)
+			}
+			RELEASE_OP | SIGNAL_OP | RESET_OP => {
+				//we run one method at a time, so mutexes and events have nothing to synchronize
+				cursor.take(2)?;
+				self.target(cursor, frame)?;
+				Ok(Flow::Next)
+			}
+			FATAL_OP => {
+				cursor.take(2)?;
+				let fatal_type = cursor.next()?;
+				let code = cursor.integer(4)? as u32;
+				let argument = self.eval_integer(cursor, frame)?;
+				Err(AmlError::Fatal {fatal_type, code, argument})
+			}
+			LOAD_OP | LOAD_TABLE_OP | UNLOAD_OP | DATA_REGION_OP => Err(AmlError::UnknownOpcode(0x5B00 | op as u16)),
+			_ => {
+				self.eval(cursor, frame)?;
+				Ok(Flow::Next)
+			}
+		}
+	}
+
+	fn field_list(&mut self, cursor: &mut Cursor, end: usize, frame: &mut Frame, kind: FieldKind) -> Result<(), AmlError> {
+		let mut flags = cursor.next()?;
+		let mut bit_offset = 0;
+		while cursor.pos < end {
+			match cursor.peek()? {
+				RESERVED_FIELD => {
+					cursor.next()?;
+					bit_offset += cursor.pkg_length_value()? as u64;
+				}
+				ACCESS_FIELD => {
+					cursor.next()?;
+					let access_type = cursor.next()?;
+					//the access attribute only matters to SMBus and GenericSerialBus regions
+					cursor.next()?;
+					flags = (flags & 0xF0) | (access_type & 0x0F);
+				}
+				EXTENDED_ACCESS_FIELD => {
+					cursor.next()?;
+					let access_type = cursor.next()?;
+					cursor.take(2)?;
+					flags = (flags & 0xF0) | (access_type & 0x0F);
+				}
+				CONNECT_FIELD => return Err(AmlError::Unsupported("ConnectField")),
+				_ => {
+					let segment = cursor.name_segment()?;
+					let bit_length = cursor.pkg_length_value()? as u64;
+					let name = NameString {root: false, parents: 0, segments: vec![segment]};
+					let path = self.namespace.resolve(&frame.scope, &name);
+					let field = FieldUnit {kind: kind.clone(), flags, bit_offset, bit_length};
+					self.add_object(frame, path, AmlValue::Field(field))?;
+					bit_offset += bit_length;
+				}
+			}
+		}
+		cursor.pos = end;
+		Ok(())
+	}
+
+	// The named buffer that CreateField and friends carve up
+	fn buffer_source(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<String, AmlError> {
+		match self.target(cursor, frame)? {
+			Target::Name(path) => Ok(path),
+			_ => Err(AmlError::Unsupported("buffer field over a temporary buffer")),
+		}
+	}
+
+	fn eval_integer(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<u64, AmlError> {
+		self.eval(cursor, frame)?.as_integer()
+	}
+
+	// Evaluates a TermArg
+	fn eval(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+		let op = cursor.peek()?;
+		if is_name_start(op) {
+			return self.eval_name(cursor, frame);
+		}
+		cursor.next()?;
+		let ones = self.ones();
+		match op {
+			ZERO_OP => Ok(AmlValue::Integer(0)),
+			ONE_OP => Ok(AmlValue::Integer(1)),
+			ONES_OP => Ok(AmlValue::Integer(ones)),
+			BYTE_PREFIX => Ok(AmlValue::Integer(cursor.integer(1)?)),
+			WORD_PREFIX => Ok(AmlValue::Integer(cursor.integer(2)?)),
+			DWORD_PREFIX => Ok(AmlValue::Integer(cursor.integer(4)?)),
+			QWORD_PREFIX => Ok(AmlValue::Integer(cursor.integer(8)?)),
+			STRING_PREFIX => {
+				let start = cursor.pos;
+				while cursor.next()? != 0 {}
+				let bytes = &cursor.code[start..cursor.pos - 1];
+				Ok(AmlValue::String(String::from_utf8_lossy(bytes).into_owned()))
+			}
+			BUFFER_OP => {
+				let end = cursor.pkg_length()?;
+				let size = self.eval_integer(cursor, frame)?;
+				let initializer = cursor.take_until(end)?;
+				//the initializer is bounded by the table, only the declared size needs checking
+				let size = if size > initializer.len() as u64 {object_size(size)?} else {initializer.len()};
+				let mut bytes = vec![0; size];
+				bytes[..initializer.len()].copy_from_slice(initializer);
+				Ok(AmlValue::Buffer(bytes))
+			}
+			PACKAGE_OP | VAR_PACKAGE_OP => {
+				let end = cursor.pkg_length()?;
+				let count = if op == PACKAGE_OP {
+					cursor.next()? as usize
+				}
+				else {
+					object_size(self.eval_integer(cursor, frame)?)?
+				};
+				let mut elements = Vec::new();
+				while cursor.pos < end {
+					if is_name_start(cursor.peek()?) {
+						//names in a package are references, and may be to objects that don't exist yet
+						let name = cursor.name_string()?;
+						let path = self.namespace.lookup(&frame.scope, &name).unwrap_or_else(|| self.namespace.resolve(&frame.scope, &name));
+						elements.push(AmlValue::Reference(Target::Name(path)));
+					}
+					else {
+						elements.push(self.eval(cursor, frame)?);
+					}
+				}
+				if elements.len() < count {
+					elements.resize(count, AmlValue::Uninitialized);
+				}
+				Ok(AmlValue::Package(elements))
+			}
+			LOCAL0_OP..=LOCAL7_OP => Ok(frame.locals[(op - LOCAL0_OP) as usize].clone()),
+			ARG0_OP..=ARG6_OP => {
+				match frame.args.get((op - ARG0_OP) as usize) {
+					Some(AmlValue::Reference(target)) => {
+						let target = target.clone();
+						self.read_target(&target, frame)
+					}
+					Some(value) => Ok(value.clone()),
+					None => Ok(AmlValue::Uninitialized),
+				}
+			}
+			STORE_OP => {
+				let value = self.eval(cursor, frame)?;
+				let target = self.target(cursor, frame)?;
+				self.store(&target, value.clone(), frame)?;
+				Ok(value)
+			}
+			COPY_OBJECT_OP => {
+				let value = self.eval(cursor, frame)?;
+				let target = self.target(cursor, frame)?;
+				self.store_raw(&target, value.clone(), frame)?;
+				Ok(value)
+			}
+			ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHIFT_LEFT_OP | SHIFT_RIGHT_OP | AND_OP | NAND_OP | OR_OP | NOR_OP | XOR_OP | MOD_OP => {
+				let left = self.eval_integer(cursor, frame)?;
+				let right = self.eval_integer(cursor, frame)?;
+				let result = match op {
+					ADD_OP => left.wrapping_add(right),
+					SUBTRACT_OP => left.wrapping_sub(right),
+					MULTIPLY_OP => left.wrapping_mul(right),
+					SHIFT_LEFT_OP => if right >= 64 {0} else {left << right},
+					SHIFT_RIGHT_OP => if right >= 64 {0} else {left >> right},
+					AND_OP => left & right,
+					NAND_OP => !(left & right),
+					OR_OP => left | right,
+					NOR_OP => !(left | right),
+					XOR_OP => left ^ right,
+					_ => left.checked_rem(right).ok_or(AmlError::DivideByZero)?,
+				} & ones;
+				self.store_result(cursor, frame, AmlValue::Integer(result))
+			}
+			DIVIDE_OP => {
+				let dividend = self.eval_integer(cursor, frame)?;
+				let divisor = self.eval_integer(cursor, frame)?;
+				if divisor == 0 {
+					return Err(AmlError::DivideByZero);
+				}
+				let remainder = self.target(cursor, frame)?;
+				self.store(&remainder, AmlValue::Integer(dividend % divisor), frame)?;
+				self.store_result(cursor, frame, AmlValue::Integer(dividend / divisor))
+			}
+			NOT_OP => {
+				let value = !self.eval_integer(cursor, frame)? & ones;
+				self.store_result(cursor, frame, AmlValue::Integer(value))
+			}
+			FIND_SET_LEFT_BIT_OP | FIND_SET_RIGHT_BIT_OP => {
+				//bits are numbered from 1, 0 means no bit is set
+				let value = self.eval_integer(cursor, frame)?;
+				let bit = match (value, op) {
+					(0, _) => 0,
+					(_, FIND_SET_LEFT_BIT_OP) => 64 - value.leading_zeros() as u64,
+					_ => value.trailing_zeros() as u64 + 1,
+				};
+				self.store_result(cursor, frame, AmlValue::Integer(bit))
+			}
+			INCREMENT_OP | DECREMENT_OP => {
+				let target = self.target(cursor, frame)?;
+				let value = self.read_target(&target, frame)?.as_integer()?;
+				let value = if op == INCREMENT_OP {value.wrapping_add(1)} else {value.wrapping_sub(1)} & ones;
+				self.store(&target, AmlValue::Integer(value), frame)?;
+				Ok(AmlValue::Integer(value))
+			}
+			LNOT_OP => {
+				let value = self.eval_integer(cursor, frame)?;
+				Ok(self.boolean(value == 0))
+			}
+			LAND_OP | LOR_OP => {
+				let left = self.eval_integer(cursor, frame)? != 0;
+				let right = self.eval_integer(cursor, frame)? != 0;
+				Ok(self.boolean(if op == LAND_OP {left && right} else {left || right}))
+			}
+			LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+				let left = self.eval(cursor, frame)?;
+				let right = self.eval(cursor, frame)?;
+				let ordering = match &left {
+					AmlValue::Integer(left) => left.cmp(&right.as_integer()?),
+					AmlValue::String(left) => left.as_bytes().cmp(right.as_string()?.as_bytes()),
+					AmlValue::Buffer(left) => left.as_slice().cmp(right.as_buffer()?.as_slice()),
+					_ => return Err(AmlError::TypeMismatch),
+				};
+				let result = match op {
+					LEQUAL_OP => ordering == core::cmp::Ordering::Equal,
+					LGREATER_OP => ordering == core::cmp::Ordering::Greater,
+					_ => ordering == core::cmp::Ordering::Less,
+				};
+				Ok(self.boolean(result))
+			}
+			CONCAT_OP => {
+				let left = self.eval(cursor, frame)?;
+				let right = self.eval(cursor, frame)?;
+				let result = match left {
+					AmlValue::String(mut string) => {
+						string.push_str(&right.as_string()?);
+						AmlValue::String(string)
+					}
+					AmlValue::Integer(value) => {
+						let width = if ones == u64::MAX {8} else {4};
+						let mut bytes = value.to_le_bytes()[..width].to_vec();
+						bytes.extend_from_slice(&right.as_integer()?.to_le_bytes()[..width]);
+						AmlValue::Buffer(bytes)
+					}
+					other => {
+						let mut bytes = other.as_buffer()?;
+						bytes.extend(right.as_buffer()?);
+						AmlValue::Buffer(bytes)
+					}
+				};
+				self.store_result(cursor, frame, result)
+			}
+			CONCAT_RES_OP => {
+				//resource templates end in a two byte end tag, which the first template loses
+				let mut left = self.eval(cursor, frame)?.as_buffer()?;
+				let right = self.eval(cursor, frame)?.as_buffer()?;
+				let keep = left.len().saturating_sub(2);
+				left.truncate(keep);
+				left.extend(right);
+				self.store_result(cursor, frame, AmlValue::Buffer(left))
+			}
+			SIZE_OF_OP => {
+				let target = self.target(cursor, frame)?;
+				let size = self.read_target(&target, frame)?.size()?;
+				Ok(AmlValue::Integer(size))
+			}
+			OBJECT_TYPE_OP => {
+				let target = self.target(cursor, frame)?;
+				let value = match &target {
+					Target::Name(path) => self.resolve_alias(path)?.1,
+					_ => self.read_target(&target, frame)?,
+				};
+				Ok(AmlValue::Integer(value.type_code()))
+			}
+			REF_OF_OP => {
+				let target = self.target(cursor, frame)?;
+				Ok(AmlValue::Reference(target))
+			}
+			DEREF_OF_OP => {
+				match self.eval(cursor, frame)? {
+					AmlValue::Reference(target) => self.read_target(&target, frame),
+					AmlValue::String(path) => self.read_named(&namespace::normalize(&path)),
+					_ => Err(AmlError::TypeMismatch),
+				}
+			}
+			INDEX_OP => {
+				let source = if self.is_super_name(cursor)? {
+					self.target(cursor, frame)?
+				}
+				else {
+					//a temporary, such as a package literal, can only be read from
+					let value = self.eval(cursor, frame)?;
+					let index = self.eval_integer(cursor, frame)? as usize;
+					let element = element(&value, index)?;
+					return self.store_result(cursor, frame, element);
+				};
+				let index = self.eval_integer(cursor, frame)? as usize;
+				//check the index now, so that a bad one fails where it was made
+				element(&self.read_target(&source, frame)?, index)?;
+				let reference = AmlValue::Reference(Target::Index(Box::new(source), index));
+				self.store_result(cursor, frame, reference)
+			}
+			MATCH_OP => {
+				let package = self.eval(cursor, frame)?;
+				let first_op = cursor.next()?;
+				let first = self.eval_integer(cursor, frame)?;
+				let second_op = cursor.next()?;
+				let second = self.eval_integer(cursor, frame)?;
+				let start = self.eval_integer(cursor, frame)? as usize;
+				let elements = match package {
+					AmlValue::Package(elements) => elements,
+					_ => return Err(AmlError::TypeMismatch),
+				};
+				let found = elements.iter().enumerate().skip(start).find(|(_, element)| {
+					match element.as_integer() {
+						Ok(value) => matches_operator(first_op, value, first) && matches_operator(second_op, value, second),
+						Err(_) => false,
+					}
+				});
+				Ok(AmlValue::Integer(found.map_or(ones, |(i, _)| i as u64)))
+			}
+			TO_BUFFER_OP => {
+				let value = self.eval(cursor, frame)?.as_buffer()?;
+				self.store_result(cursor, frame, AmlValue::Buffer(value))
+			}
+			TO_INTEGER_OP => {
+				let value = self.eval(cursor, frame)?;
+				let value = match value {
+					AmlValue::String(string) if !string.starts_with("0x") && !string.starts_with("0X") => {
+						//decimal unless it has a hex prefix
+						string.chars().take_while(|c| c.is_ascii_digit()).fold(0u64, |value, c| value.wrapping_mul(10).wrapping_add(c as u64 - '0' as u64))
+					}
+					other => other.as_integer()?,
+				};
+				self.store_result(cursor, frame, AmlValue::Integer(value & ones))
+			}
+			TO_HEX_STRING_OP | TO_DECIMAL_STRING_OP => {
+				let value = self.eval(cursor, frame)?;
+				let string = match (&value, op) {
+					(AmlValue::Integer(value), TO_HEX_STRING_OP) => alloc::format!("0x{:X}", value),
+					(AmlValue::Integer(value), _) => alloc::format!("{}", value),
+					(AmlValue::Buffer(bytes), _) => {
+						let parts: Vec<String> = bytes.iter()
+							.map(|byte| if op == TO_HEX_STRING_OP {alloc::format!("0x{:02X}", byte)} else {alloc::format!("{}", byte)})
+							.collect();
+						parts.join(",")
+					}
+					(AmlValue::String(string), _) => string.clone(),
+					_ => return Err(AmlError::TypeMismatch),
+				};
+				self.store_result(cursor, frame, AmlValue::String(string))
+			}
+			TO_STRING_OP => {
+				let bytes = self.eval(cursor, frame)?.as_buffer()?;
+				let length = self.eval_integer(cursor, frame)?;
+				let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len()).min(length as usize);
+				let string = String::from_utf8_lossy(&bytes[..end]).into_owned();
+				self.store_result(cursor, frame, AmlValue::String(string))
+			}
+			MID_OP => {
+				let value = self.eval(cursor, frame)?;
+				let index = self.eval_integer(cursor, frame)? as usize;
+				let length = self.eval_integer(cursor, frame)? as usize;
+				let result = match value {
+					AmlValue::String(string) => {
+						let bytes = string.as_bytes();
+						let start = index.min(bytes.len());
+						let end = index.saturating_add(length).min(bytes.len());
+						AmlValue::String(String::from_utf8_lossy(&bytes[start..end]).into_owned())
+					}
+					other => {
+						let bytes = other.as_buffer()?;
+						let start = index.min(bytes.len());
+						let end = index.saturating_add(length).min(bytes.len());
+						AmlValue::Buffer(bytes[start..end].to_vec())
+					}
+				};
+				self.store_result(cursor, frame, result)
+			}
+			EXT_OP_PREFIX => self.eval_ext(cursor, frame),
+			_ => Err(AmlError::UnknownOpcode(op as u16)),
+		}
+	}
+
+	fn eval_ext(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+		let op = cursor.next()?;
+		match op {
+			COND_REF_OF_OP => {
+				let exists = if is_name_start(cursor.peek()?) {
+					let name = cursor.name_string()?;
+					self.namespace.lookup(&frame.scope, &name).map(Target::Name)
+				}
+				else {
+					Some(self.target(cursor, frame)?)
+				};
+				let result_target = self.target(cursor, frame)?;
+				match exists {
+					Some(target) => {
+						self.store(&result_target, AmlValue::Reference(target), frame)?;
+						Ok(self.boolean(true))
+					}
+					None => Ok(self.boolean(false)),
+				}
+			}
+			ACQUIRE_OP => {
+				self.target(cursor, frame)?;
+				cursor.integer(2)?;
+				//0 means the mutex was acquired rather than timing out
+				Ok(AmlValue::Integer(0))
+			}
+			WAIT_OP => {
+				self.target(cursor, frame)?;
+				self.eval_integer(cursor, frame)?;
+				Ok(AmlValue::Integer(0))
+			}
+			REVISION_OP => Ok(AmlValue::Integer(1)),
+			DEBUG_OP => Ok(AmlValue::Uninitialized),
+			TIMER_OP => Ok(AmlValue::Integer(self.handler.timer())),
+			FROM_BCD_OP | TO_BCD_OP => {
+				let mut value = self.eval_integer(cursor, frame)?;
+				let mut result = 0;
+				let mut shift = 0;
+				//a 64-bit integer only holds 16 BCD digits, any above that are dropped
+				while value != 0 && shift < 16 {
+					if op == FROM_BCD_OP {
+						result += (value & 0xF) * 10u64.pow(shift);
+						value >>= 4;
+					}
+					else {
+						result |= (value % 10) << (4 * shift);
+						value /= 10;
+					}
+					shift += 1;
+				}
+				self.store_result(cursor, frame, AmlValue::Integer(result))
+			}
+			_ => Err(AmlError::UnknownOpcode(0x5B00 | op as u16)),
+		}
+	}
+
+	// A name in a TermArg: method calls run, anything else is read
+	fn eval_name(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+		let name = cursor.name_string()?;
+		let path = self.namespace.lookup(&frame.scope, &name).ok_or_else(|| AmlError::NotFound(self.namespace.resolve(&frame.scope, &name)))?;
+		let (path, value) = self.resolve_alias(&path)?;
+		if let AmlValue::Method {flags, ..} = value {
+			let mut args = Vec::new();
+			for _ in 0..flags & 0b111 {
+				args.push(self.eval(cursor, frame)?);
+			}
+			return self.invoke(&path, args);
+		}
+		self.read_named(&path)
+	}
+
+	fn is_super_name(&self, cursor: &Cursor) -> Result<bool, AmlError> {
+		let op = cursor.peek()?;
+		Ok(is_name_start(op) || (LOCAL0_OP..=ARG6_OP).contains(&op) || (op == EXT_OP_PREFIX && cursor.peek_at(1)? == DEBUG_OP))
+	}
+
+	// Parses a SuperName or Target, which names where a result goes
+	fn target(&mut self, cursor: &mut Cursor, frame: &mut Frame) -> Result<Target, AmlError> {
+		let op = cursor.peek()?;
+		match op {
+			ZERO_OP => {
+				cursor.next()?;
+				Ok(Target::Null)
+			}
+			LOCAL0_OP..=LOCAL7_OP => {
+				cursor.next()?;
+				Ok(Target::Local((op - LOCAL0_OP) as usize))
+			}
+			ARG0_OP..=ARG6_OP => {
+				cursor.next()?;
+				Ok(Target::Arg((op - ARG0_OP) as usize))
+			}
+			EXT_OP_PREFIX if cursor.peek_at(1)? == DEBUG_OP => {
+				cursor.take(2)?;
+				Ok(Target::Debug)
+			}
+			_ if is_name_start(op) => {
+				let name = cursor.name_string()?;
+				let path = self.namespace.lookup(&frame.scope, &name).ok_or_else(|| AmlError::NotFound(self.namespace.resolve(&frame.scope, &name)))?;
+				Ok(Target::Name(path))
+			}
+			//Index, RefOf and DerefOf and method calls produce references
+			_ => match self.eval(cursor, frame)? {
+				AmlValue::Reference(target) => Ok(target),
+				AmlValue::String(path) => Ok(Target::Name(namespace::normalize(&path))),
+				_ => Err(AmlError::TypeMismatch),
+			},
+		}
+	}
+
+	// Stores an operator's result in its optional target operand, then returns it
+	fn store_result(&mut self, cursor: &mut Cursor, frame: &mut Frame, value: AmlValue) -> Result<AmlValue, AmlError> {
+		let target = self.target(cursor, frame)?;
+		self.store(&target, value.clone(), frame)?;
+		Ok(value)
+	}
+
+	// Reads what a target refers to, without running methods or converting anything
+	fn read_target(&mut self, target: &Target, frame: &mut Frame) -> Result<AmlValue, AmlError> {
+		match target {
+			Target::Null | Target::Debug => Ok(AmlValue::Uninitialized),
+			Target::Local(i) => Ok(frame.locals[*i].clone()),
+			Target::Arg(i) => match frame.args.get(*i).cloned() {
+				Some(AmlValue::Reference(target)) => self.read_target(&target, frame),
+				Some(value) => Ok(value),
+				None => Ok(AmlValue::Uninitialized),
+			},
+			Target::Name(path) => self.read_named(path),
+			Target::Index(container, index) => {
+				let container = self.read_target(container, frame)?;
+				element(&container, *index)
+			}
+		}
+	}
+
+	// Store, which converts the value to the type of a named Integer, String or Buffer it replaces
+	fn store(&mut self, target: &Target, value: AmlValue, frame: &mut Frame) -> Result<(), AmlError> {
+		match target {
+			Target::Name(path) => {
+				let (path, existing) = self.resolve_alias(path)?;
+				let value = match existing {
+					AmlValue::Field(field) => return self.write_field(&field, &value),
+					AmlValue::BufferField {buffer, bit_offset, bit_length} => {
+						return self.write_buffer_field(&buffer, bit_offset, bit_length, &value);
+					}
+					AmlValue::Integer(_) => AmlValue::Integer(value.as_integer()?),
+					AmlValue::String(_) => AmlValue::String(value.as_string()?),
+					AmlValue::Buffer(old) => {
+						let mut bytes = value.as_buffer()?;
+						bytes.resize(old.len(), 0);
+						AmlValue::Buffer(bytes)
+					}
+					_ => value,
+				};
+				self.namespace.set(path, value);
+				Ok(())
+			}
+			_ => self.store_raw(target, value, frame),
+		}
+	}
+
+	// CopyObject, and Store to anything other than a name, replace the target outright
+	fn store_raw(&mut self, target: &Target, value: AmlValue, frame: &mut Frame) -> Result<(), AmlError> {
+		match target {
+			Target::Null => Ok(()),
+			Target::Debug => {
+				self.handler.debug(&value);
+				Ok(())
+			}
+			Target::Local(i) => {
+				frame.locals[*i] = value;
+				Ok(())
+			}
+			Target::Arg(i) => {
+				if let Some(AmlValue::Reference(referenced)) = frame.args.get(*i).cloned() {
+					return self.store(&referenced, value, frame);
+				}
+				if frame.args.len() <= *i {
+					frame.args.resize(*i + 1, AmlValue::Uninitialized);
+				}
+				frame.args[*i] = value;
+				Ok(())
+			}
+			Target::Name(path) => {
+				let (path, _) = self.resolve_alias(path)?;
+				self.namespace.set(path, value);
+				Ok(())
+			}
+			Target::Index(container, index) => {
+				let mut whole = match &**container {
+					Target::Name(path) => self.resolve_alias(path)?.1,
+					other => self.read_target(other, frame)?,
+				};
+				match &mut whole {
+					AmlValue::Package(elements) => {
+						*elements.get_mut(*index).ok_or(AmlError::IndexOutOfBounds)? = value;
+					}
+					AmlValue::Buffer(bytes) => {
+						*bytes.get_mut(*index).ok_or(AmlError::IndexOutOfBounds)? = value.as_integer()? as u8;
+					}
+					AmlValue::BufferField {..} | AmlValue::Field(_) => return Err(AmlError::Unsupported("Index into a field")),
+					_ => return Err(AmlError::TypeMismatch),
+				}
+				self.store_raw(container, whole, frame)
+			}
+		}
+	}
+
+	// Reads a named object, reading through fields so that the result is plain data
+	fn read_named(&mut self, path: &str) -> Result<AmlValue, AmlError> {
+		let (_, value) = self.resolve_alias(path)?;
+		match value {
+			AmlValue::Field(field) => self.read_field(&field),
+			AmlValue::BufferField {buffer, bit_offset, bit_length} => {
+				let bytes = self.read_named(&buffer)?.as_buffer()?;
+				if bit_length <= 64 {
+					Ok(AmlValue::Integer(value::read_bits(&bytes, bit_offset, bit_length)))
+				}
+				else {
+					let mut result = vec![0; byte_length(bit_length)?];
+					copy_bits(&bytes, bit_offset, &mut result, 0, bit_length);
+					Ok(AmlValue::Buffer(result))
+				}
+			}
+			other => Ok(other),
+		}
+	}
+
+	fn write_buffer_field(&mut self, buffer: &str, bit_offset: u64, bit_length: u64, value: &AmlValue) -> Result<(), AmlError> {
+		let (path, existing) = self.resolve_alias(buffer)?;
+		let mut bytes = match existing {
+			AmlValue::Buffer(bytes) => bytes,
+			_ => return Err(AmlError::TypeMismatch),
+		};
+		let mut source = value.as_buffer()?;
+		source.resize(byte_length(bit_length)?, 0);
+		copy_bits(&source, 0, &mut bytes, bit_offset, bit_length);
+		self.namespace.set(path, AmlValue::Buffer(bytes));
+		Ok(())
+	}
+
+	fn read_field(&mut self, field: &FieldUnit) -> Result<AmlValue, AmlError> {
+		let width = field.access_width();
+		let end = field.bit_offset + field.bit_length;
+		let mut result = vec![0u8; byte_length(field.bit_length)?];
+		let mut bit = field.bit_offset;
+		while bit < end {
+			let unit_start = bit / width * width;
+			let shift = bit - unit_start;
+			let count = (width - shift).min(end - bit);
+			let unit = self.read_unit(field, unit_start / 8, width)?;
+			copy_bits(&unit.to_le_bytes(), shift, &mut result, bit - field.bit_offset, count);
+			bit += count;
+		}
+		if field.bit_length <= 64 {
+			Ok(AmlValue::Integer(value::read_bits(&result, 0, field.bit_length)))
+		}
+		else {
+			Ok(AmlValue::Buffer(result))
+		}
+	}
+
+	fn write_field(&mut self, field: &FieldUnit, value: &AmlValue) -> Result<(), AmlError> {
+		let width = field.access_width();
+		let end = field.bit_offset + field.bit_length;
+		let mut source = value.as_buffer()?;
+		source.resize(byte_length(field.bit_length)?, 0);
+		let mut bit = field.bit_offset;
+		while bit < end {
+			let unit_start = bit / width * width;
+			let shift = bit - unit_start;
+			let count = (width - shift).min(end - bit);
+			let unit = if count == width {
+				0
+			}
+			else {
+				match field.update_rule() {
+					0 => self.read_unit(field, unit_start / 8, width)?,
+					1 => u64::MAX,
+					_ => 0,
+				}
+			};
+			let mut unit_bytes = unit.to_le_bytes();
+			copy_bits(&source, bit - field.bit_offset, &mut unit_bytes, shift, count);
+			let unit = value::read_bits(&unit_bytes, 0, width);
+			self.write_unit(field, unit_start / 8, width, unit)?;
+			bit += count;
+		}
+		Ok(())
+	}
+
+	// Reads one access-width unit at `byte_offset` into the field's region
+	fn read_unit(&mut self, field: &FieldUnit, byte_offset: u64, width: u64) -> Result<u64, AmlError> {
+		match &field.kind {
+			FieldKind::Region(region) => {
+				let (space, address, pci) = self.region_address(region, byte_offset)?;
+				let width = width as u8;
+				match space {
+					RegionSpace::SystemMemory => Ok(self.handler.read_memory(address, width)),
+					RegionSpace::SystemIo => Ok(self.handler.read_io(address as u16, width)),
+					RegionSpace::PciConfig => Ok(self.handler.read_pci(pci, address as u16, width)),
+					other => Err(AmlError::UnsupportedRegionSpace(other)),
+				}
+			}
+			FieldKind::Index {index, data} => {
+				self.store(&Target::Name(index.clone()), AmlValue::Integer(byte_offset), &mut Frame::load())?;
+				self.read_named(data)?.as_integer()
+			}
+		}
+	}
+
+	fn write_unit(&mut self, field: &FieldUnit, byte_offset: u64, width: u64, unit: u64) -> Result<(), AmlError> {
+		match &field.kind {
+			FieldKind::Region(region) => {
+				let (space, address, pci) = self.region_address(region, byte_offset)?;
+				let width = width as u8;
+				match space {
+					RegionSpace::SystemMemory => self.handler.write_memory(address, width, unit),
+					RegionSpace::SystemIo => self.handler.write_io(address as u16, width, unit),
+					RegionSpace::PciConfig => self.handler.write_pci(pci, address as u16, width, unit),
+					other => return Err(AmlError::UnsupportedRegionSpace(other)),
+				}
+				Ok(())
+			}
+			FieldKind::Index {index, data} => {
+				let mut frame = Frame::load();
+				self.store(&Target::Name(index.clone()), AmlValue::Integer(byte_offset), &mut frame)?;
+				self.store(&Target::Name(data.clone()), AmlValue::Integer(unit), &mut frame)
+			}
+		}
+	}
+
+	// The space and address of a byte in a region, plus the PCI function for PCI config regions
+	fn region_address(&mut self, region: &str, byte_offset: u64) -> Result<(RegionSpace, u64, PciConfigAddress), AmlError> {
+		let (space, offset, length) = match self.resolve_alias(region)?.1 {
+			AmlValue::OperationRegion {space, offset, length} => (space, offset, length),
+			_ => return Err(AmlError::TypeMismatch),
+		};
+		if byte_offset >= length {
+			return Err(AmlError::IndexOutOfBounds);
+		}
+		let pci = if space == RegionSpace::PciConfig {
+			self.pci_address(region)?
+		}
+		else {
+			PciConfigAddress::default()
+		};
+		Ok((space, offset + byte_offset, pci))
+	}
+
+	// A PCI config region belongs to the function named by its device's _ADR, on the bus named by the host bridge's _BBN
+	fn pci_address(&mut self, region: &str) -> Result<PciConfigAddress, AmlError> {
+		let device = namespace::parent(region).unwrap_or("\\");
+		let adr = self.evaluate_optional(&join(device, "_ADR"))?.unwrap_or(0);
+		let mut address = PciConfigAddress {
+			segment: 0,
+			bus: 0,
+			device: (adr >> 16) as u8,
+			function: adr as u8,
+		};
+		let mut scope = namespace::parent(device);
+		while let Some(path) = scope {
+			if let Some(bus) = self.evaluate_optional(&join(path, "_BBN"))? {
+				address.bus = bus as u8;
+				address.segment = self.evaluate_optional(&join(path, "_SEG"))?.unwrap_or(0) as u16;
+				break;
+			}
+			scope = namespace::parent(path);
+		}
+		Ok(address)
+	}
+
+	/// Evaluates an object that may not exist to an integer
+	pub(super) fn evaluate_optional(&mut self, path: &str) -> Result<Option<u64>, AmlError> {
+		if !self.namespace.contains(path) {
+			return Ok(None);
+		}
+		self.evaluate(path, Vec::new())?.as_integer().map(Some)
+	}
+}
+
+fn join(scope: &str, segment: &str) -> String {
+	let mut path = String::from(scope);
+	if path.len() > 1 {
+		path.push('.');
+	}
+	path.push_str(segment);
+	path
+}
+
+fn element(container: &AmlValue, index: usize) -> Result<AmlValue, AmlError> {
+	match container {
+		AmlValue::Package(elements) => elements.get(index).cloned().ok_or(AmlError::IndexOutOfBounds),
+		AmlValue::Buffer(bytes) => bytes.get(index).map(|byte| AmlValue::Integer(*byte as u64)).ok_or(AmlError::IndexOutOfBounds),
+		AmlValue::String(string) => string.as_bytes().get(index).map(|byte| AmlValue::Integer(*byte as u64)).ok_or(AmlError::IndexOutOfBounds),
+		_ => Err(AmlError::TypeMismatch),
+	}
+}
+
+// Copies `length` bits from bit `from` of `source` to bit `to` of `destination`
+fn copy_bits(source: &[u8], from: u64, destination: &mut [u8], to: u64, length: u64) {
+	let mut done = 0;
+	while done < length {
+		let count = (length - done).min(64);
+		let bits = value::read_bits(source, from + done, count);
+		value::write_bits(destination, to + done, count, bits);
+		done += count;
+	}
+}
+
+// The comparisons Match can make: MTR, MEQ, MLE, MLT, MGE and MGT
+fn matches_operator(operator: u8, value: u64, operand: u64) -> bool {
+	match operator {
+		0 => true,
+		1 => value == operand,
+		2 => value <= operand,
+		3 => value < operand,
+		4 => value >= operand,
+		5 => value > operand,
+		_ => false,
+	}
+}
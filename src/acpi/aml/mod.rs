@@ -0,0 +1,317 @@
+use super::{find_table, find_tables, gas, Sdt, sdt::{DSDT, SSDT}};
+use crate::{pci::config::PCIAddress, println};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use spin::Mutex;
+
+pub mod interpreter;
+pub mod namespace;
+pub mod opcode;
+pub mod value;
+pub use interpreter::Interpreter;
+pub use value::AmlValue;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AmlError {
+	/// An object ran past the end of its table or package
+	UnexpectedEnd,
+	/// An opcode we don't know, with extended opcodes as 0x5Bxx
+	UnknownOpcode(u16),
+	InvalidName,
+	NotFound(String),
+	AlreadyExists(String),
+	TypeMismatch,
+	DivideByZero,
+	UnsupportedRegionSpace(value::RegionSpace),
+	Unsupported(&'static str),
+	RecursionLimit,
+	/// A While loop ran for too long
+	LoopTimeout,
+	/// The firmware executed a Fatal operator
+	Fatal {fatal_type: u8, code: u32, argument: u64},
+	IndexOutOfBounds,
+	/// A buffer, package or field is larger than we are willing to allocate
+	TooLarge(u64),
+}
+
+/// The PCI function that a PCI config OperationRegion belongs to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PciConfigAddress {
+	pub segment: u16,
+	pub bus: u8,
+	pub device: u8,
+	pub function: u8,
+}
+
+/// Access to the hardware on behalf of AML code
+///
+/// Widths are in bits and are 8, 16, 32 or 64.
+pub trait Handler {
+	fn read_memory(&mut self, address: u64, width: u8) -> u64;
+	fn write_memory(&mut self, address: u64, width: u8, value: u64);
+	fn read_io(&mut self, port: u16, width: u8) -> u64;
+	fn write_io(&mut self, port: u16, width: u8, value: u64);
+	fn read_pci(&mut self, address: PciConfigAddress, offset: u16, width: u8) -> u64;
+	fn write_pci(&mut self, address: PciConfigAddress, offset: u16, width: u8, value: u64);
+	/// Sleep, in milliseconds
+	fn sleep(&mut self, millis: u64) {
+		self.stall(millis * 1000);
+	}
+	/// Busy wait, in microseconds
+	fn stall(&mut self, _micros: u64) {}
+	/// A monotonic timer in 100ns units
+	fn timer(&mut self) -> u64 {
+		0
+	}
+	fn notify(&mut self, _path: &str, _value: u64) {}
+	fn debug(&mut self, _value: &AmlValue) {}
+}
+
+/// Accesses the machine's own memory, I/O ports and PCI configuration space
+pub struct KernelHandler;
+impl Handler for KernelHandler {
+	fn read_memory(&mut self, address: u64, width: u8) -> u64 {
+		unsafe {gas::read_memory(address, width)}
+	}
+	fn write_memory(&mut self, address: u64, width: u8, value: u64) {
+		unsafe {gas::write_memory(address, width, value)}
+	}
+	fn read_io(&mut self, port: u16, width: u8) -> u64 {
+		unsafe {gas::read_io(port, width)}
+	}
+	fn write_io(&mut self, port: u16, width: u8, value: u64) {
+		unsafe {gas::write_io(port, width, value)}
+	}
+	fn read_pci(&mut self, address: PciConfigAddress, offset: u16, width: u8) -> u64 {
+		let address = PCIAddress::new(address.segment, address.bus, address.device, address.function);
+		unsafe {gas::read_pci(address, offset, width)}
+	}
+	fn write_pci(&mut self, address: PciConfigAddress, offset: u16, width: u8, value: u64) {
+		let address = PCIAddress::new(address.segment, address.bus, address.device, address.function);
+		unsafe {gas::write_pci(address, offset, width, value)}
+	}
+	fn stall(&mut self, micros: u64) {
+		let start = crate::time::Instant::now();
+		while start.elapsed().as_micros() < micros as u128 {
+			core::sync::atomic::spin_loop_hint();
+		}
+	}
+	fn timer(&mut self) -> u64 {
+		crate::time::Instant::now().as_nanos() / 100
+	}
+	fn notify(&mut self, path: &str, value: u64) {
+		println!("AML: Notify({}, {:#X})", path, value);
+	}
+	fn debug(&mut self, value: &AmlValue) {
+		println!("AML debug: {:?}", value);
+	}
+}
+
+/// One entry of a `_PRT` PCI interrupt routing table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrtEntry {
+	/// The device in bits 16-31, the function is always 0xFFFF meaning any
+	pub address: u64,
+	/// 0 to 3 for INTA# to INTD#
+	pub pin: u8,
+	/// The link device that routes the pin, None when it is hardwired
+	pub source: Option<String>,
+	/// The global system interrupt when hardwired, otherwise the index into the link's resources
+	pub source_index: u32,
+}
+impl PrtEntry {
+	pub fn device(&self) -> u8 {
+		(self.address >> 16) as u8
+	}
+}
+
+// _STA bits
+pub const STA_PRESENT: u64 = 1 << 0;
+pub const STA_ENABLED: u64 = 1 << 1;
+pub const STA_FUNCTIONING: u64 = 1 << 3;
+
+/// Decodes a compressed EISA ID, such as the integer form of `PNP0A03`
+pub fn eisa_id(id: u32) -> String {
+	let id = id.swap_bytes();
+	let letter = |shift: u32| (b'@' + ((id >> shift) & 0x1F) as u8) as char;
+	let mut string = String::new();
+	string.push(letter(26));
+	string.push(letter(21));
+	string.push(letter(16));
+	string.push_str(&alloc::format!("{:04X}", id & 0xFFFF));
+	string
+}
+
+impl Interpreter {
+	/// Evaluates `_STA` of a device, which defaults to present and working when it isn't defined
+	pub fn device_status(&mut self, device: &str) -> Result<u64, AmlError> {
+		let path = child(device, "_STA");
+		Ok(self.evaluate_optional(&path)?.unwrap_or(0x0F))
+	}
+	/// Evaluates `_HID` of a device as a string such as `PNP0A03` or `ACPI0003`
+	pub fn hardware_id(&mut self, device: &str) -> Result<Option<String>, AmlError> {
+		let path = child(device, "_HID");
+		if !self.namespace().contains(&path) {
+			return Ok(None);
+		}
+		match self.evaluate(&path, Vec::new())? {
+			AmlValue::Integer(id) => Ok(Some(eisa_id(id as u32))),
+			other => other.as_string().map(Some),
+		}
+	}
+	/// Evaluates `_CRS` of a device, the resource template describing what it currently uses
+	pub fn current_resources(&mut self, device: &str) -> Result<Vec<u8>, AmlError> {
+		self.evaluate(&child(device, "_CRS"), Vec::new())?.as_buffer()
+	}
+	/// Evaluates `_PRT` of a PCI bus
+	pub fn routing_table(&mut self, bus: &str) -> Result<Vec<PrtEntry>, AmlError> {
+		let entries = match self.evaluate(&child(bus, "_PRT"), Vec::new())? {
+			AmlValue::Package(entries) => entries,
+			_ => return Err(AmlError::TypeMismatch),
+		};
+		let mut table = Vec::new();
+		for entry in entries {
+			let fields = match entry {
+				AmlValue::Package(fields) if fields.len() >= 4 => fields,
+				_ => return Err(AmlError::TypeMismatch),
+			};
+			let source = match &fields[2] {
+				AmlValue::Reference(value::Target::Name(path)) => Some(path.clone()),
+				AmlValue::String(path) => Some(namespace::normalize(path)),
+				_ => None,
+			};
+			table.push(PrtEntry {
+				address: fields[0].as_integer()?,
+				pin: fields[1].as_integer()? as u8,
+				source,
+				source_index: fields[3].as_integer()? as u32,
+			});
+		}
+		Ok(table)
+	}
+	/// Evaluates `\_Sx` for the sleep state `state`, returning SLP_TYPa and SLP_TYPb
+	pub fn sleep_type(&mut self, state: u8) -> Result<(u8, u8), AmlError> {
+		let path = alloc::format!("\\_S{}_", state);
+		match self.evaluate(&path, Vec::new())? {
+			AmlValue::Package(elements) if !elements.is_empty() => {
+				let pm1a = elements[0].as_integer()? as u8;
+				let pm1b = elements.get(1).map_or(Ok(0), |element| element.as_integer())? as u8;
+				Ok((pm1a, pm1b))
+			}
+			_ => Err(AmlError::TypeMismatch),
+		}
+	}
+}
+
+fn child(device: &str, name: &str) -> String {
+	let mut path = namespace::normalize(device);
+	if path.len() > 1 {
+		path.push('.');
+	}
+	path.push_str(name);
+	path
+}
+
+/// The namespace built from the firmware's tables, None until `init` has run or if there is no DSDT
+pub static AML: Mutex<Option<Interpreter>> = Mutex::new(None);
+
+/// Loads the DSDT and then every SSDT into a new namespace
+pub fn init() -> Result<(), AmlError> {
+	let dsdt = match find_table::<DSDT>() {
+		Some(dsdt) => dsdt,
+		None => return Err(AmlError::NotFound(String::from("DSDT"))),
+	};
+	let mut interpreter = Interpreter::new(Box::new(KernelHandler));
+	interpreter.load_table(dsdt.get_aml(), dsdt.header().revision(), true)?;
+	for ssdt in find_tables::<SSDT>() {
+		//a broken SSDT shouldn't take the DSDT's namespace down with it
+		if let Err(err) = interpreter.load_table(ssdt.get_aml(), ssdt.header().revision(), false) {
+			println!("AML: failed to load {}: {:?}", ssdt.header(), err);
+		}
+	}
+	*AML.lock() = Some(interpreter);
+	Ok(())
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[cfg(test)]
+struct MockHandler {
+	memory: [u8; 16],
+}
+#[cfg(test)]
+impl Handler for MockHandler {
+	fn read_memory(&mut self, address: u64, width: u8) -> u64 {
+		value::read_bits(&self.memory, address * 8, width as u64)
+	}
+	fn write_memory(&mut self, address: u64, width: u8, value: u64) {
+		value::write_bits(&mut self.memory, address * 8, width as u64, value)
+	}
+	fn read_io(&mut self, _port: u16, _width: u8) -> u64 {0}
+	fn write_io(&mut self, _port: u16, _width: u8, _value: u64) {}
+	fn read_pci(&mut self, _address: PciConfigAddress, _offset: u16, _width: u8) -> u64 {0}
+	fn write_pci(&mut self, _address: PciConfigAddress, _offset: u16, _width: u8, _value: u64) {}
+}
+
+#[test_case]
+fn test_aml_methods() {
+	serial_print!("test_aml_methods... ");
+	//Name(_S5, Package(2) {5, 0})
+	//Method(DBL, 1) {Return(Multiply(Arg0, 2))}
+	//Scope(_SB) {Device(DEV0) {Name(_HID, EisaId("PNP0A03")) Method(_STA) {Return(0x0B)}}}
+	static AML_CODE: [u8; 57] = [
+		0x08, b'_', b'S', b'5', b'_', 0x12, 0x05, 0x02, 0x0A, 0x05, 0x00,
+		0x14, 0x0C, b'D', b'B', b'L', b'_', 0x01, 0xA4, 0x77, 0x68, 0x0A, 0x02, 0x00,
+		0x10, 0x20, b'_', b'S', b'B', b'_',
+		0x5B, 0x82, 0x19, b'D', b'E', b'V', b'0',
+		0x08, b'_', b'H', b'I', b'D', 0x0C, 0x41, 0xD0, 0x0A, 0x03,
+		0x14, 0x09, b'_', b'S', b'T', b'A', 0x00, 0xA4, 0x0A, 0x0B,
+	];
+	let mut interpreter = Interpreter::new(Box::new(MockHandler {memory: [0; 16]}));
+	interpreter.load_table(&AML_CODE, 2, true).unwrap();
+	assert_eq!(interpreter.sleep_type(5), Ok((5, 0)));
+	assert_eq!(interpreter.evaluate("\\DBL", alloc::vec![AmlValue::Integer(21)]), Ok(AmlValue::Integer(42)));
+	assert_eq!(interpreter.hardware_id("\\_SB.DEV0"), Ok(Some(String::from("PNP0A03"))));
+	assert_eq!(interpreter.device_status("\\_SB.DEV0"), Ok(0x0B));
+	serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_aml_fields() {
+	serial_print!("test_aml_fields... ");
+	//OperationRegion(MEM, SystemMemory, 4, 8)
+	//Field(MEM, ByteAcc, NoLock, Preserve) {, 4, LOW, 4, HIGH, 8}
+	//Method(SET) {Store(0x0A, LOW) Store(0xBC, HIGH) Return(HIGH)}
+	static AML_CODE: [u8; 57] = [
+		0x5B, 0x80, b'M', b'E', b'M', b'_', 0x00, 0x0A, 0x04, 0x0A, 0x08,
+		0x5B, 0x81, 0x12, b'M', b'E', b'M', b'_', 0x01, 0x00, 0x04, b'L', b'O', b'W', b'_', 0x04, b'H', b'I', b'G', b'H', 0x08,
+		0x14, 0x19, b'S', b'E', b'T', b'_', 0x00,
+		0x70, 0x0A, 0x0A, b'L', b'O', b'W', b'_',
+		0x70, 0x0A, 0xBC, b'H', b'I', b'G', b'H',
+		0xA4, b'H', b'I', b'G', b'H',
+	];
+	let mut interpreter = Interpreter::new(Box::new(MockHandler {memory: [0; 16]}));
+	interpreter.load_table(&AML_CODE, 2, true).unwrap();
+	assert_eq!(interpreter.evaluate("\\SET", Vec::new()), Ok(AmlValue::Integer(0xBC)));
+	assert_eq!(interpreter.evaluate("\\LOW", Vec::new()), Ok(AmlValue::Integer(0x0A)));
+	serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_aml_size_limits() {
+	serial_print!("test_aml_size_limits... ");
+	//Method(SML) {Return(Buffer(4) {0x01})}
+	//Method(BIG) {Return(Buffer(0xFFFFFFFF) {})}
+	//Method(PKG) {Return(VarPackage(0xFFFFFFFF) {})}
+	static AML_CODE: [u8; 43] = [
+		0x14, 0x0C, b'S', b'M', b'L', b'_', 0x00, 0xA4, 0x11, 0x04, 0x0A, 0x04, 0x01,
+		0x14, 0x0E, b'B', b'I', b'G', b'_', 0x00, 0xA4, 0x11, 0x06, 0x0C, 0xFF, 0xFF, 0xFF, 0xFF,
+		0x14, 0x0E, b'P', b'K', b'G', b'_', 0x00, 0xA4, 0x13, 0x06, 0x0C, 0xFF, 0xFF, 0xFF, 0xFF,
+	];
+	let mut interpreter = Interpreter::new(Box::new(MockHandler {memory: [0; 16]}));
+	interpreter.load_table(&AML_CODE, 2, true).unwrap();
+	assert_eq!(interpreter.evaluate("\\SML", Vec::new()), Ok(AmlValue::Buffer(alloc::vec![1, 0, 0, 0])));
+	assert_eq!(interpreter.evaluate("\\BIG", Vec::new()), Err(AmlError::TooLarge(0xFFFF_FFFF)));
+	assert_eq!(interpreter.evaluate("\\PKG", Vec::new()), Err(AmlError::TooLarge(0xFFFF_FFFF)));
+	serial_println!("[ok]");
+}
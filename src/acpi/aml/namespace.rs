@@ -0,0 +1,150 @@
+use super::{AmlError, value::AmlValue};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+/// A name as it appears in AML, before it is resolved against a scope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameString {
+	pub root: bool,
+	/// Number of `^` prefixes
+	pub parents: usize,
+	pub segments: Vec<[u8;4]>,
+}
+impl NameString {
+	/// Whether the name is a single segment, the only kind that the search rules apply to
+	pub fn is_simple(&self) -> bool {
+		!self.root && self.parents == 0 && self.segments.len() == 1
+	}
+}
+
+/// Every object defined by the loaded tables, keyed by absolute path such as `\_SB_.PCI0._PRT`
+pub struct Namespace {
+	objects: BTreeMap<String, AmlValue>,
+}
+impl Namespace {
+	/// Creates a namespace holding the predefined root scopes
+	pub fn new() -> Self {
+		let mut objects = BTreeMap::new();
+		for scope in &["\\_GPE", "\\_PR_", "\\_SB_", "\\_SI_", "\\_TZ_"] {
+			objects.insert(String::from(*scope), AmlValue::Scope);
+		}
+		objects.insert(String::from("\\_OS_"), AmlValue::String(String::from("Microsoft Windows NT")));
+		objects.insert(String::from("\\_REV"), AmlValue::Integer(2));
+		//\_OSI is answered by the interpreter itself, the empty body is never run
+		objects.insert(String::from("\\_OSI"), AmlValue::Method {flags: 1, code: &[]});
+		Namespace {objects}
+	}
+	pub fn get(&self, path: &str) -> Option<&AmlValue> {
+		self.objects.get(path)
+	}
+	pub fn contains(&self, path: &str) -> bool {
+		self.objects.contains_key(path)
+	}
+	/// Adds an object, replacing a bare scope of the same name but nothing else
+	pub fn add(&mut self, path: String, value: AmlValue) -> Result<(), AmlError> {
+		match self.objects.get(&path) {
+			Some(AmlValue::Scope) | None => {
+				self.objects.insert(path, value);
+				Ok(())
+			}
+			Some(_) => Err(AmlError::AlreadyExists(path)),
+		}
+	}
+	pub(super) fn set(&mut self, path: String, value: AmlValue) {
+		self.objects.insert(path, value);
+	}
+	pub(super) fn remove(&mut self, path: &str) {
+		self.objects.remove(path);
+	}
+	/// Every object in path order, parents before their children
+	pub fn iter(&self) -> impl Iterator<Item = (&str, &AmlValue)> {
+		self.objects.iter().map(|(path, value)| (path.as_str(), value))
+	}
+	/// The paths of the objects directly inside `scope`
+	pub fn children<'a>(&'a self, scope: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+		self.objects.keys()
+			.map(|path| path.as_str())
+			.filter(move |path| parent(path) == Some(scope))
+	}
+	/// The paths of every Device object
+	pub fn devices(&self) -> Vec<String> {
+		self.objects.iter()
+			.filter(|(_, value)| **value == AmlValue::Device)
+			.map(|(path, _)| path.clone())
+			.collect()
+	}
+
+	/// Turns a name into an absolute path relative to `scope`, without checking that it exists
+	pub fn resolve(&self, scope: &str, name: &NameString) -> String {
+		let mut segments = if name.root {Vec::new()} else {segments(scope)};
+		for _ in 0..name.parents {
+			segments.pop();
+		}
+		let mut path = join(&segments);
+		for segment in &name.segments {
+			push_segment(&mut path, segment);
+		}
+		path
+	}
+	/// Finds an existing object, searching the enclosing scopes for single-segment names
+	pub fn lookup(&self, scope: &str, name: &NameString) -> Option<String> {
+		if !name.is_simple() {
+			let path = self.resolve(scope, name);
+			return if self.contains(&path) {Some(path)} else {None};
+		}
+		let mut segments = segments(scope);
+		loop {
+			let mut path = join(&segments);
+			push_segment(&mut path, &name.segments[0]);
+			if self.contains(&path) {
+				return Some(path);
+			}
+			segments.pop()?;
+		}
+	}
+}
+impl Default for Namespace {
+	fn default() -> Self {Self::new()}
+}
+
+fn segments(path: &str) -> Vec<&str> {
+	path.trim_start_matches('\\').split('.').filter(|segment| !segment.is_empty()).collect()
+}
+
+fn join(segments: &[&str]) -> String {
+	let mut path = String::from("\\");
+	path.push_str(&segments.join("."));
+	path
+}
+
+fn push_segment(path: &mut String, segment: &[u8;4]) {
+	if path.len() > 1 {
+		path.push('.');
+	}
+	path.extend(segment.iter().map(|byte| *byte as char));
+}
+
+/// The path of the scope that contains `path`, None for the root
+pub fn parent(path: &str) -> Option<&str> {
+	if path == "\\" {
+		return None;
+	}
+	match path.rfind('.') {
+		Some(dot) => Some(&path[..dot]),
+		None => Some("\\"),
+	}
+}
+
+/// Converts a path such as `\_SB.PCI0` to the padded form used as a key, `\_SB_.PCI0`
+pub fn normalize(path: &str) -> String {
+	let mut normalized = String::from("\\");
+	for segment in segments(path) {
+		if normalized.len() > 1 {
+			normalized.push('.');
+		}
+		normalized.push_str(segment);
+		for _ in segment.len()..4 {
+			normalized.push('_');
+		}
+	}
+	normalized
+}
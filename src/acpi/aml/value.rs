@@ -0,0 +1,215 @@
+use super::AmlError;
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+/// The address space an OperationRegion lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionSpace {
+	SystemMemory,
+	SystemIo,
+	PciConfig,
+	EmbeddedControl,
+	SmBus,
+	SystemCmos,
+	PciBarTarget,
+	Other(u8),
+}
+impl From<u8> for RegionSpace {
+	fn from(space: u8) -> Self {
+		match space {
+			0 => RegionSpace::SystemMemory,
+			1 => RegionSpace::SystemIo,
+			2 => RegionSpace::PciConfig,
+			3 => RegionSpace::EmbeddedControl,
+			4 => RegionSpace::SmBus,
+			5 => RegionSpace::SystemCmos,
+			6 => RegionSpace::PciBarTarget,
+			other => RegionSpace::Other(other),
+		}
+	}
+}
+
+/// How a field unit reaches its OperationRegion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldKind {
+	/// Directly, through the region at this path
+	Region(String),
+	/// By writing the offset to the `index` field and then accessing the `data` field
+	Index {index: String, data: String},
+}
+
+/// A named bit range inside an OperationRegion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldUnit {
+	pub kind: FieldKind,
+	/// AccessType in bits 0-3, LockRule in bit 4 and UpdateRule in bits 5-6
+	pub flags: u8,
+	pub bit_offset: u64,
+	pub bit_length: u64,
+}
+impl FieldUnit {
+	/// The width of each access in bits, AnyAcc and BufferAcc use bytes
+	pub fn access_width(&self) -> u64 {
+		match self.flags & 0x0F {
+			2 => 16,
+			3 => 32,
+			4 => 64,
+			_ => 8,
+		}
+	}
+	/// 0 to preserve the bits around a partial write, 1 to write them as ones, 2 to write them as zeros
+	pub fn update_rule(&self) -> u8 {
+		(self.flags >> 5) & 0b11
+	}
+}
+
+/// Where a Store or a reference points
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+	/// NullName, the result is discarded
+	Null,
+	/// The Debug object
+	Debug,
+	Local(usize),
+	Arg(usize),
+	/// A named object, by absolute path
+	Name(String),
+	/// An element of the package, buffer or string that the inner target holds
+	Index(Box<Target>, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AmlValue {
+	Uninitialized,
+	Integer(u64),
+	String(String),
+	Buffer(Vec<u8>),
+	Package(Vec<AmlValue>),
+	/// Made by RefOf, Index or a name inside a package, read through with DerefOf
+	Reference(Target),
+	/// The body of a method, with ArgCount in bits 0-2 of the flags
+	Method {flags: u8, code: &'static [u8]},
+	OperationRegion {space: RegionSpace, offset: u64, length: u64},
+	Field(FieldUnit),
+	/// Bits of the named buffer, made by CreateField and friends
+	BufferField {buffer: String, bit_offset: u64, bit_length: u64},
+	/// Another name for the object at this path
+	Alias(String),
+	/// A bare scope, such as \_SB
+	Scope,
+	Device,
+	Processor {id: u8},
+	PowerResource,
+	ThermalZone,
+	Mutex,
+	Event,
+}
+
+impl AmlValue {
+	/// Implicit conversion to an integer, as done for the operands of arithmetic
+	pub fn as_integer(&self) -> Result<u64, AmlError> {
+		match self {
+			AmlValue::Integer(value) => Ok(*value),
+			AmlValue::Buffer(bytes) => {
+				let mut value = 0;
+				for (i, byte) in bytes.iter().take(8).enumerate() {
+					value |= (*byte as u64) << (i * 8);
+				}
+				Ok(value)
+			}
+			AmlValue::String(string) => {
+				//strings convert as hexadecimal, stopping at the first non-hex character
+				let digits = string.trim_start_matches("0x").trim_start_matches("0X");
+				let mut value = 0u64;
+				for c in digits.chars().take(16) {
+					match c.to_digit(16) {
+						Some(digit) => value = (value << 4) | digit as u64,
+						None => break,
+					}
+				}
+				Ok(value)
+			}
+			_ => Err(AmlError::TypeMismatch),
+		}
+	}
+	pub fn as_buffer(&self) -> Result<Vec<u8>, AmlError> {
+		match self {
+			AmlValue::Buffer(bytes) => Ok(bytes.clone()),
+			AmlValue::Integer(value) => Ok(value.to_le_bytes().to_vec()),
+			AmlValue::String(string) => {
+				let mut bytes = string.as_bytes().to_vec();
+				if !bytes.is_empty() {
+					bytes.push(0);
+				}
+				Ok(bytes)
+			}
+			_ => Err(AmlError::TypeMismatch),
+		}
+	}
+	pub fn as_string(&self) -> Result<String, AmlError> {
+		match self {
+			AmlValue::String(string) => Ok(string.clone()),
+			AmlValue::Integer(value) => Ok(alloc::format!("{:016X}", value)),
+			AmlValue::Buffer(bytes) => {
+				let end = bytes.iter().position(|byte| *byte == 0).unwrap_or(bytes.len());
+				Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+			}
+			_ => Err(AmlError::TypeMismatch),
+		}
+	}
+	/// Number of elements, bytes or characters, as returned by SizeOf
+	pub fn size(&self) -> Result<u64, AmlError> {
+		match self {
+			AmlValue::String(string) => Ok(string.len() as u64),
+			AmlValue::Buffer(bytes) => Ok(bytes.len() as u64),
+			AmlValue::Package(elements) => Ok(elements.len() as u64),
+			_ => Err(AmlError::TypeMismatch),
+		}
+	}
+	/// The type code returned by ObjectType
+	pub fn type_code(&self) -> u64 {
+		match self {
+			AmlValue::Uninitialized | AmlValue::Scope | AmlValue::Alias(_) | AmlValue::Reference(_) => 0,
+			AmlValue::Integer(_) => 1,
+			AmlValue::String(_) => 2,
+			AmlValue::Buffer(_) => 3,
+			AmlValue::Package(_) => 4,
+			AmlValue::Field(_) => 5,
+			AmlValue::Device => 6,
+			AmlValue::Event => 7,
+			AmlValue::Method {..} => 8,
+			AmlValue::Mutex => 9,
+			AmlValue::OperationRegion {..} => 10,
+			AmlValue::PowerResource => 11,
+			AmlValue::Processor {..} => 12,
+			AmlValue::ThermalZone => 13,
+			AmlValue::BufferField {..} => 14,
+		}
+	}
+}
+
+// Reads `length` bits (at most 64) starting at bit `offset` of a little-endian byte string
+pub(super) fn read_bits(bytes: &[u8], offset: u64, length: u64) -> u64 {
+	let mut value = 0;
+	for i in 0..length {
+		let bit = offset + i;
+		let byte = bytes.get((bit / 8) as usize).copied().unwrap_or(0);
+		value |= (((byte >> (bit % 8)) & 1) as u64) << i;
+	}
+	value
+}
+
+// Writes the low `length` bits (at most 64) of `value` starting at bit `offset`
+pub(super) fn write_bits(bytes: &mut [u8], offset: u64, length: u64, value: u64) {
+	for i in 0..length {
+		let bit = offset + i;
+		if let Some(byte) = bytes.get_mut((bit / 8) as usize) {
+			let mask = 1 << (bit % 8);
+			if value & (1 << i) != 0 {
+				*byte |= mask;
+			}
+			else {
+				*byte &= !mask;
+			}
+		}
+	}
+}
@@ -0,0 +1,252 @@
+use super::sdt::GenericAddressStructure;
+use crate::{memory, pci::config::{self, PCIAddress}};
+use x86_64::{PhysAddr, instructions::port::Port};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GasError {
+	/// The register lives in an address space we can't reach, such as an embedded controller
+	UnsupportedAddressSpace(u8),
+	/// The access size is reserved, or too wide for the address space
+	InvalidAccessSize(u8),
+	/// The register has a zero address or width, which is how firmware marks it as absent
+	NotPresent,
+	/// The bit offset and width run past 64 bits
+	TooWide,
+}
+
+impl GenericAddressStructure {
+	pub fn is_present(&self) -> bool {
+		self.get_address() != 0 && self.get_bit_width() != 0
+	}
+
+	/// The width in bits of each access, derived from the register's own width when the access size is undefined
+	///
+	/// PCI configuration space has no 64-bit accesses, wider registers without an access size are split into dwords.
+	pub fn access_width(&self) -> Result<u8, GasError> {
+		let pci = self.get_address_space() == Self::PCI_CONFIG;
+		let width = match self.get_access_size() {
+			0 => match self.get_bit_offset() as u16 + self.get_bit_width() as u16 {
+				0..=8 => 8,
+				9..=16 => 16,
+				17..=32 => 32,
+				_ if pci => 32,
+				_ => 64,
+			},
+			4 if pci => return Err(GasError::InvalidAccessSize(4)),
+			size @ 1..=4 => 8 << (size - 1),
+			size => return Err(GasError::InvalidAccessSize(size)),
+		};
+		Ok(width)
+	}
+
+	fn check(&self) -> Result<(), GasError> {
+		if !self.is_present() {
+			return Err(GasError::NotPresent);
+		}
+		if self.get_bit_offset() as u16 + self.get_bit_width() as u16 > 64 {
+			return Err(GasError::TooWide);
+		}
+		Ok(())
+	}
+	// The number of accesses needed to cover bit_offset..bit_offset + bit_width
+	fn access_count(&self, access_width: u8) -> u64 {
+		let bits = self.get_bit_offset() as u64 + self.get_bit_width() as u64;
+		((bits + access_width as u64 - 1) / access_width as u64).max(1)
+	}
+	fn mask(&self) -> u64 {
+		if self.get_bit_width() >= 64 {u64::MAX} else {(1 << self.get_bit_width()) - 1}
+	}
+
+	/// Reads the register, shifting out `bit_offset` and masking to `bit_width`
+	/// # Safety
+	/// - Reading a device register can have side effects, such as clearing status bits
+	pub unsafe fn read(&self) -> Result<u64, GasError> {
+		self.check()?;
+		let width = self.access_width()?;
+		let mut raw = 0u64;
+		for i in 0..self.access_count(width) {
+			let shift = i * width as u64;
+			if shift >= 64 {
+				break;
+			}
+			raw |= self.read_unit(i, width)? << shift;
+		}
+		Ok((raw >> self.get_bit_offset()) & self.mask())
+	}
+
+	/// Writes the register, preserving the bits around it when it doesn't fill its accesses
+	/// # Safety
+	/// - Writing a device register can reset or power off the machine, among other things
+	pub unsafe fn write(&self, value: u64) -> Result<(), GasError> {
+		self.check()?;
+		let width = self.access_width()?;
+		let count = self.access_count(width);
+		let unit_mask = if width == 64 {u64::MAX} else {(1u64 << width) - 1};
+		let offset = self.get_bit_offset() as u64;
+		for i in 0..count {
+			let shift = i * width as u64;
+			if shift >= 64 {
+				break;
+			}
+			//the register's bits that fall in this access unit
+			let field_mask = ((self.mask() << offset) >> shift) & unit_mask;
+			let bits = ((value & self.mask()) << offset >> shift) & unit_mask;
+			let unit = if field_mask != unit_mask {
+				(self.read_unit(i, width)? & !field_mask) | bits
+			}
+			else {
+				bits
+			};
+			self.write_unit(i, width, unit)?;
+		}
+		Ok(())
+	}
+
+	unsafe fn read_unit(&self, index: u64, width: u8) -> Result<u64, GasError> {
+		let address = self.get_address() + index * (width / 8) as u64;
+		match self.get_address_space() {
+			Self::SYSTEM_MEMORY => Ok(read_memory(address, width)),
+			Self::SYSTEM_IO => Ok(read_io(address as u16, width)),
+			Self::PCI_CONFIG => {
+				let (function, offset) = pci_address(address);
+				Ok(read_pci(function, offset, width))
+			}
+			space => Err(GasError::UnsupportedAddressSpace(space)),
+		}
+	}
+	unsafe fn write_unit(&self, index: u64, width: u8, value: u64) -> Result<(), GasError> {
+		let address = self.get_address() + index * (width / 8) as u64;
+		match self.get_address_space() {
+			Self::SYSTEM_MEMORY => write_memory(address, width, value),
+			Self::SYSTEM_IO => write_io(address as u16, width, value),
+			Self::PCI_CONFIG => {
+				let (function, offset) = pci_address(address);
+				write_pci(function, offset, width, value);
+			}
+			space => return Err(GasError::UnsupportedAddressSpace(space)),
+		}
+		Ok(())
+	}
+}
+
+// A PCI config GAS address holds the device in bits 32-47, the function in 16-31 and the register in 0-15, always on bus 0
+fn pci_address(address: u64) -> (PCIAddress, u16) {
+	(PCIAddress::new(0, 0, (address >> 32) as u8 & 0x1F, (address >> 16) as u8 & 0x7), address as u16)
+}
+
+/// Reads physical memory with a single access of `width` bits
+/// # Safety
+/// - The address must be mapped through the physical memory offset and safe to read
+pub unsafe fn read_memory(address: u64, width: u8) -> u64 {
+	let virt = memory::phys_to_virt(PhysAddr::new(address));
+	match width {
+		8 => core::ptr::read_volatile(virt.as_ptr::<u8>()) as u64,
+		16 => core::ptr::read_volatile(virt.as_ptr::<u16>()) as u64,
+		32 => core::ptr::read_volatile(virt.as_ptr::<u32>()) as u64,
+		_ => core::ptr::read_volatile(virt.as_ptr::<u64>()),
+	}
+}
+
+/// Writes physical memory with a single access of `width` bits
+/// # Safety
+/// - The address must be mapped through the physical memory offset and safe to write
+pub unsafe fn write_memory(address: u64, width: u8, value: u64) {
+	let virt = memory::phys_to_virt(PhysAddr::new(address));
+	match width {
+		8 => core::ptr::write_volatile(virt.as_mut_ptr::<u8>(), value as u8),
+		16 => core::ptr::write_volatile(virt.as_mut_ptr::<u16>(), value as u16),
+		32 => core::ptr::write_volatile(virt.as_mut_ptr::<u32>(), value as u32),
+		_ => core::ptr::write_volatile(virt.as_mut_ptr::<u64>(), value),
+	}
+}
+
+/// Reads an I/O port, 64-bit reads are split into two dwords
+/// # Safety
+/// - This reads from CPU I/O ports
+pub unsafe fn read_io(port: u16, width: u8) -> u64 {
+	match width {
+		8 => Port::<u8>::new(port).read() as u64,
+		16 => Port::<u16>::new(port).read() as u64,
+		32 => Port::<u32>::new(port).read() as u64,
+		_ => Port::<u32>::new(port).read() as u64 | (Port::<u32>::new(port + 4).read() as u64) << 32,
+	}
+}
+
+/// Writes an I/O port, 64-bit writes are split into two dwords
+/// # Safety
+/// - This writes to CPU I/O ports
+pub unsafe fn write_io(port: u16, width: u8, value: u64) {
+	match width {
+		8 => Port::<u8>::new(port).write(value as u8),
+		16 => Port::<u16>::new(port).write(value as u16),
+		32 => Port::<u32>::new(port).write(value as u32),
+		_ => {
+			Port::<u32>::new(port).write(value as u32);
+			Port::<u32>::new(port + 4).write((value >> 32) as u32);
+		}
+	}
+}
+
+/// Reads PCI configuration space through the configuration access mechanism in use
+/// # Safety
+/// - Reading some registers has side effects on the device
+pub unsafe fn read_pci(address: PCIAddress, offset: u16, width: u8) -> u64 {
+	match width {
+		8 => config::read_register::<u8>(address, offset) as u64,
+		16 => config::read_register::<u16>(address, offset) as u64,
+		_ => config::read_register::<u32>(address, offset) as u64,
+	}
+}
+
+/// Writes PCI configuration space through the configuration access mechanism in use
+/// # Safety
+/// - Writing configuration space reprograms the device
+pub unsafe fn write_pci(address: PCIAddress, offset: u16, width: u8, value: u64) {
+	match width {
+		8 => config::write_register(address, offset, value as u8),
+		16 => config::write_register(address, offset, value as u16),
+		_ => config::write_register(address, offset, value as u32),
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_gas_access_width() {
+	serial_print!("test_gas_access_width... ");
+	assert_eq!(GenericAddressStructure::io(0x404, 16).access_width(), Ok(16));
+	assert_eq!(GenericAddressStructure::new(GenericAddressStructure::SYSTEM_MEMORY, 12, 8, 0, 0x1000).access_width(), Ok(32));
+	assert_eq!(GenericAddressStructure::new(GenericAddressStructure::SYSTEM_IO, 32, 0, 1, 0x400).access_width(), Ok(8));
+	assert_eq!(GenericAddressStructure::new(GenericAddressStructure::SYSTEM_IO, 8, 0, 5, 0x400).access_width(), Err(GasError::InvalidAccessSize(5)));
+	assert_eq!(GenericAddressStructure::new(GenericAddressStructure::PCI_CONFIG, 64, 0, 4, 0x10).access_width(), Err(GasError::InvalidAccessSize(4)));
+	assert_eq!(GenericAddressStructure::new(GenericAddressStructure::PCI_CONFIG, 64, 0, 0, 0x10).access_width(), Ok(32));
+	assert_eq!(unsafe {GenericAddressStructure::io(0, 8).read()}, Err(GasError::NotPresent));
+	serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_gas_system_memory() {
+	serial_print!("test_gas_system_memory... ");
+	use x86_64::VirtAddr;
+	let mut buffer: u64 = 0x1122_3344_5566_7788;
+	let pointer = &mut buffer as *mut u64;
+	let address = memory::virt_to_phys(VirtAddr::from_ptr(pointer)).expect("the stack is mapped").as_u64();
+
+	//12 bits at bit offset 4 through word accesses, and a byte further in
+	let field = GenericAddressStructure::new(GenericAddressStructure::SYSTEM_MEMORY, 12, 4, 2, address);
+	assert_eq!(unsafe {field.read()}, Ok(0x778));
+	let byte = GenericAddressStructure::new(GenericAddressStructure::SYSTEM_MEMORY, 8, 0, 1, address + 5);
+	assert_eq!(unsafe {byte.read()}, Ok(0x33));
+
+	//a partial write keeps the low nibble and every byte outside the access
+	assert_eq!(unsafe {field.write(0xABC)}, Ok(()));
+	assert_eq!(unsafe {core::ptr::read_volatile(pointer)}, 0x1122_3344_5566_ABC8);
+
+	//address space 3 is the embedded controller, which we can't reach
+	let embedded = GenericAddressStructure::new(3, 8, 0, 1, 0x62);
+	assert_eq!(unsafe {embedded.read()}, Err(GasError::UnsupportedAddressSpace(3)));
+	assert_eq!(unsafe {embedded.write(0)}, Err(GasError::UnsupportedAddressSpace(3)));
+	assert_eq!(unsafe {core::ptr::read_volatile(pointer)}, 0x1122_3344_5566_ABC8);
+	serial_println!("[ok]");
+}
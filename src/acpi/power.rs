@@ -0,0 +1,197 @@
+use super::{find_table, find_tables, sdt::{DSDT, FADT, GenericAddressStructure, SSDT}};
+use x86_64::instructions::port::Port;
+
+// PM1 control register bits
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+const PM1_SLP_TYP_MASK: u16 = 0b111 << PM1_SLP_TYP_SHIFT;
+const PM1_SLP_EN: u16 = 1 << 13;
+
+// AML opcodes needed to pick the \_S5 package out of the DSDT
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+
+// Status reads to wait for the 8042's input buffer to drain, each one takes about a microsecond
+const KBC_READY_ATTEMPTS: u32 = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+	/// There is no FADT, or it has no PM1a control block
+	NoFadt,
+	/// Neither the DSDT nor an SSDT defines a usable \_S5 package
+	NoS5,
+	/// The firmware didn't hand over power management within the timeout
+	AcpiEnableTimeout,
+	/// SLP_EN was written but the machine is still running
+	StillRunning,
+}
+
+/// The SLP_TYP values to write to PM1a and PM1b to enter a sleep state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+	pub pm1a: u8,
+	pub pm1b: u8,
+}
+
+/// Resets the machine through the FADT reset register, then the keyboard controller, then a triple fault
+pub fn reboot() -> ! {
+	x86_64::instructions::interrupts::disable();
+	if let Some((register, value)) = find_table::<FADT>().and_then(|fadt| fadt.get_reset_register()) {
+		//the reset register is 8 bits wide by definition, whatever the firmware claims
+		let register = GenericAddressStructure::new(register.get_address_space(), 8, 0, register.get_access_size(), register.get_address());
+		if unsafe {register.write(value as u64)}.is_ok() {
+			spin_for_a_while();
+		}
+	}
+
+	//pulse the CPU reset line through the 8042
+	//without one the status port reads 0xFF forever, so give up waiting and write the command regardless
+	let mut status_port = Port::<u8>::new(0x64);
+	unsafe {
+		for _ in 0..KBC_READY_ATTEMPTS {
+			if status_port.read() & 0b10 == 0 {
+				break;
+			}
+		}
+		status_port.write(0xFE);
+	}
+	spin_for_a_while();
+
+	//with an empty IDT, the breakpoint becomes a double fault and then a triple fault
+	unsafe {
+		let empty = x86_64::structures::DescriptorTablePointer {limit: 0, base: 0};
+		x86_64::instructions::tables::lidt(&empty);
+	}
+	x86_64::instructions::interrupts::int3();
+	crate::hlt_loop();
+}
+
+/// Powers the machine off by entering the S5 soft-off state
+///
+/// Only returns if the machine couldn't be powered off.
+pub fn shutdown() -> PowerError {
+	let fadt = match find_table::<FADT>() {
+		Some(fadt) => fadt,
+		None => return PowerError::NoFadt,
+	};
+	let pm1a_control = match fadt.get_pm1a_control_register() {
+		Some(register) => register,
+		None => return PowerError::NoFadt,
+	};
+	let sleep_type = match s5_sleep_type() {
+		Some(sleep_type) => sleep_type,
+		None => return PowerError::NoS5,
+	};
+	if let Err(err) = enable_acpi_mode(fadt) {
+		return err;
+	}
+
+	x86_64::instructions::interrupts::disable();
+	write_sleep_type(&pm1a_control, sleep_type.pm1a);
+	if let Some(pm1b_control) = fadt.get_pm1b_control_register() {
+		write_sleep_type(&pm1b_control, sleep_type.pm1b);
+	}
+	spin_for_a_while();
+	PowerError::StillRunning
+}
+
+/// Switches from legacy (SMM) to ACPI power management, if the firmware hasn't already
+pub fn enable_acpi_mode(fadt: &FADT) -> Result<(), PowerError> {
+	let pm1a_control = fadt.get_pm1a_control_register().ok_or(PowerError::NoFadt)?;
+	let sci_enabled = || unsafe {pm1a_control.read()}.map_or(false, |value| value as u16 & PM1_SCI_EN != 0);
+	if sci_enabled() {
+		return Ok(());
+	}
+	//a zero SMI command port or enable value means the machine is always in ACPI mode
+	if fadt.get_smi_command_port() == 0 || fadt.get_acpi_enable() == 0 {
+		return Ok(());
+	}
+	let mut smi_command = Port::<u8>::new(fadt.get_smi_command_port() as u16);
+	unsafe {smi_command.write(fadt.get_acpi_enable())};
+	for _ in 0..1_000_000 {
+		if sci_enabled() {
+			return Ok(());
+		}
+		core::sync::atomic::spin_loop_hint();
+	}
+	Err(PowerError::AcpiEnableTimeout)
+}
+
+fn write_sleep_type(control: &GenericAddressStructure, sleep_type: u8) {
+	unsafe {
+		let value = control.read().unwrap_or(0) as u16 & !(PM1_SLP_TYP_MASK | PM1_SLP_EN);
+		let _ = control.write((value | ((sleep_type as u16) << PM1_SLP_TYP_SHIFT) | PM1_SLP_EN) as u64);
+	}
+}
+
+// Gives a reset or power off time to take effect before trying something else
+fn spin_for_a_while() {
+	for _ in 0..10_000_000 {
+		core::sync::atomic::spin_loop_hint();
+	}
+}
+
+/// Reads SLP_TYPa and SLP_TYPb for S5 by evaluating \_S5
+///
+/// Falls back to scanning the DSDT and SSDTs for the package when the interpreter couldn't load them.
+pub fn s5_sleep_type() -> Option<SleepType> {
+	if let Some(interpreter) = super::aml::AML.lock().as_mut() {
+		if let Ok((pm1a, pm1b)) = interpreter.sleep_type(5) {
+			return Some(SleepType {pm1a, pm1b});
+		}
+	}
+	let dsdt = find_table::<DSDT>().map(|dsdt| dsdt.get_aml());
+	dsdt.into_iter()
+		.chain(find_tables::<SSDT>().map(|ssdt| ssdt.get_aml()))
+		.find_map(parse_s5)
+}
+
+// Finds `Name(_S5, Package() {a, b, ...})` and returns the first two elements
+fn parse_s5(aml: &[u8]) -> Option<SleepType> {
+	let position = aml.windows(4).position(|window| window == b"_S5_")?;
+	//the name is preceded by NameOp, possibly with a root prefix in between
+	let name_op = match position.checked_sub(1).map(|i| aml[i]) {
+		Some(b'\\') => position.checked_sub(2).map(|i| aml[i]),
+		other => other,
+	};
+	if name_op != Some(AML_NAME_OP) {
+		return None;
+	}
+	let mut rest = aml.get(position + 4..)?;
+	if *rest.first()? != AML_PACKAGE_OP {
+		return None;
+	}
+	//the top two bits of the PkgLength lead byte count the extra length bytes, then comes NumElements
+	let length_bytes = (*rest.get(1)? >> 6) as usize;
+	rest = rest.get(2 + length_bytes + 1..)?;
+	let (pm1a, rest) = parse_byte_data(rest)?;
+	let (pm1b, _) = parse_byte_data(rest).unwrap_or((0, rest));
+	Some(SleepType {pm1a, pm1b})
+}
+
+fn parse_byte_data(aml: &[u8]) -> Option<(u8, &[u8])> {
+	match *aml.first()? {
+		AML_BYTE_PREFIX => Some((*aml.get(1)?, &aml[2..])),
+		AML_ZERO_OP => Some((0, &aml[1..])),
+		AML_ONE_OP => Some((1, &aml[1..])),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_parse_s5() {
+	serial_print!("test_parse_s5... ");
+	//Name(\_S5, Package(0x04) {0x05, Zero, Zero, Zero}) as emitted by iasl
+	let aml = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x00, 0x00, 0x00];
+	assert_eq!(parse_s5(&aml), Some(SleepType {pm1a: 5, pm1b: 0}));
+	//a method named _S5_ isn't the sleep package
+	let aml = [0x14, 0x06, b'_', b'S', b'5', b'_', 0x00];
+	assert_eq!(parse_s5(&aml), None);
+	serial_println!("[ok]");
+}
@@ -0,0 +1,426 @@
+use super::{aml, find_table, power::{self, PowerError}, sdt::{FADT, GenericAddressStructure}};
+use crate::println;
+use alloc::{sync::{Arc, Weak}, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{pin::Pin, task::{Context, Poll}};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{future::poll_fn, stream::Stream, task::AtomicWaker};
+use x86_64::instructions::{interrupts, port::Port};
+
+// PM1 status and enable register bits
+const PM1_TIMER: u16 = 1 << 0;
+const PM1_GLOBAL: u16 = 1 << 5;
+const PM1_POWER_BUTTON: u16 = 1 << 8;
+const PM1_SLEEP_BUTTON: u16 = 1 << 9;
+const PM1_RTC: u16 = 1 << 10;
+const PM1_WAKE: u16 = 1 << 15;
+
+// The only SCI line with a handler in the IDT, which is where every chipset we know of puts it
+const SCI_IRQ: u16 = 9;
+// The edge/level control registers, which mark IRQ0-7 and IRQ8-15 as level triggered
+const ELCR_SLAVE: u16 = 0x4D1;
+
+/// Something the firmware reported through the SCI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiEvent {
+	PowerButton,
+	SleepButton,
+	RtcAlarm,
+	/// A general purpose event, after its `\_GPE._Lxx` or `_Exx` method has run
+	Gpe(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SciError {
+	NoFadt,
+	/// The SCI is wired to an IRQ that we have no handler for
+	UnsupportedIrq(u16),
+	Power(PowerError),
+}
+impl From<PowerError> for SciError {
+	fn from(err: PowerError) -> Self {
+		SciError::Power(err)
+	}
+}
+
+// One GPE block, with a status byte and an enable byte for every 8 GPEs
+struct GpeBlock {
+	status: GenericAddressStructure,
+	/// Number of status bytes, which is also the number of enable bytes
+	length: u8,
+	base: u16,
+}
+impl GpeBlock {
+	// `block_length` is in bytes and comes from the FADT, the register's bit width saturates for blocks of 32 bytes or more
+	fn new(register: GenericAddressStructure, block_length: u8, base: u16) -> Self {
+		GpeBlock {status: register, length: block_length / 2, base}
+	}
+	fn byte(&self, index: u8) -> GenericAddressStructure {
+		GenericAddressStructure::new(self.status.get_address_space(), 8, 0, 1, self.status.get_address() + index as u64)
+	}
+	fn status_byte(&self, index: u8) -> GenericAddressStructure {
+		self.byte(index)
+	}
+	fn enable_byte(&self, index: u8) -> GenericAddressStructure {
+		self.byte(self.length + index)
+	}
+	fn contains(&self, gpe: u16) -> bool {
+		gpe >= self.base && gpe < self.base + self.length as u16 * 8
+	}
+}
+
+// The event registers, which never change once they are found
+struct Registers {
+	pm1_status: Vec<GenericAddressStructure>,
+	pm1_enable: Vec<GenericAddressStructure>,
+	gpe_blocks: Vec<GpeBlock>,
+}
+impl Registers {
+	// Splits each PM1 event block into its status and enable halves
+	// GPE blocks come with their length in bytes, and GPE1 with its base
+	fn new(pm1_blocks: impl Iterator<Item = GenericAddressStructure>, gpe0: Option<(GenericAddressStructure, u8)>, gpe1: Option<(GenericAddressStructure, u8, u16)>) -> Self {
+		let mut pm1_status = Vec::new();
+		let mut pm1_enable = Vec::new();
+		for block in pm1_blocks {
+			//the block holds the status register followed by the enable register, each half its width
+			let half = block.get_bit_width() / 2;
+			pm1_status.push(GenericAddressStructure::new(block.get_address_space(), half, 0, 0, block.get_address()));
+			pm1_enable.push(GenericAddressStructure::new(block.get_address_space(), half, 0, 0, block.get_address() + half as u64 / 8));
+		}
+		let mut gpe_blocks = Vec::new();
+		if let Some((gpe0, length)) = gpe0 {
+			gpe_blocks.push(GpeBlock::new(gpe0, length, 0));
+		}
+		if let Some((gpe1, length, base)) = gpe1 {
+			gpe_blocks.push(GpeBlock::new(gpe1, length, base));
+		}
+		Registers {pm1_status, pm1_enable, gpe_blocks}
+	}
+	// The PM1a and PM1b status registers are read as one by ORing them together
+	fn read_pm1_status(&self) -> u16 {
+		self.pm1_status.iter().fold(0, |status, register| status | unsafe {register.read()}.unwrap_or(0) as u16)
+	}
+	fn read_pm1_enable(&self) -> u16 {
+		self.pm1_enable.iter().fold(0, |enable, register| enable | unsafe {register.read()}.unwrap_or(0) as u16)
+	}
+	// Status bits are cleared by writing ones to them
+	fn clear_pm1_status(&self, bits: u16) {
+		for register in &self.pm1_status {
+			let _ = unsafe {register.write(bits as u64)};
+		}
+	}
+	fn write_pm1_enable(&self, bits: u16) {
+		for register in &self.pm1_enable {
+			let _ = unsafe {register.write(bits as u64)};
+		}
+	}
+	fn gpe_block(&self, gpe: u16) -> Option<(&GpeBlock, u8, u8)> {
+		let block = self.gpe_blocks.iter().find(|block| block.contains(gpe))?;
+		let offset = gpe - block.base;
+		Some((block, (offset / 8) as u8, 1 << (offset % 8)))
+	}
+	fn set_gpe_enabled(&self, gpe: u16, enabled: bool) {
+		if let Some((block, index, bit)) = self.gpe_block(gpe) {
+			let register = block.enable_byte(index);
+			unsafe {
+				let value = register.read().unwrap_or(0) as u8;
+				let value = if enabled {value | bit} else {value & !bit};
+				let _ = register.write(value as u64);
+			}
+		}
+	}
+	fn clear_gpe_status(&self, gpe: u16) {
+		if let Some((block, index, bit)) = self.gpe_block(gpe) {
+			let _ = unsafe {block.status_byte(index).write(bit as u64)};
+		}
+	}
+}
+
+static REGISTERS: OnceCell<Registers> = OnceCell::uninit();
+// Events taken by the interrupt handler and not yet seen by the dispatcher
+static PENDING: OnceCell<ArrayQueue<AcpiEvent>> = OnceCell::uninit();
+static DISPATCH_WAKER: AtomicWaker = AtomicWaker::new();
+static SUBSCRIBERS: spin::Mutex<Vec<Weak<Subscriber>>> = spin::Mutex::new(Vec::new());
+
+/// Puts the machine in ACPI mode, enables the fixed events and every GPE with a handler method, and unmasks the SCI
+pub fn init() -> Result<(), SciError> {
+	let fadt = find_table::<FADT>().ok_or(SciError::NoFadt)?;
+	if fadt.get_sci_interrupt() != SCI_IRQ {
+		return Err(SciError::UnsupportedIrq(fadt.get_sci_interrupt()));
+	}
+	power::enable_acpi_mode(fadt)?;
+
+	let registers = Registers::new(
+		fadt.get_pm1a_event_register().into_iter().chain(fadt.get_pm1b_event_register()),
+		fadt.get_gpe0_register().map(|gpe0| (gpe0, fadt.get_gpe0_length())),
+		fadt.get_gpe1_register().map(|gpe1| (gpe1, fadt.get_gpe1_length(), fadt.get_gpe1_base() as u16)),
+	);
+	if registers.pm1_status.is_empty() {
+		return Err(SciError::NoFadt);
+	}
+
+	//start from a clean slate, the firmware may have left events enabled or pending
+	registers.write_pm1_enable(0);
+	registers.clear_pm1_status(PM1_TIMER | PM1_GLOBAL | PM1_POWER_BUTTON | PM1_SLEEP_BUTTON | PM1_RTC | PM1_WAKE);
+	for block in &registers.gpe_blocks {
+		for index in 0..block.length {
+			unsafe {
+				let _ = block.enable_byte(index).write(0);
+				let _ = block.status_byte(index).write(0xFF);
+			}
+		}
+	}
+
+	let mut fixed = PM1_RTC;
+	if fadt.has_fixed_power_button() {
+		fixed |= PM1_POWER_BUTTON;
+	}
+	if fadt.has_fixed_sleep_button() {
+		fixed |= PM1_SLEEP_BUTTON;
+	}
+	registers.write_pm1_enable(fixed);
+	for (gpe, _) in gpe_methods() {
+		registers.set_gpe_enabled(gpe, true);
+	}
+
+	let _ = PENDING.try_init_once(|| ArrayQueue::new(64));
+	let _ = REGISTERS.try_init_once(|| registers);
+
+	interrupts::without_interrupts(|| {
+		let mut elcr = Port::<u8>::new(ELCR_SLAVE);
+		let mut master_mask = Port::<u8>::new(0x21);
+		let mut slave_mask = Port::<u8>::new(0xA1);
+		unsafe {
+			//the SCI is a shareable, level triggered, active low interrupt
+			let level = elcr.read();
+			elcr.write(level | 1 << (SCI_IRQ - 8));
+			let mask = master_mask.read();
+			master_mask.write(mask & !(1 << 2));
+			let mask = slave_mask.read();
+			slave_mask.write(mask & !(1 << (SCI_IRQ - 8)));
+		}
+	});
+	Ok(())
+}
+
+// Every GPE with a handler method in \_GPE, and whether it is edge triggered (_Exx) rather than level triggered (_Lxx)
+fn gpe_methods() -> Vec<(u16, bool)> {
+	let mut methods = Vec::new();
+	if let Some(interpreter) = aml::AML.lock().as_ref() {
+		for path in interpreter.namespace().children("\\_GPE") {
+			let name = &path[path.len() - 4..];
+			let edge = match &name[..2] {
+				"_E" => true,
+				"_L" => false,
+				_ => continue,
+			};
+			if let Ok(gpe) = u16::from_str_radix(&name[2..], 16) {
+				methods.push((gpe, edge));
+			}
+		}
+	}
+	methods
+}
+
+/// Called by the SCI interrupt handler
+///
+/// Acknowledges fixed events and masks GPEs until the dispatcher has run their methods.
+pub(crate) fn handle_interrupt() {
+	let (registers, pending) = match (REGISTERS.try_get(), PENDING.try_get()) {
+		(Ok(registers), Ok(pending)) => (registers, pending),
+		_ => return,
+	};
+	let push = |event| {
+		if pending.push(event).is_err() {
+			println!("WARNING: ACPI event queue full; dropping {:?}", event);
+		}
+	};
+
+	let fired = registers.read_pm1_status() & registers.read_pm1_enable();
+	if fired != 0 {
+		registers.clear_pm1_status(fired);
+		if fired & PM1_POWER_BUTTON != 0 {
+			push(AcpiEvent::PowerButton);
+		}
+		if fired & PM1_SLEEP_BUTTON != 0 {
+			push(AcpiEvent::SleepButton);
+		}
+		if fired & PM1_RTC != 0 {
+			push(AcpiEvent::RtcAlarm);
+		}
+	}
+
+	for block in &registers.gpe_blocks {
+		for index in 0..block.length {
+			let (status, enable) = unsafe {
+				(block.status_byte(index).read().unwrap_or(0) as u8, block.enable_byte(index).read().unwrap_or(0) as u8)
+			};
+			let fired = status & enable;
+			if fired == 0 {
+				continue;
+			}
+			//a level triggered GPE keeps the SCI asserted until its method has run
+			let _ = unsafe {block.enable_byte(index).write((enable & !fired) as u64)};
+			for bit in 0..8 {
+				if fired & (1 << bit) != 0 {
+					push(AcpiEvent::Gpe(block.base + index as u16 * 8 + bit));
+				}
+			}
+		}
+	}
+	DISPATCH_WAKER.wake();
+}
+
+/// Runs GPE methods and passes every event on to the subscribers, must be spawned for `events` to produce anything
+pub async fn dispatch_events() {
+	let pending = match PENDING.try_get() {
+		Ok(pending) => pending,
+		Err(_) => return,
+	};
+	loop {
+		let event = poll_fn(|cx| {
+			if let Ok(event) = pending.pop() {
+				return Poll::Ready(event);
+			}
+			DISPATCH_WAKER.register(cx.waker());
+			match pending.pop() {
+				Ok(event) => {
+					DISPATCH_WAKER.take();
+					Poll::Ready(event)
+				}
+				Err(_) => Poll::Pending,
+			}
+		}).await;
+		if let AcpiEvent::Gpe(gpe) = event {
+			run_gpe_method(gpe);
+		}
+		broadcast(event);
+	}
+}
+
+// Edge triggered GPEs are acknowledged before their method runs so that a new edge isn't lost, level triggered ones after
+fn run_gpe_method(gpe: u16) {
+	let registers = match REGISTERS.try_get() {
+		Ok(registers) => registers,
+		Err(_) => return,
+	};
+	let method = gpe_methods().into_iter().find(|(number, _)| *number == gpe);
+	let edge = match method {
+		Some((_, edge)) => edge,
+		None => {
+			//nothing can quiet a GPE without a method, so leave it masked
+			registers.clear_gpe_status(gpe);
+			return;
+		}
+	};
+	if edge {
+		registers.clear_gpe_status(gpe);
+	}
+	let path = alloc::format!("\\_GPE._{}{:02X}", if edge {'E'} else {'L'}, gpe);
+	if let Some(interpreter) = aml::AML.lock().as_mut() {
+		if let Err(err) = interpreter.evaluate(&path, Vec::new()) {
+			println!("AML: {} failed: {:?}", path, err);
+		}
+	}
+	if !edge {
+		registers.clear_gpe_status(gpe);
+	}
+	registers.set_gpe_enabled(gpe, true);
+}
+
+fn broadcast(event: AcpiEvent) {
+	let mut subscribers = SUBSCRIBERS.lock();
+	subscribers.retain(|subscriber| match subscriber.upgrade() {
+		Some(subscriber) => {
+			if subscriber.queue.push(event).is_err() {
+				println!("WARNING: ACPI event subscriber is not keeping up; dropping {:?}", event);
+			}
+			subscriber.waker.wake();
+			true
+		}
+		None => false,
+	});
+}
+
+struct Subscriber {
+	queue: ArrayQueue<AcpiEvent>,
+	waker: AtomicWaker,
+}
+
+/// A stream of every ACPI event from the moment it was created
+pub struct EventStream {
+	subscriber: Arc<Subscriber>,
+}
+impl Stream for EventStream {
+	type Item = AcpiEvent;
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<AcpiEvent>> {
+		if let Ok(event) = self.subscriber.queue.pop() {
+			return Poll::Ready(Some(event));
+		}
+		self.subscriber.waker.register(cx.waker());
+		match self.subscriber.queue.pop() {
+			Ok(event) => {
+				self.subscriber.waker.take();
+				Poll::Ready(Some(event))
+			}
+			Err(_) => Poll::Pending,
+		}
+	}
+}
+
+/// Subscribes to ACPI events, each subscriber sees every event
+pub fn events() -> EventStream {
+	let subscriber = Arc::new(Subscriber {queue: ArrayQueue::new(32), waker: AtomicWaker::new()});
+	SUBSCRIBERS.lock().push(Arc::downgrade(&subscriber));
+	EventStream {subscriber}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_sci_registers() {
+	serial_print!("test_sci_registers... ");
+	let io = |port: u16, width: u8| GenericAddressStructure::io(port, width);
+	let registers = Registers::new(
+		[io(0x400, 32), io(0x500, 32)].iter().copied(),
+		Some((io(0x420, 64), 8)),
+		Some((io(0x440, 32), 4, 0x40)),
+	);
+	//each PM1 block is a 16-bit status register followed by a 16-bit enable register
+	assert_eq!(registers.pm1_status.len(), 2);
+	assert_eq!(registers.pm1_status[0].get_address(), 0x400);
+	assert_eq!(registers.pm1_enable[0].get_address(), 0x402);
+	assert_eq!(registers.pm1_enable[1].get_address(), 0x502);
+	assert_eq!(registers.pm1_enable[1].get_bit_width(), 16);
+
+	//GPE0 has 4 status bytes then 4 enable bytes, covering GPEs 0x00-0x1F
+	let gpe0 = &registers.gpe_blocks[0];
+	assert_eq!(gpe0.length, 4);
+	assert_eq!(gpe0.status_byte(1).get_address(), 0x421);
+	assert_eq!(gpe0.enable_byte(1).get_address(), 0x425);
+	assert_eq!(gpe0.enable_byte(1).get_bit_width(), 8);
+
+	let (block, index, bit) = registers.gpe_block(0x05).unwrap();
+	assert_eq!((block.base, index, bit), (0, 0, 1 << 5));
+	let (block, index, bit) = registers.gpe_block(0x1F).unwrap();
+	assert_eq!((block.base, index, bit), (0, 3, 1 << 7));
+	//GPE1 numbers start at its base, not after GPE0
+	assert!(registers.gpe_block(0x20).is_none());
+	let (block, index, bit) = registers.gpe_block(0x4A).unwrap();
+	assert_eq!((block.base, index, bit), (0x40, 1, 1 << 2));
+	assert_eq!(block.enable_byte(index).get_address(), 0x443);
+	assert!(registers.gpe_block(0x50).is_none());
+
+	//a 32 byte block saturates the legacy register's bit width, the length must come from the FADT's byte count
+	let registers = Registers::new(core::iter::empty(), Some((io(0x1800, 255), 32)), None);
+	let gpe0 = &registers.gpe_blocks[0];
+	assert_eq!(gpe0.length, 16);
+	assert_eq!(gpe0.status_byte(15).get_address(), 0x180F);
+	assert_eq!(gpe0.enable_byte(0).get_address(), 0x1810);
+	let (block, index, bit) = registers.gpe_block(0x7F).unwrap();
+	assert_eq!((index, bit), (15, 1 << 7));
+	assert_eq!(block.enable_byte(index).get_address(), 0x181F);
+	assert!(registers.gpe_block(0x80).is_none());
+	serial_println!("[ok]");
+}
@@ -1,6 +1,7 @@
-use crate::acpi::ACPISDTHeader;
-#[derive(Debug)]
-#[repr(C)]
+use crate::acpi::{ACPISDTHeader, Sdt};
+// ACPI tables are byte packed: boot_architecture_flags and every GenericAddressStructure are unaligned
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
 pub struct FADT {
     header: ACPISDTHeader,
     firmware_ctrl: u32,
@@ -66,8 +67,139 @@ pub struct FADT {
     x_gpe1_block: GenericAddressStructure,
 }
 
-#[derive(Debug)]
-#[repr(C)]
+unsafe impl Sdt for FADT {
+    const SIGNATURE: &'static [u8;4] = b"FACP";
+    //ACPI 1.0 FADTs end after the flags, the accessors check the length for everything past them
+    const MIN_LENGTH: usize = 116;
+}
+impl FADT {
+    // ACPI 1.0 FADTs end before reset_value, which sits at this offset
+    const RESET_VALUE_END: usize = 129;
+    // ACPI 1.0 FADTs end before x_dsdt, which runs up to this offset
+    const X_DSDT_END: usize = 148;
+    // ACPI 2.0 FADTs end after each of these extended register blocks
+    const X_PM1A_EVENT_END: usize = 160;
+    const X_PM1B_EVENT_END: usize = 172;
+    const X_PM1A_CONTROL_END: usize = 184;
+    const X_PM1B_CONTROL_END: usize = 196;
+    const X_PM2_CONTROL_END: usize = 208;
+    const X_PM_TIMER_END: usize = 220;
+    const X_GPE0_END: usize = 232;
+    const X_GPE1_END: usize = 244;
+    // Set in `flags` when the power or sleep button is a control method device instead of a fixed feature
+    const FLAG_PWR_BUTTON: u32 = 1 << 4;
+    const FLAG_SLP_BUTTON: u32 = 1 << 5;
+    // Set in `flags` when `reset_reg` is supported
+    const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+    pub fn get_smi_command_port(&self) -> u32 {
+        self.smi_command_port
+    }
+    /// The value to write to the SMI command port to hand power management over to the OS
+    pub fn get_acpi_enable(&self) -> u8 {
+        self.acpi_enable
+    }
+    pub fn get_acpi_disable(&self) -> u8 {
+        self.acpi_disable
+    }
+    /// The I/O port of the PM1a control block
+    pub fn get_pm1a_control_block(&self) -> u32 {
+        self.pm1a_control_block
+    }
+    /// The I/O port of the PM1b control block, 0 if there is none
+    pub fn get_pm1b_control_block(&self) -> u32 {
+        self.pm1b_control_block
+    }
+    /// The PM1a event block, which holds PM1_STS in its first half and PM1_EN in its second
+    pub fn get_pm1a_event_register(&self) -> Option<GenericAddressStructure> {
+        self.register(Self::X_PM1A_EVENT_END, self.x_pm1a_event_block, self.pm1a_event_block, self.pm1_event_length)
+    }
+    pub fn get_pm1b_event_register(&self) -> Option<GenericAddressStructure> {
+        self.register(Self::X_PM1B_EVENT_END, self.x_pm1b_event_block, self.pm1b_event_block, self.pm1_event_length)
+    }
+    pub fn get_pm1a_control_register(&self) -> Option<GenericAddressStructure> {
+        self.register(Self::X_PM1A_CONTROL_END, self.x_pm1a_control_block, self.pm1a_control_block, self.pm1_control_length)
+    }
+    pub fn get_pm1b_control_register(&self) -> Option<GenericAddressStructure> {
+        self.register(Self::X_PM1B_CONTROL_END, self.x_pm1b_control_block, self.pm1b_control_block, self.pm1_control_length)
+    }
+    pub fn get_pm2_control_register(&self) -> Option<GenericAddressStructure> {
+        self.register(Self::X_PM2_CONTROL_END, self.x_pm2_control_block, self.pm2_control_block, self.pm2_control_length)
+    }
+    pub fn get_pm_timer_register(&self) -> Option<GenericAddressStructure> {
+        self.register(Self::X_PM_TIMER_END, self.x_pm_timer_block, self.pm_timer_block, self.pm_timer_length)
+    }
+    /// The GPE0 block, which holds the GPE status bytes in its first half and the enable bytes in its second
+    pub fn get_gpe0_register(&self) -> Option<GenericAddressStructure> {
+        self.register(Self::X_GPE0_END, self.x_gpe0_block, self.gpe0_block, self.gpe0_length)
+    }
+    pub fn get_gpe1_register(&self) -> Option<GenericAddressStructure> {
+        self.register(Self::X_GPE1_END, self.x_gpe1_block, self.gpe1_block, self.gpe1_length)
+    }
+    /// The length in bytes of the GPE0 block, its status bytes followed by as many enable bytes
+    pub fn get_gpe0_length(&self) -> u8 {
+        Self::block_length(self.gpe0_length, self.get_gpe0_register())
+    }
+    pub fn get_gpe1_length(&self) -> u8 {
+        Self::block_length(self.gpe1_length, self.get_gpe1_register())
+    }
+    // A GAS can't describe blocks of 32 bytes or more, so the byte length is only derived from one when the FADT leaves it out
+    fn block_length(length: u8, register: Option<GenericAddressStructure>) -> u8 {
+        match (length, register) {
+            (0, Some(register)) => register.get_bit_width() / 8,
+            (length, _) => length,
+        }
+    }
+    /// The GPE number of the first GPE1 bit, GPE0 always starts at 0
+    pub fn get_gpe1_base(&self) -> u8 {
+        self.gpe1_base
+    }
+    /// The legacy IRQ the SCI is wired to
+    pub fn get_sci_interrupt(&self) -> u16 {
+        self.sci_interrupt
+    }
+    /// Whether the power button is reported through PM1 fixed events rather than a device in the namespace
+    pub fn has_fixed_power_button(&self) -> bool {
+        self.flags & Self::FLAG_PWR_BUTTON == 0
+    }
+    pub fn has_fixed_sleep_button(&self) -> bool {
+        self.flags & Self::FLAG_SLP_BUTTON == 0
+    }
+    // Prefers the extended register when the table is long enough to hold it, otherwise describes the legacy I/O block
+    fn register(&self, end: usize, extended: GenericAddressStructure, legacy: u32, length: u8) -> Option<GenericAddressStructure> {
+        let register = if self.header().length() >= end && extended.is_present() {
+            extended
+        }
+        else {
+            //the legacy block lengths are in bytes, GPE blocks of 32 bytes or more saturate, see get_gpe0_length
+            GenericAddressStructure::io(legacy as u16, length.saturating_mul(8))
+        };
+        if register.is_present() {Some(register)} else {None}
+    }
+
+    /// The register to write `reset_value` to to reset the machine, if the firmware supports it
+    pub fn get_reset_register(&self) -> Option<(GenericAddressStructure, u8)> {
+        if self.header().length() >= Self::RESET_VALUE_END && self.flags & Self::FLAG_RESET_REG_SUP != 0 {
+            Some((self.reset_reg, self.reset_value))
+        }
+        else {
+            None
+        }
+    }
+
+    /// The physical address of the DSDT, preferring the 64-bit pointer when the table is new enough to have one
+    pub fn get_dsdt_address(&self) -> u64 {
+        if self.header().length() >= Self::X_DSDT_END && self.x_dsdt != 0 {
+            self.x_dsdt
+        }
+        else {
+            self.dsdt as u64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
 pub struct GenericAddressStructure {
     address_space: u8,	// 0 - system memory, 1 - system I/O
     bit_width: u8,
@@ -75,6 +207,35 @@ pub struct GenericAddressStructure {
     access_size: u8,
     address: u64,
 }
+impl GenericAddressStructure {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+    pub const PCI_CONFIG: u8 = 2;
+
+    pub const fn new(address_space: u8, bit_width: u8, bit_offset: u8, access_size: u8, address: u64) -> Self {
+        GenericAddressStructure {address_space, bit_width, bit_offset, access_size, address}
+    }
+    /// A register of `bit_width` bits at an I/O port, as the FADT describes its ACPI 1.0 register blocks
+    pub const fn io(port: u16, bit_width: u8) -> Self {
+        Self::new(Self::SYSTEM_IO, bit_width, 0, 0, port as u64)
+    }
+    pub fn get_address_space(&self) -> u8 {
+        self.address_space
+    }
+    pub fn get_bit_width(&self) -> u8 {
+        self.bit_width
+    }
+    pub fn get_bit_offset(&self) -> u8 {
+        self.bit_offset
+    }
+    /// 0 for undefined, otherwise 1 for byte, 2 for word, 3 for dword and 4 for qword access
+    pub fn get_access_size(&self) -> u8 {
+        self.access_size
+    }
+    pub fn get_address(&self) -> u64 {
+        self.address
+    }
+}
 
 #[repr(C)]
 pub struct MADT {
@@ -83,8 +244,11 @@ pub struct MADT {
 	flags: u32,
 	//todo: figure out a good way to access the interrupt devices through this
 }
+unsafe impl Sdt for MADT {
+	const SIGNATURE: &'static [u8;4] = b"APIC";
+}
 
-#[repr(C)]
+#[repr(C, packed)]
 pub struct HPET {
 	header: ACPISDTHeader,
 	hardware_rev_id: u8,
@@ -99,14 +263,222 @@ pub struct HPET {
     minimum_tick: u16,
     page_protection: u8,
 }
+unsafe impl Sdt for HPET {
+	const SIGNATURE: &'static [u8;4] = b"HPET";
+}
 impl HPET {
 	pub fn get_comparator_count(&self) -> u8 {
 		self.packed_field & 0b0001_1111
 	}
 	pub fn get_counter_size(&self) -> u8 {
-		self.packed_field & 0b0010_0000 >> 5
+		(self.packed_field & 0b0010_0000) >> 5
 	}
 	pub fn get_legacy_replacement(&self) -> u8 {
-		self.packed_field & 0b1000_0000 >> 7
+		(self.packed_field & 0b1000_0000) >> 7
+	}
+	/// The physical address of the HPET's register block, it is always in system memory
+	pub fn get_base_address(&self) -> u64 {
+		self.address.address
+	}
+	pub fn get_hpet_number(&self) -> u8 {
+		self.hpet_number
+	}
+	/// The smallest periodic tick, in main counter ticks, that doesn't lose interrupts
+	pub fn get_minimum_tick(&self) -> u16 {
+		self.minimum_tick
 	}
-}
\ No newline at end of file
+}
+
+/// The Differentiated System Description Table, the firmware's main AML definition block
+#[repr(C)]
+pub struct DSDT {
+	header: ACPISDTHeader,
+}
+unsafe impl Sdt for DSDT {
+	const SIGNATURE: &'static [u8;4] = b"DSDT";
+}
+impl DSDT {
+	pub fn get_aml(&self) -> &[u8] {
+		self.header.data()
+	}
+}
+
+/// A Secondary System Description Table, there may be any number of these extending the DSDT
+#[repr(C)]
+pub struct SSDT {
+	header: ACPISDTHeader,
+}
+unsafe impl Sdt for SSDT {
+	const SIGNATURE: &'static [u8;4] = b"SSDT";
+}
+impl SSDT {
+	pub fn get_aml(&self) -> &[u8] {
+		self.header.data()
+	}
+}
+
+/// The PCI Express memory mapped configuration table, listing the ECAM region of every segment group
+#[repr(C)]
+pub struct MCFG {
+	header: ACPISDTHeader,
+}
+unsafe impl Sdt for MCFG {
+	const SIGNATURE: &'static [u8;4] = b"MCFG";
+}
+impl MCFG {
+	// 8 reserved bytes sit between the header and the first allocation
+	const ENTRIES_OFFSET: usize = 8;
+	const ENTRY_SIZE: usize = 16;
+
+	pub fn get_entries(&self) -> impl Iterator<Item = McfgEntry> + '_ {
+		self.header.data().get(Self::ENTRIES_OFFSET..).unwrap_or(&[])
+			.chunks_exact(Self::ENTRY_SIZE)
+			.map(|entry| {
+				let mut base_address = [0; 8];
+				base_address.copy_from_slice(&entry[0..8]);
+				McfgEntry {
+					base_address: u64::from_le_bytes(base_address),
+					segment: u16::from_le_bytes([entry[8], entry[9]]),
+					start_bus: entry[10],
+					end_bus: entry[11],
+				}
+			})
+	}
+}
+
+/// The ECAM region that covers buses `start_bus..=end_bus` of a PCI segment group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+	/// The physical address of bus 0's configuration space, even when `start_bus` isn't 0
+	pub base_address: u64,
+	pub segment: u16,
+	pub start_bus: u8,
+	pub end_bus: u8,
+}
+
+/// The System Resource Affinity Table, which assigns CPUs and memory ranges to NUMA proximity domains
+#[repr(C)]
+pub struct SRAT {
+	header: ACPISDTHeader,
+}
+unsafe impl Sdt for SRAT {
+	const SIGNATURE: &'static [u8;4] = b"SRAT";
+}
+impl SRAT {
+	// A reserved dword and qword sit between the header and the first entry
+	const ENTRIES_OFFSET: usize = 12;
+	const PROCESSOR_AFFINITY: u8 = 0;
+	const MEMORY_AFFINITY: u8 = 1;
+	const X2APIC_AFFINITY: u8 = 2;
+	// Bit 0 of every entry's flags
+	const FLAG_ENABLED: u32 = 1 << 0;
+
+	pub fn get_entries(&self) -> impl Iterator<Item = SratEntry> + '_ {
+		let mut entries = self.header.data().get(Self::ENTRIES_OFFSET..).unwrap_or(&[]);
+		core::iter::from_fn(move || {
+			loop {
+				let length = *entries.get(1)? as usize;
+				if length < 2 || length > entries.len() {
+					return None;
+				}
+				let (entry, rest) = entries.split_at(length);
+				entries = rest;
+				if let Some(entry) = Self::parse_entry(entry) {
+					return Some(entry);
+				}
+			}
+		})
+	}
+	// None for entries that are too short to hold their fields
+	fn parse_entry(entry: &[u8]) -> Option<SratEntry> {
+		let dword = |offset: usize| entry.get(offset..offset + 4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+		match entry[0] {
+			Self::PROCESSOR_AFFINITY => {
+				//the domain is split, its low byte is at 2 and the high three bytes at 9
+				let high = entry.get(9..12)?;
+				Some(SratEntry::Processor {
+					apic_id: *entry.get(3)? as u32,
+					domain: *entry.get(2)? as u32 | (high[0] as u32) << 8 | (high[1] as u32) << 16 | (high[2] as u32) << 24,
+					enabled: dword(4)? & Self::FLAG_ENABLED != 0,
+				})
+			}
+			Self::MEMORY_AFFINITY => {
+				let flags = dword(28)?;
+				Some(SratEntry::Memory {
+					domain: dword(2)?,
+					base: dword(8)? as u64 | (dword(12)? as u64) << 32,
+					length: dword(16)? as u64 | (dword(20)? as u64) << 32,
+					enabled: flags & Self::FLAG_ENABLED != 0,
+					hot_pluggable: flags & (1 << 1) != 0,
+					non_volatile: flags & (1 << 2) != 0,
+				})
+			}
+			Self::X2APIC_AFFINITY => Some(SratEntry::Processor {
+				apic_id: dword(8)?,
+				domain: dword(4)?,
+				enabled: dword(12)? & Self::FLAG_ENABLED != 0,
+			}),
+			other => Some(SratEntry::Unknown(other)),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SratEntry {
+	/// A local APIC or x2APIC and the domain it belongs to
+	Processor {apic_id: u32, domain: u32, enabled: bool},
+	/// A physical memory range and the domain it belongs to
+	Memory {base: u64, length: u64, domain: u32, enabled: bool, hot_pluggable: bool, non_volatile: bool},
+	/// An entry type we don't use, such as GICC or generic initiator affinity
+	Unknown(u8),
+}
+
+/// The System Locality Information Table, the relative distances between proximity domains
+#[repr(C)]
+pub struct SLIT {
+	header: ACPISDTHeader,
+}
+unsafe impl Sdt for SLIT {
+	const SIGNATURE: &'static [u8;4] = b"SLIT";
+}
+impl SLIT {
+	pub fn get_locality_count(&self) -> u64 {
+		let data = self.header.data();
+		if data.len() < 8 {
+			return 0;
+		}
+		let mut count = [0; 8];
+		count.copy_from_slice(&data[..8]);
+		u64::from_le_bytes(count)
+	}
+	/// The distance from `from` to `to`, 10 meaning local and 255 unreachable
+	pub fn get_distance(&self, from: u32, to: u32) -> Option<u8> {
+		let count = self.get_locality_count();
+		if from as u64 >= count || to as u64 >= count {
+			return None;
+		}
+		let index = 8 + from as u64 * count + to as u64;
+		self.header.data().get(index as usize).copied()
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_hpet_packed_field() {
+	serial_print!("test_hpet_packed_field... ");
+	let mut bytes = [0u8; core::mem::size_of::<HPET>()];
+	let hpet = |bytes: &[u8]| unsafe {&*(bytes.as_ptr() as *const HPET)};
+	//packed_field follows the 36 byte header and the revision
+	bytes[37] = 0b1010_0011;
+	assert_eq!(hpet(&bytes).get_comparator_count(), 3);
+	assert_eq!(hpet(&bytes).get_counter_size(), 1);
+	assert_eq!(hpet(&bytes).get_legacy_replacement(), 1);
+	//without the parentheses, the masks would be shifted instead and bit 0 would leak through
+	bytes[37] = 0b0100_0001;
+	assert_eq!(hpet(&bytes).get_comparator_count(), 1);
+	assert_eq!(hpet(&bytes).get_counter_size(), 0);
+	assert_eq!(hpet(&bytes).get_legacy_replacement(), 0);
+	serial_println!("[ok]");
+}
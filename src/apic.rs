@@ -0,0 +1,84 @@
+use crate::memory;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{PhysAddr, registers::model_specific::Msr};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+
+// Register offsets into the local APIC's MMIO page
+const REG_ID: usize = 0x20;
+const REG_EOI: usize = 0xB0;
+const REG_SPURIOUS: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+
+const SPURIOUS_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Virtual address of this machine's local APIC registers, 0 until `init` has run
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Software-enables the local APIC of the calling CPU
+///
+/// The legacy PIC keeps working, since firmware leaves LINT0 in virtual wire mode.
+pub fn init() {
+	let base = unsafe {Msr::new(IA32_APIC_BASE).read()} & 0xF_FFFF_F000;
+	let virt = memory::phys_to_virt(PhysAddr::new(base));
+	LAPIC_BASE.store(virt.as_u64(), Ordering::Relaxed);
+	unsafe {
+		let spurious = read(REG_SPURIOUS);
+		write(REG_SPURIOUS, spurious | SPURIOUS_ENABLE | SPURIOUS_VECTOR as u32);
+	}
+}
+
+pub fn is_initialized() -> bool {
+	LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Reads a local APIC register
+/// # Safety
+/// - `init` must have been called
+/// - `reg` must be a valid register offset
+pub unsafe fn read(reg: usize) -> u32 {
+	let base = LAPIC_BASE.load(Ordering::Relaxed);
+	core::ptr::read_volatile((base as usize + reg) as *const u32)
+}
+
+/// Writes a local APIC register
+/// # Safety
+/// - `init` must have been called
+/// - `reg` must be a valid register offset, and writing it must not break interrupt delivery
+pub unsafe fn write(reg: usize, value: u32) {
+	let base = LAPIC_BASE.load(Ordering::Relaxed);
+	core::ptr::write_volatile((base as usize + reg) as *mut u32, value);
+}
+
+/// Returns the local APIC ID of the calling CPU, or 0 before the APIC is set up
+pub fn id() -> u32 {
+	if is_initialized() {
+		unsafe {read(REG_ID) >> 24}
+	}
+	else {
+		0
+	}
+}
+
+/// Signals the end of an interrupt that was delivered through the local APIC
+pub fn end_of_interrupt() {
+	unsafe {write(REG_EOI, 0)};
+}
+
+/// Sends a fixed interrupt with `vector` to the CPU with the given local APIC ID
+pub fn send_ipi(apic_id: u32, vector: u8) {
+	use x86_64::instructions::interrupts;
+
+	//the two halves of the ICR must be written without another IPI being sent in between
+	interrupts::without_interrupts(|| unsafe {
+		write(REG_ICR_HIGH, apic_id << 24);
+		write(REG_ICR_LOW, vector as u32);
+		while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+			core::sync::atomic::spin_loop_hint();
+		}
+	});
+}
@@ -0,0 +1,201 @@
+use crate::{apic, hpet, interrupts::InterruptIndex, println, task::executor::{self, MAX_CPUS}, timer, tsc};
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::registers::model_specific::Msr;
+
+// Timer register offsets into the local APIC's MMIO page
+const REG_LVT_TIMER: usize = 0x320;
+const REG_INITIAL_COUNT: usize = 0x380;
+const REG_CURRENT_COUNT: usize = 0x390;
+const REG_DIVIDE_CONFIG: usize = 0x3E0;
+
+// LVT timer entry bits
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 0b01 << 17;
+const LVT_TSC_DEADLINE: u32 = 0b10 << 17;
+
+// The counter runs at the bus clock divided by 16
+const DIVIDE_BY_16: u32 = 0b0011;
+
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+const CALIBRATION_MS: u64 = 10;
+
+// Counter ticks per second, the same on every CPU, 0 until `calibrate` has run
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TSC_DEADLINE_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// Interrupts taken by each CPU's timer, by executor CPU index
+static TICKS: [AtomicU64; MAX_CPUS] = [AtomicU64::new(0); MAX_CPUS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+	/// Counts down from the initial count and fires once
+	OneShot,
+	/// Reloads the initial count every time it reaches zero
+	Periodic,
+	/// Fires when the TSC reaches the value in IA32_TSC_DEADLINE
+	TscDeadline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicTimerError {
+	/// `calibrate` hasn't run, or the timer didn't count
+	NotCalibrated,
+	/// The CPU doesn't have TSC-deadline mode, or the TSC frequency is unknown
+	TscDeadlineUnsupported,
+	/// The period is too short for the timer to count, or too long for its 32-bit counter
+	PeriodOutOfRange,
+}
+
+// An LVT timer entry delivering to our vector
+fn lvt(bits: u32) -> u32 {
+	bits | InterruptIndex::LocalTimer.as_u8() as u32
+}
+
+/// Measures the timer's frequency against the HPET, or the PIT if there is no HPET
+///
+/// Runs once, on the boot CPU; every local APIC timer in the system counts at the same rate.
+pub fn calibrate() {
+	let tsc_deadline = unsafe {__cpuid(1)}.ecx & (1 << 24) != 0;
+	TSC_DEADLINE_SUPPORTED.store(tsc_deadline, Ordering::Relaxed);
+	let freq = unsafe {
+		apic::write(REG_DIVIDE_CONFIG, DIVIDE_BY_16);
+		apic::write(REG_LVT_TIMER, lvt(LVT_MASKED));
+		apic::write(REG_INITIAL_COUNT, u32::MAX);
+		let elapsed_ns = match hpet::get() {
+			Some(hpet) => hpet.delay(CALIBRATION_MS * 1_000_000),
+			None => {
+				timer::pit_delay(CALIBRATION_MS);
+				CALIBRATION_MS * 1_000_000
+			}
+		};
+		let counted = u32::MAX - apic::read(REG_CURRENT_COUNT);
+		apic::write(REG_INITIAL_COUNT, 0);
+		(counted as u128 * 1_000_000_000 / elapsed_ns as u128) as u64
+	};
+	FREQUENCY.store(freq, Ordering::Relaxed);
+	println!("Local APIC timer: {} Hz, TSC-deadline: {}", freq, tsc_deadline);
+}
+
+/// Sets up the calling CPU's timer, leaving it stopped
+///
+/// Every CPU that runs an executor must call this once, after `calibrate` has run on the boot CPU.
+pub fn init_cpu() {
+	unsafe {
+		apic::write(REG_DIVIDE_CONFIG, DIVIDE_BY_16);
+		apic::write(REG_LVT_TIMER, lvt(LVT_MASKED));
+		apic::write(REG_INITIAL_COUNT, 0);
+	}
+}
+
+/// Counter ticks per second, or 0 before calibration
+pub fn frequency() -> u64 {
+	FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Whether `set_tsc_deadline` can be used
+pub fn supports_tsc_deadline() -> bool {
+	TSC_DEADLINE_SUPPORTED.load(Ordering::Relaxed) && tsc::frequency() != 0
+}
+
+/// Number of timer interrupts the calling CPU has taken, 0 if it hasn't been registered with the executor
+pub fn ticks() -> u64 {
+	executor::current_cpu().map_or(0, |cpu| TICKS[cpu].load(Ordering::Relaxed))
+}
+
+// Converts a duration to counter ticks, rounding up
+fn duration_to_count(duration: Duration) -> Result<u32, ApicTimerError> {
+	let freq = frequency() as u128;
+	if freq == 0 {
+		return Err(ApicTimerError::NotCalibrated);
+	}
+	let count = (duration.as_nanos() * freq + 999_999_999) / 1_000_000_000;
+	if count == 0 || count > u32::MAX as u128 {
+		return Err(ApicTimerError::PeriodOutOfRange);
+	}
+	Ok(count as u32)
+}
+
+fn program(mode: Mode, count: u32) {
+	let mode_bits = match mode {
+		Mode::OneShot => 0,
+		Mode::Periodic => LVT_PERIODIC,
+		Mode::TscDeadline => LVT_TSC_DEADLINE,
+	};
+	unsafe {
+		//writing the initial count starts the timer, so the mode has to be set first
+		apic::write(REG_INITIAL_COUNT, 0);
+		apic::write(REG_LVT_TIMER, lvt(mode_bits));
+		if mode != Mode::TscDeadline {
+			apic::write(REG_INITIAL_COUNT, count);
+		}
+	}
+}
+
+/// Interrupts the calling CPU every `period`
+pub fn start_periodic(period: Duration) -> Result<(), ApicTimerError> {
+	let count = duration_to_count(period)?;
+	program(Mode::Periodic, count);
+	Ok(())
+}
+
+/// Interrupts the calling CPU once, after `delay`
+pub fn start_one_shot(delay: Duration) -> Result<(), ApicTimerError> {
+	let count = duration_to_count(delay)?;
+	program(Mode::OneShot, count);
+	Ok(())
+}
+
+/// Interrupts the calling CPU once the TSC reaches `deadline`
+pub fn set_tsc_deadline(deadline: u64) -> Result<(), ApicTimerError> {
+	if !supports_tsc_deadline() {
+		return Err(ApicTimerError::TscDeadlineUnsupported);
+	}
+	program(Mode::TscDeadline, 0);
+	//the LVT write has to be visible before the MSR write arms the timer
+	core::sync::atomic::fence(Ordering::SeqCst);
+	unsafe {Msr::new(IA32_TSC_DEADLINE).write(deadline)};
+	Ok(())
+}
+
+/// Interrupts the calling CPU once after `delay`, using TSC-deadline mode when it is available
+///
+/// Delays longer than the one-shot counter can hold fire early, which only costs a spurious wakeup.
+pub fn wake_after(delay: Duration) {
+	if supports_tsc_deadline() {
+		let cycles = delay.as_nanos() * tsc::frequency() as u128 / 1_000_000_000;
+		let deadline = tsc::read().saturating_add(cycles.max(1) as u64);
+		if set_tsc_deadline(deadline).is_ok() {
+			return;
+		}
+	}
+	let count = match duration_to_count(delay) {
+		Ok(count) => count,
+		//zero delays fire straight away, overlong ones fire early
+		Err(ApicTimerError::PeriodOutOfRange) => if delay.as_nanos() == 0 {1} else {u32::MAX},
+		Err(_) => return,
+	};
+	program(Mode::OneShot, count);
+}
+
+/// Stops the calling CPU's timer
+pub fn stop() {
+	unsafe {
+		apic::write(REG_LVT_TIMER, lvt(LVT_MASKED));
+		apic::write(REG_INITIAL_COUNT, 0);
+		if supports_tsc_deadline() {
+			Msr::new(IA32_TSC_DEADLINE).write(0);
+		}
+	}
+}
+
+/// Called by the local APIC timer interrupt handler
+/// # Safety
+/// Must not block or allocate memory on the heap
+pub(crate) fn handle_interrupt() {
+	if let Some(cpu) = executor::current_cpu() {
+		TICKS[cpu].fetch_add(1, Ordering::Relaxed);
+	}
+}
@@ -0,0 +1,347 @@
+use crate::{acpi::{self, sdt::HPET}, memory, println, time::ClockSource};
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+// General register offsets
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_INTERRUPT_STATUS: usize = 0x020;
+const REG_MAIN_COUNTER: usize = 0x0F0;
+
+// General configuration bits
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+// Per-comparator configuration bits
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_64BIT_CAPABLE: u64 = 1 << 5;
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0b1_1111 << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+const FEMTOSECONDS_PER_SECOND: u128 = 1_000_000_000_000_000;
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+// The specification requires a tick of at most 100 ns
+const MAX_PERIOD_FS: u64 = 0x05F5_E100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+	/// There is no comparator with this index
+	NoSuchComparator(u8),
+	/// The comparator can only be used in one-shot mode
+	PeriodicUnsupported(u8),
+	/// The comparator cannot be routed to this I/O APIC input
+	RouteUnavailable(u8),
+	/// Legacy replacement routing only exists for comparators 0 and 1
+	NoLegacyRoute(u8),
+	/// Legacy replacement routing must be turned on with `set_legacy_replacement` first
+	LegacyReplacementDisabled,
+}
+
+/// Where a comparator's interrupt is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptRoute {
+	/// Through legacy replacement routing: comparator 0 raises IRQ0 and comparator 1 raises IRQ8
+	///
+	/// Only valid once `Hpet::set_legacy_replacement` has been called.
+	Legacy,
+	/// To the given I/O APIC input, which must be in the comparator's `route_capabilities`
+	IoApic(u8),
+}
+
+/// What a single comparator supports, decoded from its configuration register
+#[derive(Debug, Clone, Copy)]
+pub struct ComparatorCapabilities {
+	pub periodic: bool,
+	pub wide: bool,
+	/// Bitmask of the I/O APIC inputs this comparator can be routed to
+	pub route_capabilities: u32,
+}
+
+/// A High Precision Event Timer block
+pub struct Hpet {
+	base: VirtAddr,
+	// Length of one main counter tick
+	period_fs: u64,
+	comparator_count: u8,
+	wide_counter: bool,
+	legacy_capable: bool,
+	// The latest reading of a 32-bit main counter, extended to 64 bits by counting its wraps
+	extended: AtomicU64,
+}
+impl Hpet {
+	/// Wraps the HPET register block mapped at `base`, None if it reports an impossible counter period
+	/// # Safety
+	/// - `base` must be the virtual address of a mapped HPET register block
+	pub unsafe fn new(base: VirtAddr) -> Option<Self> {
+		let mut hpet = Hpet {
+			base,
+			period_fs: 0,
+			comparator_count: 0,
+			wide_counter: false,
+			legacy_capable: false,
+			extended: AtomicU64::new(0),
+		};
+		let capabilities = hpet.read(REG_CAPABILITIES);
+		hpet.period_fs = capabilities >> 32;
+		hpet.comparator_count = ((capabilities >> 8) & 0b1_1111) as u8 + 1;
+		hpet.wide_counter = capabilities & (1 << 13) != 0;
+		hpet.legacy_capable = capabilities & (1 << 15) != 0;
+		//every conversion divides by the period
+		if hpet.period_fs == 0 || hpet.period_fs > MAX_PERIOD_FS {
+			return None;
+		}
+		Some(hpet)
+	}
+	/// Maps the HPET described by the ACPI HPET table
+	pub fn from_acpi() -> Option<Self> {
+		let table = acpi::find_table::<HPET>()?;
+		let base = memory::phys_to_virt(PhysAddr::new(table.get_base_address()));
+		unsafe {Self::new(base)}
+	}
+
+	fn read(&self, reg: usize) -> u64 {
+		unsafe {core::ptr::read_volatile((self.base.as_u64() as usize + reg) as *const u64)}
+	}
+	fn write(&self, reg: usize, value: u64) {
+		unsafe {core::ptr::write_volatile((self.base.as_u64() as usize + reg) as *mut u64, value)}
+	}
+	fn comparator_config_reg(n: u8) -> usize {
+		0x100 + 0x20 * n as usize
+	}
+	fn comparator_value_reg(n: u8) -> usize {
+		0x108 + 0x20 * n as usize
+	}
+
+	/// Starts the main counter
+	pub fn enable(&self) {
+		self.write(REG_CONFIG, self.read(REG_CONFIG) | CONFIG_ENABLE);
+	}
+	/// Halts the main counter
+	pub fn disable(&self) {
+		self.write(REG_CONFIG, self.read(REG_CONFIG) & !CONFIG_ENABLE);
+	}
+	/// Routes comparators 0 and 1 to IRQ0 and IRQ8, taking over from the PIT and RTC
+	///
+	/// The RTC can't raise interrupts while this is on, so it is never turned on implicitly.
+	pub fn set_legacy_replacement(&self, enable: bool) {
+		let config = self.read(REG_CONFIG);
+		if enable {
+			self.write(REG_CONFIG, config | CONFIG_LEGACY_REPLACEMENT);
+		}
+		else {
+			self.write(REG_CONFIG, config & !CONFIG_LEGACY_REPLACEMENT);
+		}
+	}
+	pub fn is_legacy_capable(&self) -> bool {
+		self.legacy_capable
+	}
+	pub fn is_legacy_replacement_enabled(&self) -> bool {
+		self.read(REG_CONFIG) & CONFIG_LEGACY_REPLACEMENT != 0
+	}
+	/// The current value of the main counter
+	///
+	/// Narrow counters are extended to 64 bits in software, which only works if they are read at least once every half wrap.
+	/// At the usual 14.318 MHz that is about every 150 seconds.
+	pub fn counter(&self) -> u64 {
+		if self.wide_counter {
+			return self.read(REG_MAIN_COUNTER);
+		}
+		let raw = self.read(REG_MAIN_COUNTER) as u32;
+		let mut last = self.extended.load(Ordering::Acquire);
+		loop {
+			let value = extend_counter(last, raw);
+			//a reading that lost the race to a newer one mustn't move the extended value backwards
+			if value <= last {
+				return value;
+			}
+			match self.extended.compare_exchange_weak(last, value, Ordering::AcqRel, Ordering::Acquire) {
+				Ok(_) => return value,
+				Err(current) => last = current,
+			}
+		}
+	}
+	pub fn is_wide_counter(&self) -> bool {
+		self.wide_counter
+	}
+	/// Length of one main counter tick in femtoseconds
+	pub fn period_fs(&self) -> u64 {
+		self.period_fs
+	}
+	/// Main counter ticks per second
+	pub fn frequency(&self) -> u64 {
+		(FEMTOSECONDS_PER_SECOND / self.period_fs as u128) as u64
+	}
+	/// Converts a number of main counter ticks to nanoseconds
+	pub fn ticks_to_nanos(&self, ticks: u64) -> u64 {
+		(ticks as u128 * self.period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND) as u64
+	}
+	/// Converts nanoseconds to main counter ticks, rounding up
+	pub fn nanos_to_ticks(&self, nanos: u64) -> u64 {
+		let femtoseconds = nanos as u128 * FEMTOSECONDS_PER_NANOSECOND;
+		((femtoseconds + self.period_fs as u128 - 1) / self.period_fs as u128) as u64
+	}
+	/// Nanoseconds since the main counter was last reset
+	pub fn nanos(&self) -> u64 {
+		self.ticks_to_nanos(self.counter())
+	}
+	/// Spins for at least `nanos` nanoseconds, returning how long it actually waited
+	pub fn delay(&self, nanos: u64) -> u64 {
+		let ticks = self.nanos_to_ticks(nanos);
+		let start = self.counter();
+		let mut end = start;
+		while end.wrapping_sub(start) < ticks {
+			core::sync::atomic::spin_loop_hint();
+			end = self.counter();
+		}
+		self.ticks_to_nanos(end.wrapping_sub(start))
+	}
+	pub fn comparator_count(&self) -> u8 {
+		self.comparator_count
+	}
+	pub fn comparator_capabilities(&self, n: u8) -> Result<ComparatorCapabilities, HpetError> {
+		self.check_comparator(n)?;
+		let config = self.read(Self::comparator_config_reg(n));
+		Ok(ComparatorCapabilities {
+			periodic: config & TIMER_PERIODIC_CAPABLE != 0,
+			wide: config & TIMER_64BIT_CAPABLE != 0,
+			route_capabilities: (config >> 32) as u32,
+		})
+	}
+	fn check_comparator(&self, n: u8) -> Result<(), HpetError> {
+		if n < self.comparator_count {
+			Ok(())
+		}
+		else {
+			Err(HpetError::NoSuchComparator(n))
+		}
+	}
+	// Builds an edge triggered, enabled configuration for the comparator with the requested routing
+	fn interrupt_config(&self, n: u8, route: InterruptRoute) -> Result<u64, HpetError> {
+		let capabilities = self.comparator_capabilities(n)?;
+		let mut config = self.read(Self::comparator_config_reg(n));
+		config &= !(TIMER_LEVEL_TRIGGERED | TIMER_PERIODIC | TIMER_ROUTE_MASK | TIMER_FSB_ENABLE | TIMER_32BIT_MODE);
+		match route {
+			InterruptRoute::Legacy => {
+				if n > 1 || !self.legacy_capable {
+					return Err(HpetError::NoLegacyRoute(n));
+				}
+				if !self.is_legacy_replacement_enabled() {
+					return Err(HpetError::LegacyReplacementDisabled);
+				}
+			}
+			InterruptRoute::IoApic(input) => {
+				if input >= 32 || capabilities.route_capabilities & (1 << input) == 0 {
+					return Err(HpetError::RouteUnavailable(input));
+				}
+				config |= (input as u64) << TIMER_ROUTE_SHIFT;
+			}
+		}
+		Ok(config | TIMER_INT_ENABLE)
+	}
+	/// Raises a single interrupt once the main counter has advanced by `ticks`
+	pub fn program_one_shot(&self, n: u8, ticks: u64, route: InterruptRoute) -> Result<(), HpetError> {
+		let config = self.interrupt_config(n, route)?;
+		self.disable_comparator(n)?;
+		self.write(Self::comparator_value_reg(n), self.counter().wrapping_add(ticks));
+		self.write(Self::comparator_config_reg(n), config);
+		Ok(())
+	}
+	/// Raises an interrupt every `period` main counter ticks
+	pub fn program_periodic(&self, n: u8, period: u64, route: InterruptRoute) -> Result<(), HpetError> {
+		if !self.comparator_capabilities(n)?.periodic {
+			return Err(HpetError::PeriodicUnsupported(n));
+		}
+		let config = self.interrupt_config(n, route)?;
+		self.disable_comparator(n)?;
+		//with VALUE_SET, the first write sets the first deadline and the second sets the period
+		self.write(Self::comparator_config_reg(n), config | TIMER_PERIODIC | TIMER_VALUE_SET);
+		self.write(Self::comparator_value_reg(n), self.counter().wrapping_add(period));
+		self.write(Self::comparator_value_reg(n), period);
+		Ok(())
+	}
+	/// Stops the comparator from raising interrupts
+	pub fn disable_comparator(&self, n: u8) -> Result<(), HpetError> {
+		self.check_comparator(n)?;
+		let reg = Self::comparator_config_reg(n);
+		self.write(reg, self.read(reg) & !(TIMER_INT_ENABLE | TIMER_PERIODIC));
+		Ok(())
+	}
+	/// Acknowledges a level triggered interrupt from comparator `n`
+	pub fn clear_interrupt(&self, n: u8) {
+		self.write(REG_INTERRUPT_STATUS, 1 << n);
+	}
+}
+
+impl ClockSource for Hpet {
+	fn name(&self) -> &'static str {
+		"hpet"
+	}
+	fn nanos(&self) -> u64 {
+		Hpet::nanos(self)
+	}
+}
+
+// Places a 32-bit reading relative to the last extended value, assuming less than half a wrap has passed either way
+fn extend_counter(last: u64, raw: u32) -> u64 {
+	let delta = raw.wrapping_sub(last as u32);
+	if delta < 1 << 31 {
+		last.wrapping_add(delta as u64)
+	}
+	else {
+		//a reading taken before `last`, from a CPU that raced with the one that stored it
+		last.wrapping_sub(delta.wrapping_neg() as u64)
+	}
+}
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// Maps and starts the HPET if ACPI describes one
+pub fn init() {
+	if let Some(hpet) = Hpet::from_acpi() {
+		hpet.enable();
+		println!("HPET: {} comparators at {} Hz", hpet.comparator_count(), hpet.frequency());
+		HPET.try_init_once(|| hpet).expect("hpet::init should only be called once");
+	}
+}
+
+/// Returns the system HPET, if there is one and it has been initialized
+pub fn get() -> Option<&'static Hpet> {
+	HPET.try_get().ok()
+}
+
+/// Nanoseconds since the HPET was started, for use as a high-resolution clocksource
+pub fn nanoseconds() -> Option<u64> {
+	get().map(Hpet::nanos)
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_narrow_counter_wrap() {
+	serial_print!("test_narrow_counter_wrap... ");
+	let period_fs = 69_841_279; //14.318 MHz
+	let hpet = Hpet {base: VirtAddr::new(0), period_fs, comparator_count: 3, wide_counter: false, legacy_capable: true, extended: AtomicU64::new(0)};
+	let mut last = 0;
+	let mut nanos = 0;
+	//the raw counter runs through two wraps, and time must keep moving forward through both
+	for raw in [0x1000_0000u32, 0x8000_0000, 0xF000_0000, 0x0000_1000, 0x7000_0000, 0xC000_0000, 0x0001_0000].iter() {
+		let extended = extend_counter(last, *raw);
+		assert_eq!(extended as u32, *raw);
+		assert!(extended > last);
+		assert!(hpet.ticks_to_nanos(extended) > nanos);
+		nanos = hpet.ticks_to_nanos(extended);
+		last = extended;
+	}
+	assert_eq!(last, 0x2_0001_0000);
+	//a stale reading lands just before the latest one rather than a whole wrap later
+	assert_eq!(extend_counter(0x1_0000_0010, 0xFFFF_FFF0), 0xFFFF_FFF0);
+	serial_println!("[ok]");
+}
@@ -1,7 +1,8 @@
 use crate::{gdt, hlt_loop, println};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
 //todo: reduce the reliance on the x86_64 crate's IDT types and handle exceptions on our own
 //todo: see https://os.phil-opp.com/catching-exceptions/
@@ -11,9 +12,17 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, Pag
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    // IRQ8, the first input of the slave PIC
+    Rtc = PIC_2_OFFSET,
+    // IRQ9, where the chipset routes the ACPI SCI
+    Sci,
+    // Sent between CPUs to wake an idle executor, delivered through the local APIC
+    Wakeup = 0xF0,
+    LocalTimer,
+    Spurious = crate::apic::SPURIOUS_VECTOR,
 }
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub(crate) fn as_u8(self) -> u8 {
         self as u8
     }
     fn as_usize(self) -> usize {
@@ -32,6 +41,14 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::Sci.as_usize()].set_handler_fn(sci_interrupt_handler);
+        idt[InterruptIndex::Wakeup.as_usize()].set_handler_fn(wakeup_interrupt_handler);
+        idt[InterruptIndex::LocalTimer.as_usize()].set_handler_fn(local_timer_interrupt_handler);
+        idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
+        for (index, handler) in MSI_ENTRY_POINTS.iter().enumerate() {
+            idt[usize::from(MSI_VECTOR_BASE) + index].set_handler_fn(*handler);
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt
     };
@@ -53,6 +70,8 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::timer::tick();
+
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
@@ -73,6 +92,87 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
     }
 }
 
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::rtc::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn sci_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    crate::acpi::sci::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Sci.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn wakeup_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    //nothing to do, taking the interrupt is enough to bring the executor out of hlt
+    crate::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn local_timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    //besides counting, taking the interrupt wakes a tickless executor so it can run its expired timers
+    crate::apic_timer::handle_interrupt();
+    crate::apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    //spurious interrupts must not be acknowledged
+}
+
+/// The first of the vectors handed out to MSI and MSI-X interrupts, which sit just below the local APIC's own vectors
+pub const MSI_VECTOR_BASE: u8 = 0xE0;
+pub const MSI_VECTOR_COUNT: usize = 16;
+
+// The handler registered for each MSI vector as a `fn()`, 0 while the vector is free
+static MSI_HANDLERS: [AtomicUsize; MSI_VECTOR_COUNT] = [AtomicUsize::new(0); MSI_VECTOR_COUNT];
+
+// An interrupt handler can't tell which vector it was entered through, so each MSI vector gets its own entry point
+macro_rules! msi_entry_points {
+    ($($index:literal),*) => {
+        [$({
+            extern "x86-interrupt" fn msi_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+                dispatch_msi($index);
+            }
+            msi_interrupt_handler as HandlerFunc
+        }),*]
+    };
+}
+static MSI_ENTRY_POINTS: [HandlerFunc; MSI_VECTOR_COUNT] = msi_entry_points!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+
+fn dispatch_msi(index: usize) {
+    let handler = MSI_HANDLERS[index].load(Ordering::Acquire);
+    if handler != 0 {
+        let handler: fn() = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    crate::apic::end_of_interrupt();
+}
+
+/// Reserves a vector for a message signalled interrupt and runs `handler` whenever it arrives
+///
+/// The handler runs in interrupt context with the local APIC already due an EOI, so it must not block.
+/// Returns None when every MSI vector is taken.
+pub fn allocate_msi_vector(handler: fn()) -> Option<u8> {
+    MSI_HANDLERS.iter().enumerate().find_map(|(index, slot)| {
+        slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed)
+            .ok()
+            .map(|_| MSI_VECTOR_BASE + index as u8)
+    })
+}
+
+/// Returns a vector from `allocate_msi_vector`, the device must no longer be sending it
+pub fn free_msi_vector(vector: u8) {
+    if let Some(slot) = vector.checked_sub(MSI_VECTOR_BASE).and_then(|index| MSI_HANDLERS.get(usize::from(index))) {
+        slot.store(0, Ordering::Release);
+    }
+}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     error_code: PageFaultErrorCode,
@@ -19,14 +19,20 @@ use bootloader::entry_point;
 use core::panic::PanicInfo;
 
 pub mod allocator;
+pub mod apic;
+pub mod apic_timer;
 pub mod gdt;
+pub mod hpet;
 pub mod interrupts;
 pub mod memory;
+pub mod numa;
 pub mod serial;
 pub mod vga_buffer;
 pub mod time;
+pub mod rtc;
 pub mod acpi;
 pub mod timer;
+pub mod tsc;
 use acpi::ACPI;
 
 pub mod task;
@@ -41,7 +47,12 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
         test();
 	}
 	let end = unsafe {core::arch::x86_64::_rdtsc()};
-	serial_println!("Cycles for tests: {}", end-start);
+	if tsc::frequency() != 0 {
+		serial_println!("Cycles for tests: {} ({} us)", end-start, tsc::cycles_to_nanos(end-start) / 1000);
+	}
+	else {
+		serial_println!("Cycles for tests: {}", end-start);
+	}
     exit_qemu(QemuExitCode::Success);
 }
 
@@ -90,17 +101,37 @@ pub fn init(boot_info: &'static BootInfo) {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
-	x86_64::instructions::interrupts::enable();
 
 	let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(physical_memory_offset) };
     let mut frame_allocator =
         unsafe { memory::init_allocator(&boot_info.memory_map) };
 	allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+	apic::init();
+	task::executor::register_cpu().expect("the boot CPU couldn't be registered");
+	//only now that the executor's queues exist can interrupt handlers wake tasks
+	x86_64::instructions::interrupts::enable();
 
-	acpi::get_rsdp(physical_memory_offset);
+	if let Err(err) = acpi::get_rsdp(physical_memory_offset) {
+		println!("ACPI unavailable: {:?}", err);
+	}
+	else if let Err(err) = acpi::aml::init() {
+		println!("AML namespace unavailable: {:?}", err);
+	}
+	numa::init();
+	if let Some(topology) = numa::topology() {
+		println!("NUMA topology with {} nodes", topology.domains().len());
+	}
+	unsafe { memory::init_numa_allocator(&boot_info.memory_map, frame_allocator) };
+	pci::config::init();
+	println!("PCI configuration access through {}", pci::config::config_access().name());
+	if let Err(err) = acpi::sci::init() {
+		println!("ACPI events unavailable: {:?}", err);
+	}
+	hpet::init();
+	tsc::init();
 	let mut century_register = 0;
-	if let Some(fadt) = *(ACPI.fadt.read()) {
+	if let Some(fadt) = acpi::find_table::<acpi::sdt::FADT>() {
 		century_register = fadt.century;
 	}
 	let current_time = time::get_current_time(century_register);
@@ -109,17 +140,16 @@ pub fn init(boot_info: &'static BootInfo) {
 	println!("{}", ACPI);
 
 	timer::set_interrupt_freq(100);
-
-	let pci = pci::PCI::new();
-	for bus in pci.busses() {
-		for device in bus.devices() {
-			for func in device.functions() {
-				if let Some(header) = func.header() {
-					println!("{:>02X}:{:>02X}.{:>02X} {}", bus.num, device.num, func.num, header);
-				}
-			}
-		}
+	time::init(&current_time);
+	rtc::init(century_register);
+	println!("Using {} clocksource", time::clocksource_name());
+	apic_timer::calibrate();
+	apic_timer::init_cpu();
+	if timer::enable_tickless() {
+		println!("Tickless idle using the local APIC timer");
 	}
+
+	print!("{}", pci::tree::DeviceTree::scan());
 }
 
 pub fn hlt_loop() -> ! {
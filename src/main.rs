@@ -9,6 +9,8 @@ use core::panic::PanicInfo;
 use oxide_os::println;
 use oxide_os::task::{Task, executor::Executor};
 use oxide_os::task::keyboard;
+use oxide_os::acpi::{power, sci::{self, AcpiEvent}};
+use futures_util::stream::StreamExt;
 
 extern crate alloc;
 
@@ -42,5 +44,17 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
 	let mut executor = Executor::new();
 	executor.spawn(Task::new(keyboard::print_keypresses()));
+	executor.spawn(Task::new(sci::dispatch_events()));
+	executor.spawn(Task::new(shutdown_on_power_button()));
 	executor.run();
 }
+
+async fn shutdown_on_power_button() {
+	let mut events = sci::events();
+	while let Some(event) = events.next().await {
+		if event == AcpiEvent::PowerButton {
+			println!("Power button pressed, shutting down");
+			println!("Shutdown failed: {:?}", power::shutdown());
+		}
+	}
+}
@@ -1,13 +1,32 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::{
     structures::paging::{
-        FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB, UnusedPhysFrame,
+        FrameAllocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB, UnusedPhysFrame,
     },
     PhysAddr, VirtAddr,
 };
+use core::{ops::Range, sync::atomic::{AtomicU64, Ordering}};
+use crate::numa::{self, Topology};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// Where the bootloader mapped the complete physical memory, set once by `init`
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the virtual address through which the given physical address can be accessed
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
 
 pub struct BootInfoFrameAllocator<I: Iterator<Item = UnusedPhysFrame>> {
     usable_frames: I,
+    allocated: usize,
+}
+impl<I: Iterator<Item = UnusedPhysFrame>> BootInfoFrameAllocator<I> {
+    /// The number of frames handed out so far, which are the first usable frames of the memory map
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated
+    }
 }
 
 /// Create a FrameAllocator from the passed memory map.
@@ -15,7 +34,8 @@ pub struct BootInfoFrameAllocator<I: Iterator<Item = UnusedPhysFrame>> {
 /// - All frames marked as USABLE in `memory_map` are really unused
 pub unsafe fn init_allocator(memory_map: &'static MemoryMap) -> BootInfoFrameAllocator<impl Iterator<Item = UnusedPhysFrame>> {
 	BootInfoFrameAllocator {
-		usable_frames: usable_frames(memory_map)
+		usable_frames: usable_frames(memory_map),
+		allocated: 0,
 	}
 }
 fn usable_frames(memory_map: &'static MemoryMap) -> impl Iterator<Item = UnusedPhysFrame> {
@@ -33,10 +53,151 @@ fn usable_frames(memory_map: &'static MemoryMap) -> impl Iterator<Item = UnusedP
 
 unsafe impl<I: Iterator<Item = UnusedPhysFrame>> FrameAllocator<Size4KiB> for BootInfoFrameAllocator<I> {
     fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
-        self.usable_frames.next()
+        let frame = self.usable_frames.next();
+        if frame.is_some() {
+            self.allocated += 1;
+        }
+        frame
+    }
+}
+
+/// The usable frames of one proximity domain
+struct DomainPool {
+    domain: u32,
+    ranges: Vec<Range<u64>>,
+}
+impl DomainPool {
+    fn is_empty(&self) -> bool {
+        self.ranges.iter().all(|range| range.start >= range.end)
+    }
+    fn take(&mut self) -> Option<u64> {
+        let range = self.ranges.iter_mut().find(|range| range.start < range.end)?;
+        let frame = range.start;
+        range.start += 4096;
+        Some(frame)
+    }
+}
+
+/// A frame allocator that hands out frames from the requested NUMA node, or the nearest one that has some left
+pub struct NumaFrameAllocator {
+    topology: Option<&'static Topology>,
+    pools: Vec<DomainPool>,
+    /// Usable memory that the SRAT doesn't assign to any domain
+    unassigned: Vec<Range<u64>>,
+}
+
+impl NumaFrameAllocator {
+    /// Takes over the usable frames of the memory map, skipping the ones the boot allocator already handed out
+    /// # Safety
+    /// - All frames marked as USABLE in `memory_map` are really unused, apart from the first `allocated` ones
+    /// - No other allocator hands out frames from `memory_map` afterwards
+    pub unsafe fn new(memory_map: &'static MemoryMap, allocated: usize, topology: Option<&'static Topology>) -> Self {
+        let usable = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.start_addr()..r.range.end_addr());
+        Self::from_ranges(usable, allocated, topology)
+    }
+    /// Takes over the given ranges of usable memory, in memory map order, skipping the first `allocated` frames
+    /// # Safety
+    /// - All frames in `usable` are really unused, apart from the first `allocated` ones
+    /// - No other allocator hands out frames from `usable` afterwards
+    pub unsafe fn from_ranges(usable: impl Iterator<Item = Range<u64>>, allocated: usize, topology: Option<&'static Topology>) -> Self {
+        let mut allocator = NumaFrameAllocator { topology, pools: Vec::new(), unassigned: Vec::new() };
+        let mut skip = allocated as u64;
+        for Range { mut start, end } in usable {
+            let frames = (end - start) / 4096;
+            if skip >= frames {
+                skip -= frames;
+                continue;
+            }
+            start += skip * 4096;
+            skip = 0;
+            allocator.add_range(start..end);
+        }
+        allocator
+    }
+
+    // Splits a range of usable memory at the SRAT's boundaries and files each piece under its domain
+    fn add_range(&mut self, range: Range<u64>) {
+        let mut start = range.start;
+        while start < range.end {
+            let memory = self.topology.map(|topology| topology.memory_ranges()).unwrap_or(&[]);
+            if let Some(owner) = memory.iter().find(|memory| memory.contains(start)) {
+                let end = range.end.min(owner.base + owner.length);
+                let domain = owner.domain;
+                match self.pools.iter_mut().find(|pool| pool.domain == domain) {
+                    Some(pool) => pool.ranges.push(start..end),
+                    None => self.pools.push(DomainPool { domain, ranges: alloc::vec![start..end] }),
+                }
+                start = end;
+            } else {
+                //up to wherever the next domain's memory begins
+                let end = memory.iter()
+                    .map(|memory| memory.base)
+                    .filter(|base| *base > start)
+                    .min()
+                    .unwrap_or(range.end)
+                    .min(range.end);
+                self.unassigned.push(start..end);
+                start = end;
+            }
+        }
+    }
+
+    /// Allocates a frame from `domain`, falling back to the nearest domain with free frames and then to unassigned memory
+    pub fn allocate_frame_on(&mut self, domain: u32) -> Option<UnusedPhysFrame> {
+        let topology = self.topology;
+        let distance = |to: u32| match topology {
+            Some(topology) => topology.distance(domain, to),
+            None if to == domain => numa::LOCAL_DISTANCE,
+            None => numa::REMOTE_DISTANCE,
+        };
+        let address = match self.pools.iter_mut()
+            .filter(|pool| !pool.is_empty())
+            .min_by_key(|pool| (distance(pool.domain), pool.domain != domain, pool.domain))
+        {
+            Some(pool) => pool.take(),
+            None => {
+                let range = self.unassigned.iter_mut().find(|range| range.start < range.end)?;
+                let frame = range.start;
+                range.start += 4096;
+                Some(frame)
+            }
+        }?;
+        let frame = PhysFrame::containing_address(PhysAddr::new(address));
+        Some(unsafe { UnusedPhysFrame::new(frame) })
+    }
+
+    /// The number of free frames left in a domain
+    pub fn free_frames(&self, domain: u32) -> u64 {
+        self.pools.iter()
+            .filter(|pool| pool.domain == domain)
+            .flat_map(|pool| pool.ranges.iter())
+            .map(|range| range.end.saturating_sub(range.start) / 4096)
+            .sum()
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for NumaFrameAllocator {
+    /// Prefers frames local to the calling CPU
+    fn allocate_frame(&mut self) -> Option<UnusedPhysFrame> {
+        self.allocate_frame_on(numa::current_domain())
     }
 }
 
+/// The frame allocator used once the heap is up, installed by `init_numa_allocator`
+///
+/// Nothing allocates frames after boot yet, the boot allocator's frames all go to the heap.
+pub static FRAME_ALLOCATOR: Mutex<Option<NumaFrameAllocator>> = Mutex::new(None);
+
+/// Replaces the boot allocator with one that knows the NUMA topology
+/// # Safety
+/// - `boot_allocator` was created from `memory_map` and is not used afterwards
+pub unsafe fn init_numa_allocator<I: Iterator<Item = UnusedPhysFrame>>(memory_map: &'static MemoryMap, boot_allocator: BootInfoFrameAllocator<I>) {
+    let allocator = NumaFrameAllocator::new(memory_map, boot_allocator.allocated_frames(), numa::topology());
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
 /// Gets a mut reference to the active level 4 table
 /// # Safety
 /// - The complete physical memory is mapped to virtual memory at the passed `physical_memory_offset`
@@ -55,11 +216,69 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+/// Walks the active page table to find the physical address a virtual address is mapped to
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table_addr = level_4_table_frame.start_address();
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, &index) in indexes.iter().enumerate() {
+        //only read through the table, so that it doesn't alias the mapper's &mut
+        let table = unsafe { &*phys_to_virt(table_addr).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            //a 1GiB or 2MiB page, the remaining index bits are part of the page offset
+            let page_size = 1u64 << (12 + 9 * (3 - level));
+            return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+        }
+        table_addr = entry.addr();
+    }
+    Some(table_addr + u64::from(addr.page_offset()))
+}
+
 /// Initialize the memory
 /// # Safety
 /// - The complete physical memory must be mapped to virtual memory at the passed in `physical_memory_offset`
 /// - This method must only be called once, to avoid aliasing &mut references
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
+
+#[cfg(test)]
+use crate::{numa::MemoryRange, serial_print, serial_println};
+
+#[test_case]
+fn test_numa_frame_allocator() {
+    serial_print!("test_numa_frame_allocator... ");
+    let range = |base, length, domain| MemoryRange { base, length, domain, hot_pluggable: false, non_volatile: false };
+    //domain 1 is nearer to domain 0 than domain 2 is
+    let topology = Topology::new(
+        alloc::vec![(0, 0), (1, 1), (2, 2)],
+        alloc::vec![range(0, 0x10_0000, 0), range(0x10_0000, 0x10_0000, 1), range(0x20_0000, 0x10_0000, 2)],
+        alloc::vec![10, 21, 31, 21, 10, 21, 31, 21, 10],
+        3,
+    );
+    let topology: &'static Topology = alloc::boxed::Box::leak(alloc::boxed::Box::new(topology));
+    let usable = alloc::vec![0x1000..0x3000, 0xF_E000..0x10_2000, 0x20_0000..0x20_1000, 0x30_0000..0x30_1000];
+    //the boot allocator handed out the whole first range and the first frame of the second
+    let mut allocator = unsafe { NumaFrameAllocator::from_ranges(usable.into_iter(), 3, Some(topology)) };
+    assert_eq!(allocator.free_frames(0), 1);
+    assert_eq!(allocator.free_frames(1), 2);
+    assert_eq!(allocator.free_frames(2), 1);
+
+    let mut allocate = |domain| allocator.allocate_frame_on(domain).map(|frame| frame.start_address().as_u64());
+    assert_eq!(allocate(1), Some(0x10_0000));
+    assert_eq!(allocate(0), Some(0xF_F000));
+    //once domain 0 runs out, the nearest domain is used before the farther one and then unassigned memory
+    assert_eq!(allocate(0), Some(0x10_1000));
+    assert_eq!(allocate(0), Some(0x20_0000));
+    assert_eq!(allocate(0), Some(0x30_0000));
+    assert_eq!(allocate(0), None);
+    serial_println!("[ok]");
+}
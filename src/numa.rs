@@ -0,0 +1,160 @@
+use crate::acpi::{self, sdt::{SLIT, SRAT, SratEntry}};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+
+// The distances the SLIT uses for a domain to itself, and that we assume between domains without one
+pub const LOCAL_DISTANCE: u8 = 10;
+pub const REMOTE_DISTANCE: u8 = 20;
+
+/// A physical memory range that belongs to a proximity domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRange {
+	pub base: u64,
+	pub length: u64,
+	pub domain: u32,
+	pub hot_pluggable: bool,
+	pub non_volatile: bool,
+}
+impl MemoryRange {
+	pub fn contains(&self, address: u64) -> bool {
+		address >= self.base && address - self.base < self.length
+	}
+}
+
+/// Which CPUs and memory belong to which proximity domain, and how far apart the domains are
+#[derive(Debug, Default)]
+pub struct Topology {
+	/// (APIC ID, domain) of every enabled CPU
+	cpus: Vec<(u32, u32)>,
+	memory: Vec<MemoryRange>,
+	/// Every domain that owns a CPU or memory, in ascending order
+	domains: Vec<u32>,
+	/// Row-major distances between domains 0..locality_count, from the SLIT
+	distances: Vec<u8>,
+	locality_count: usize,
+}
+
+impl Topology {
+	/// Builds a topology from (APIC ID, domain) pairs, memory ranges and a row-major `locality_count` square distance matrix
+	pub fn new(cpus: Vec<(u32, u32)>, memory: Vec<MemoryRange>, distances: Vec<u8>, locality_count: usize) -> Self {
+		assert_eq!(distances.len(), locality_count * locality_count, "the distance matrix must be square");
+		let mut domains: Vec<u32> = cpus.iter().map(|(_, domain)| *domain)
+			.chain(memory.iter().map(|range| range.domain))
+			.collect();
+		domains.sort_unstable();
+		domains.dedup();
+		Topology {cpus, memory, domains, distances, locality_count}
+	}
+
+	/// Builds the topology from the SRAT and, if there is one, the SLIT
+	pub fn from_acpi() -> Option<Self> {
+		let srat = acpi::find_table::<SRAT>()?;
+		let mut cpus = Vec::new();
+		let mut memory = Vec::new();
+		for entry in srat.get_entries() {
+			match entry {
+				SratEntry::Processor {apic_id, domain, enabled: true} => cpus.push((apic_id, domain)),
+				SratEntry::Memory {base, length, domain, enabled: true, hot_pluggable, non_volatile} if length != 0 => {
+					memory.push(MemoryRange {base, length, domain, hot_pluggable, non_volatile});
+				}
+				_ => {}
+			}
+		}
+
+		let mut distances = Vec::new();
+		let mut locality_count = 0;
+		if let Some(slit) = acpi::find_table::<SLIT>() {
+			//only keep the matrix if it is small enough to be plausible and is entirely present
+			let count = slit.get_locality_count() as usize;
+			if count <= 256 && slit.get_distance(count.saturating_sub(1) as u32, count.saturating_sub(1) as u32).is_some() {
+				locality_count = count;
+				for from in 0..count {
+					for to in 0..count {
+						distances.push(slit.get_distance(from as u32, to as u32).unwrap_or(REMOTE_DISTANCE));
+					}
+				}
+			}
+		}
+		Some(Topology::new(cpus, memory, distances, locality_count))
+	}
+
+	pub fn domains(&self) -> &[u32] {
+		&self.domains
+	}
+	pub fn memory_ranges(&self) -> &[MemoryRange] {
+		&self.memory
+	}
+	/// The domain of the CPU with this local APIC ID
+	pub fn domain_of_cpu(&self, apic_id: u32) -> Option<u32> {
+		self.cpus.iter().find(|(id, _)| *id == apic_id).map(|(_, domain)| *domain)
+	}
+	/// The APIC IDs of the CPUs in a domain
+	pub fn cpus_in(&self, domain: u32) -> impl Iterator<Item = u32> + '_ {
+		self.cpus.iter().filter(move |(_, cpu_domain)| *cpu_domain == domain).map(|(id, _)| *id)
+	}
+	/// The domain that owns a physical address
+	pub fn domain_of_address(&self, address: u64) -> Option<u32> {
+		self.memory.iter().find(|range| range.contains(address)).map(|range| range.domain)
+	}
+	/// The relative distance between two domains, 10 being local
+	pub fn distance(&self, from: u32, to: u32) -> u8 {
+		let (from_index, to_index) = (from as usize, to as usize);
+		if from_index < self.locality_count && to_index < self.locality_count {
+			self.distances[from_index * self.locality_count + to_index]
+		}
+		else if from == to {
+			LOCAL_DISTANCE
+		}
+		else {
+			REMOTE_DISTANCE
+		}
+	}
+	/// Every domain, nearest to `from` first
+	pub fn domains_by_distance(&self, from: u32) -> Vec<u32> {
+		let mut domains = self.domains.clone();
+		domains.sort_by_key(|domain| (self.distance(from, *domain), *domain != from, *domain));
+		domains
+	}
+}
+
+static TOPOLOGY: OnceCell<Topology> = OnceCell::uninit();
+
+/// Reads the NUMA topology out of ACPI, machines without an SRAT are treated as a single node
+pub fn init() {
+	if let Some(topology) = Topology::from_acpi() {
+		let _ = TOPOLOGY.try_init_once(|| topology);
+	}
+}
+
+pub fn topology() -> Option<&'static Topology> {
+	TOPOLOGY.try_get().ok()
+}
+
+/// The domain of the calling CPU, 0 when there is no topology
+pub fn current_domain() -> u32 {
+	topology().and_then(|topology| topology.domain_of_cpu(crate::apic::id())).unwrap_or(0)
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_numa_distances() {
+	serial_print!("test_numa_distances... ");
+	let topology = Topology {
+		cpus: alloc::vec![(0, 0), (1, 1), (2, 2)],
+		memory: alloc::vec![
+			MemoryRange {base: 0, length: 0x8000_0000, domain: 0, hot_pluggable: false, non_volatile: false},
+			MemoryRange {base: 0x8000_0000, length: 0x8000_0000, domain: 1, hot_pluggable: false, non_volatile: false},
+		],
+		domains: alloc::vec![0, 1, 2],
+		distances: alloc::vec![10, 21, 31, 21, 10, 21, 31, 21, 10],
+		locality_count: 3,
+	};
+	assert_eq!(topology.domain_of_cpu(2), Some(2));
+	assert_eq!(topology.domain_of_address(0x9000_0000), Some(1));
+	assert_eq!(topology.domain_of_address(0x1_0000_0000), None);
+	assert_eq!(topology.distance(0, 2), 31);
+	assert_eq!(topology.domains_by_distance(2), alloc::vec![2, 1, 0]);
+	serial_println!("[ok]");
+}
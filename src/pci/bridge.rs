@@ -0,0 +1,174 @@
+use super::{config::PCIAddress, read_header, HeaderType, PCIBarIter};
+use core::ops::RangeInclusive;
+
+/// The type 1 header of a PCI-to-PCI bridge
+#[derive(Default, Debug)]
+#[repr(C)]
+pub struct PCIBridgeHeader {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub command: u16,
+    pub status: u16,
+    pub revision: u8,
+    pub interface: u8,
+    pub subclass: u8,
+    pub class: u8,
+    pub cache_line_size: u8,
+    pub latency_timer: u8,
+    pub header_type: u8,
+    pub bist: u8,
+    pub base_address_registers: [u32; 2],
+    pub primary_bus: u8,
+    pub secondary_bus: u8,
+    pub subordinate_bus: u8,
+    pub secondary_latency_timer: u8,
+    pub io_base: u8,
+    pub io_limit: u8,
+    pub secondary_status: u16,
+    pub memory_base: u16,
+    pub memory_limit: u16,
+    pub prefetchable_memory_base: u16,
+    pub prefetchable_memory_limit: u16,
+    pub prefetchable_base_upper: u32,
+    pub prefetchable_limit_upper: u32,
+    pub io_base_upper: u16,
+    pub io_limit_upper: u16,
+    pub capabilities: u8,
+    pub reserved: [u8; 3],
+    pub expansion_rom_bar: u32,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bridge_control: u16,
+}
+
+impl PCIBridgeHeader {
+    /// Reads the header of the function at `address`, None if there is none or it isn't a PCI-to-PCI bridge
+    pub fn read(address: PCIAddress) -> Option<Self> {
+        read_header::<Self>(address).filter(|header| HeaderType::from(header.header_type) == HeaderType::PCIBridge)
+    }
+    /// The buses behind the bridge
+    pub fn bus_range(&self) -> RangeInclusive<u8> {
+        self.secondary_bus..=self.subordinate_bus
+    }
+    pub fn bars(&self) -> PCIBarIter<'_> {
+        PCIBarIter::new(&self.base_address_registers)
+    }
+
+    /// The I/O ports forwarded to the secondary bus, None if the window is closed
+    pub fn io_window(&self) -> Option<RangeInclusive<u64>> {
+        //the low nibble says whether the upper 16 bits are implemented
+        let upper = |low: u8, upper: u16| if low & 0xF == 1 { (upper as u64) << 16 } else { 0 };
+        let base = upper(self.io_base, self.io_base_upper) | ((self.io_base & 0xF0) as u64) << 8;
+        let limit = upper(self.io_limit, self.io_limit_upper) | ((self.io_limit & 0xF0) as u64) << 8 | 0xFFF;
+        window(base, limit)
+    }
+    /// The non-prefetchable memory forwarded to the secondary bus, None if the window is closed
+    pub fn memory_window(&self) -> Option<RangeInclusive<u64>> {
+        let base = ((self.memory_base & 0xFFF0) as u64) << 16;
+        let limit = ((self.memory_limit & 0xFFF0) as u64) << 16 | 0xF_FFFF;
+        window(base, limit)
+    }
+    /// The prefetchable memory forwarded to the secondary bus, None if the window is closed
+    pub fn prefetchable_window(&self) -> Option<RangeInclusive<u64>> {
+        //the low nibble says whether the window is 64-bit
+        let upper = |low: u16, upper: u32| if low & 0xF == 1 { (upper as u64) << 32 } else { 0 };
+        let base = upper(self.prefetchable_memory_base, self.prefetchable_base_upper)
+            | ((self.prefetchable_memory_base & 0xFFF0) as u64) << 16;
+        let limit = upper(self.prefetchable_memory_limit, self.prefetchable_limit_upper)
+            | ((self.prefetchable_memory_limit & 0xFFF0) as u64) << 16
+            | 0xF_FFFF;
+        window(base, limit)
+    }
+}
+
+// A bridge closes a window by programming its base above its limit
+fn window(base: u64, limit: u64) -> Option<RangeInclusive<u64>> {
+    if base <= limit {
+        Some(base..=limit)
+    } else {
+        None
+    }
+}
+
+/// The type 2 header of a CardBus bridge
+#[derive(Default, Debug)]
+#[repr(C)]
+pub struct PCICardBusHeader {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub command: u16,
+    pub status: u16,
+    pub revision: u8,
+    pub interface: u8,
+    pub subclass: u8,
+    pub class: u8,
+    pub cache_line_size: u8,
+    pub latency_timer: u8,
+    pub header_type: u8,
+    pub bist: u8,
+    pub socket_base_address: u32,
+    pub capabilities: u8,
+    pub reserved: u8,
+    pub secondary_status: u16,
+    pub pci_bus: u8,
+    pub cardbus_bus: u8,
+    pub subordinate_bus: u8,
+    pub cardbus_latency_timer: u8,
+    pub memory_base_0: u32,
+    pub memory_limit_0: u32,
+    pub memory_base_1: u32,
+    pub memory_limit_1: u32,
+    pub io_base_0: u32,
+    pub io_limit_0: u32,
+    pub io_base_1: u32,
+    pub io_limit_1: u32,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bridge_control: u16,
+    pub subsystem_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub legacy_base_address: u32,
+}
+
+impl PCICardBusHeader {
+    /// Reads the header of the function at `address`, None if there is none or it isn't a CardBus bridge
+    pub fn read(address: PCIAddress) -> Option<Self> {
+        read_header::<Self>(address).filter(|header| HeaderType::from(header.header_type) == HeaderType::CardBusBridge)
+    }
+    /// The buses behind the bridge
+    pub fn bus_range(&self) -> RangeInclusive<u8> {
+        self.cardbus_bus..=self.subordinate_bus
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_bridge_windows() {
+    serial_print!("test_bridge_windows... ");
+    assert_eq!(core::mem::size_of::<PCIBridgeHeader>(), 0x40);
+    assert_eq!(core::mem::size_of::<PCICardBusHeader>(), 0x48);
+    let bridge = PCIBridgeHeader {
+        header_type: 0x01,
+        secondary_bus: 1,
+        subordinate_bus: 3,
+        io_base: 0xC1,
+        io_limit: 0xD1,
+        memory_base: 0xFE80,
+        memory_limit: 0xFE90,
+        prefetchable_memory_base: 0x0001,
+        prefetchable_memory_limit: 0xFFF1,
+        prefetchable_base_upper: 0x80,
+        prefetchable_limit_upper: 0x80,
+        ..PCIBridgeHeader::default()
+    };
+    assert_eq!(bridge.bus_range(), 1..=3);
+    assert_eq!(bridge.io_window(), Some(0xC000..=0xDFFF));
+    assert_eq!(bridge.memory_window(), Some(0xFE80_0000..=0xFE9F_FFFF));
+    assert_eq!(bridge.prefetchable_window(), Some(0x80_0000_0000..=0x80_FFFF_FFFF));
+    //the reset values of base 0 and limit 0 still describe a window, firmware closes one with base > limit
+    let closed = PCIBridgeHeader { memory_base: 0xFFF0, memory_limit: 0x0000, ..PCIBridgeHeader::default() };
+    assert_eq!(closed.memory_window(), None);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,206 @@
+use super::{
+    config::{self, ConfigRegister, PCIAddress},
+    msi::{Msi, MsiX},
+    HeaderType, HEADER_TYPE_OFFSET,
+};
+
+const STATUS_OFFSET: u16 = 0x06;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+/// Offset of the pointer to the first capability in type 0 and type 1 headers
+pub const CAPABILITIES_POINTER_OFFSET: u16 = 0x34;
+const CARDBUS_CAPABILITIES_POINTER_OFFSET: u16 = 0x14;
+
+pub const ID_POWER_MANAGEMENT: u8 = 0x01;
+pub const ID_MSI: u8 = 0x05;
+pub const ID_VENDOR_SPECIFIC: u8 = 0x09;
+pub const ID_PCI_EXPRESS: u8 = 0x10;
+pub const ID_MSIX: u8 = 0x11;
+
+// A list longer than the 192 bytes after the header could hold can only come from a loop
+const MAX_CAPABILITIES: u8 = 48;
+
+/// The location of a capability structure in a function's configuration space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilityAddress {
+    pub function: PCIAddress,
+    pub offset: u16,
+}
+impl CapabilityAddress {
+    /// Reads a register at `offset` bytes into the capability
+    /// # Safety
+    /// - Reading some registers has side effects on the device
+    pub unsafe fn read<T: ConfigRegister>(&self, offset: u16) -> T {
+        config::read_register(self.function, self.offset + offset)
+    }
+    /// Writes a register at `offset` bytes into the capability
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    pub unsafe fn write<T: ConfigRegister>(&self, offset: u16, value: T) {
+        config::write_register(self.function, self.offset + offset, value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Capability {
+    PowerManagement(PowerManagement),
+    Msi(Msi),
+    MsiX(MsiX),
+    PciExpress(PciExpress),
+    VendorSpecific(VendorSpecific),
+    Other { id: u8, address: CapabilityAddress },
+}
+impl Capability {
+    fn new(id: u8, address: CapabilityAddress) -> Self {
+        match id {
+            ID_POWER_MANAGEMENT => Capability::PowerManagement(PowerManagement(address)),
+            ID_MSI => Capability::Msi(Msi::new(address)),
+            ID_MSIX => Capability::MsiX(MsiX::new(address)),
+            ID_PCI_EXPRESS => Capability::PciExpress(PciExpress(address)),
+            ID_VENDOR_SPECIFIC => Capability::VendorSpecific(VendorSpecific(address)),
+            id => Capability::Other { id, address },
+        }
+    }
+    pub fn id(&self) -> u8 {
+        match self {
+            Capability::PowerManagement(_) => ID_POWER_MANAGEMENT,
+            Capability::Msi(_) => ID_MSI,
+            Capability::MsiX(_) => ID_MSIX,
+            Capability::PciExpress(_) => ID_PCI_EXPRESS,
+            Capability::VendorSpecific(_) => ID_VENDOR_SPECIFIC,
+            Capability::Other { id, .. } => *id,
+        }
+    }
+    pub fn address(&self) -> CapabilityAddress {
+        match self {
+            Capability::PowerManagement(capability) => capability.0,
+            Capability::Msi(capability) => capability.address(),
+            Capability::MsiX(capability) => capability.address(),
+            Capability::PciExpress(capability) => capability.0,
+            Capability::VendorSpecific(capability) => capability.0,
+            Capability::Other { address, .. } => *address,
+        }
+    }
+}
+
+/// Follows a function's linked list of capabilities
+pub struct CapabilityIter {
+    function: PCIAddress,
+    next: u8,
+    remaining: u8,
+}
+impl CapabilityIter {
+    pub fn new(function: PCIAddress) -> Self {
+        let next = unsafe {
+            let status: u16 = config::read_register(function, STATUS_OFFSET);
+            if status == 0xFFFF || status & STATUS_CAPABILITIES_LIST == 0 {
+                0
+            } else {
+                let header_type: u8 = config::read_register(function, HEADER_TYPE_OFFSET);
+                let pointer = match HeaderType::from(header_type) {
+                    HeaderType::CardBusBridge => CARDBUS_CAPABILITIES_POINTER_OFFSET,
+                    _ => CAPABILITIES_POINTER_OFFSET,
+                };
+                config::read_register(function, pointer)
+            }
+        };
+        CapabilityIter { function, next, remaining: MAX_CAPABILITIES }
+    }
+}
+impl Iterator for CapabilityIter {
+    type Item = Capability;
+    fn next(&mut self) -> Option<Self::Item> {
+        //the bottom two bits of a pointer are reserved, and capabilities never overlap the header
+        let offset = self.next & 0xFC;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header: u16 = unsafe { config::read_register(self.function, offset as u16) };
+        self.next = (header >> 8) as u8;
+        let address = CapabilityAddress { function: self.function, offset: offset as u16 };
+        Some(Capability::new(header as u8, address))
+    }
+}
+
+/// PCI power management, which moves the function between D0 (on) and D3hot
+#[derive(Debug, Clone, Copy)]
+pub struct PowerManagement(CapabilityAddress);
+impl PowerManagement {
+    const CAPABILITIES: u16 = 0x02;
+    const CONTROL: u16 = 0x04;
+    // PME status is cleared by writing a one, so it is written back as zero
+    const CONTROL_PME_STATUS: u16 = 1 << 15;
+
+    pub fn capabilities(&self) -> u16 {
+        unsafe { self.0.read(Self::CAPABILITIES) }
+    }
+    /// The current power state, 0 for D0 through 3 for D3hot
+    pub fn power_state(&self) -> u8 {
+        unsafe { self.0.read::<u16>(Self::CONTROL) as u8 & 0b11 }
+    }
+    /// # Safety
+    /// - The function loses its configuration in D3hot, and must be given time to settle after a transition
+    pub unsafe fn set_power_state(&self, state: u8) {
+        let control: u16 = self.0.read(Self::CONTROL);
+        let control = (control & !Self::CONTROL_PME_STATUS & !0b11) | (state as u16 & 0b11);
+        self.0.write(Self::CONTROL, control);
+    }
+}
+
+/// The PCI Express capability, describing the link and the kind of PCIe function
+#[derive(Debug, Clone, Copy)]
+pub struct PciExpress(CapabilityAddress);
+impl PciExpress {
+    const CAPABILITIES: u16 = 0x02;
+    const DEVICE_CAPABILITIES: u16 = 0x04;
+    const DEVICE_CONTROL: u16 = 0x08;
+    const DEVICE_STATUS: u16 = 0x0A;
+    const LINK_CAPABILITIES: u16 = 0x0C;
+    const LINK_STATUS: u16 = 0x12;
+
+    pub fn version(&self) -> u8 {
+        unsafe { self.0.read::<u16>(Self::CAPABILITIES) as u8 & 0xF }
+    }
+    /// The device/port type, such as 0 for an endpoint or 4 for a root port
+    pub fn device_type(&self) -> u8 {
+        unsafe { (self.0.read::<u16>(Self::CAPABILITIES) >> 4) as u8 & 0xF }
+    }
+    pub fn device_capabilities(&self) -> u32 {
+        unsafe { self.0.read(Self::DEVICE_CAPABILITIES) }
+    }
+    pub fn device_control(&self) -> u16 {
+        unsafe { self.0.read(Self::DEVICE_CONTROL) }
+    }
+    pub fn device_status(&self) -> u16 {
+        unsafe { self.0.read(Self::DEVICE_STATUS) }
+    }
+    pub fn link_capabilities(&self) -> u32 {
+        unsafe { self.0.read(Self::LINK_CAPABILITIES) }
+    }
+    /// The negotiated link speed, 1 for 2.5GT/s, 2 for 5GT/s and so on
+    pub fn link_speed(&self) -> u8 {
+        unsafe { self.0.read::<u16>(Self::LINK_STATUS) as u8 & 0xF }
+    }
+    /// The negotiated number of lanes
+    pub fn link_width(&self) -> u8 {
+        unsafe { (self.0.read::<u16>(Self::LINK_STATUS) >> 4) as u8 & 0x3F }
+    }
+}
+
+/// A vendor-specific capability, whose contents only the vendor's driver understands
+#[derive(Debug, Clone, Copy)]
+pub struct VendorSpecific(CapabilityAddress);
+impl VendorSpecific {
+    /// The length of the capability in bytes, including its header
+    pub fn length(&self) -> u8 {
+        unsafe { self.0.read(0x02) }
+    }
+    /// Reads a byte of the capability, None past its end
+    pub fn read_byte(&self, index: u8) -> Option<u8> {
+        if index < self.length() {
+            Some(unsafe { self.0.read(index as u16) })
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::{acpi::{self, sdt::MCFG}, memory};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use spin::RwLock;
+use x86_64::{PhysAddr, instructions::port::Port};
+
+/// Size of a function's configuration space through the legacy ports
+pub const LEGACY_CONFIG_SIZE: u16 = 256;
+/// Size of a function's configuration space through ECAM, including the PCIe extended capabilities
+pub const EXTENDED_CONFIG_SIZE: u16 = 4096;
+
+/// Identifies a single PCI function
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PCIAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+impl PCIAddress {
+    pub fn new(segment: u16, bus: u8, device: u8, function: u8) -> Self {
+        PCIAddress { segment, bus, device, function }
+    }
+}
+impl core::fmt::Display for PCIAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04X}:{:02X}:{:02X}.{}", self.segment, self.bus, self.device, self.function)
+    }
+}
+
+/// A mechanism for reaching PCI configuration space
+///
+/// Offsets are in bytes and aligned to the access width; reads of functions or offsets that can't be reached return all ones.
+pub trait ConfigAccess: Send + Sync {
+    fn name(&self) -> &'static str;
+    /// The number of bytes of configuration space reachable for functions on this bus
+    fn config_size(&self, segment: u16, bus: u8) -> u16;
+    /// # Safety
+    /// - Reading some registers has side effects on the device
+    unsafe fn read(&self, address: PCIAddress, offset: u16) -> u32;
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    unsafe fn write(&self, address: PCIAddress, offset: u16, value: u32);
+
+    /// # Safety
+    /// - Reading some registers has side effects on the device
+    unsafe fn read_u16(&self, address: PCIAddress, offset: u16) -> u16 {
+        (self.read(address, offset & !3) >> ((offset & 2) * 8)) as u16
+    }
+    /// # Safety
+    /// - Reading some registers has side effects on the device
+    unsafe fn read_u8(&self, address: PCIAddress, offset: u16) -> u8 {
+        (self.read(address, offset & !3) >> ((offset & 3) * 8)) as u8
+    }
+    /// Writes a word, by default through a read-modify-write of the dword around it
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    unsafe fn write_u16(&self, address: PCIAddress, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        write_within_dword(self, address, offset, 0xFFFF << shift, (value as u32) << shift);
+    }
+    /// Writes a byte, by default through a read-modify-write of the dword around it
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    unsafe fn write_u8(&self, address: PCIAddress, offset: u16, value: u8) {
+        let shift = (offset & 3) * 8;
+        write_within_dword(self, address, offset, 0xFF << shift, (value as u32) << shift);
+    }
+}
+
+// The status register's error bits are cleared by writing ones to them, so writing back what was read would clear them
+fn write_one_to_clear_mask(dword_offset: u16) -> u32 {
+    match dword_offset {
+        0x04 => 0xFFFF_0000,
+        _ => 0,
+    }
+}
+unsafe fn write_within_dword<A: ConfigAccess + ?Sized>(access: &A, address: PCIAddress, offset: u16, mask: u32, bits: u32) {
+    let dword_offset = offset & !3;
+    let preserved = access.read(address, dword_offset) & !mask & !write_one_to_clear_mask(dword_offset);
+    access.write(address, dword_offset, preserved | (bits & mask));
+}
+
+/// Configuration mechanism #1, the CF8/CFC I/O ports, which only reach segment 0 and the first 256 bytes
+pub struct PortIo;
+impl PortIo {
+    // The data port mirrors the selected dword at 0xCFC-0xCFF, so narrower accesses go to the matching byte of it
+    fn data_port(offset: u16) -> u16 {
+        0xCFC + (offset & 3)
+    }
+    fn config_address(address: PCIAddress, offset: u16) -> u32 {
+        0x8000_0000
+            | ((address.bus as u32) << 16)
+            | ((address.device as u32) << 11)
+            | ((address.function as u32) << 8)
+            | ((offset as u32) & 0xFC)
+    }
+    fn reachable(address: PCIAddress, offset: u16) -> bool {
+        address.segment == 0 && offset < LEGACY_CONFIG_SIZE
+    }
+}
+impl ConfigAccess for PortIo {
+    fn name(&self) -> &'static str {
+        "port I/O"
+    }
+    fn config_size(&self, _segment: u16, _bus: u8) -> u16 {
+        LEGACY_CONFIG_SIZE
+    }
+    unsafe fn read(&self, address: PCIAddress, offset: u16) -> u32 {
+        if !Self::reachable(address, offset) {
+            return 0xFFFF_FFFF;
+        }
+        let mut port_config_address = Port::new(0xCF8);
+        let mut port_config_data = Port::new(0xCFC);
+        port_config_address.write(Self::config_address(address, offset));
+        port_config_data.read()
+    }
+    unsafe fn write(&self, address: PCIAddress, offset: u16, value: u32) {
+        if !Self::reachable(address, offset) {
+            return;
+        }
+        let mut port_config_address = Port::new(0xCF8);
+        let mut port_config_data = Port::new(0xCFC);
+        port_config_address.write(Self::config_address(address, offset));
+        port_config_data.write(value);
+    }
+    unsafe fn read_u16(&self, address: PCIAddress, offset: u16) -> u16 {
+        if !Self::reachable(address, offset) {
+            return 0xFFFF;
+        }
+        Port::<u32>::new(0xCF8).write(Self::config_address(address, offset));
+        Port::<u16>::new(Self::data_port(offset & !1)).read()
+    }
+    unsafe fn read_u8(&self, address: PCIAddress, offset: u16) -> u8 {
+        if !Self::reachable(address, offset) {
+            return 0xFF;
+        }
+        Port::<u32>::new(0xCF8).write(Self::config_address(address, offset));
+        Port::<u8>::new(Self::data_port(offset)).read()
+    }
+    unsafe fn write_u16(&self, address: PCIAddress, offset: u16, value: u16) {
+        if !Self::reachable(address, offset) {
+            return;
+        }
+        Port::<u32>::new(0xCF8).write(Self::config_address(address, offset));
+        Port::<u16>::new(Self::data_port(offset & !1)).write(value);
+    }
+    unsafe fn write_u8(&self, address: PCIAddress, offset: u16, value: u8) {
+        if !Self::reachable(address, offset) {
+            return;
+        }
+        Port::<u32>::new(0xCF8).write(Self::config_address(address, offset));
+        Port::<u8>::new(Self::data_port(offset)).write(value);
+    }
+}
+
+/// The configuration space of a range of buses, memory mapped as described by an MCFG entry
+#[derive(Debug, Clone, Copy)]
+struct EcamRegion {
+    base_address: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+/// The PCIe enhanced configuration access mechanism, falling back to port I/O for buses it doesn't cover
+pub struct Ecam {
+    regions: Vec<EcamRegion>,
+}
+impl Ecam {
+    /// Collects the ECAM regions of the MCFG, None if there isn't one or it lists no regions
+    pub fn from_acpi() -> Option<Self> {
+        let table = acpi::find_table::<MCFG>()?;
+        let regions: Vec<EcamRegion> = table.get_entries()
+            .filter(|entry| entry.base_address != 0 && entry.start_bus <= entry.end_bus)
+            .map(|entry| EcamRegion {
+                base_address: entry.base_address,
+                segment: entry.segment,
+                start_bus: entry.start_bus,
+                end_bus: entry.end_bus,
+            })
+            .collect();
+        if regions.is_empty() {
+            None
+        } else {
+            Some(Ecam { regions })
+        }
+    }
+    fn region(&self, segment: u16, bus: u8) -> Option<&EcamRegion> {
+        self.regions.iter().find(|region| {
+            region.segment == segment && bus >= region.start_bus && bus <= region.end_bus
+        })
+    }
+    // Each function gets 4KiB: bus in bits 20-27, device in 15-19 and function in 12-14
+    fn register(&self, address: PCIAddress, offset: u16) -> Option<*mut u8> {
+        let region = self.region(address.segment, address.bus)?;
+        if offset >= EXTENDED_CONFIG_SIZE {
+            return None;
+        }
+        let physical = region.base_address
+            + ((address.bus as u64) << 20)
+            + ((address.device as u64 & 0x1F) << 15)
+            + ((address.function as u64 & 0x7) << 12)
+            + offset as u64;
+        Some(memory::phys_to_virt(PhysAddr::new(physical)).as_mut_ptr())
+    }
+}
+impl ConfigAccess for Ecam {
+    fn name(&self) -> &'static str {
+        "ECAM"
+    }
+    fn config_size(&self, segment: u16, bus: u8) -> u16 {
+        if self.region(segment, bus).is_some() {
+            EXTENDED_CONFIG_SIZE
+        } else {
+            PortIo.config_size(segment, bus)
+        }
+    }
+    unsafe fn read(&self, address: PCIAddress, offset: u16) -> u32 {
+        match self.register(address, offset & !3) {
+            Some(register) => core::ptr::read_volatile(register as *const u32),
+            None => PortIo.read(address, offset),
+        }
+    }
+    unsafe fn write(&self, address: PCIAddress, offset: u16, value: u32) {
+        match self.register(address, offset & !3) {
+            Some(register) => core::ptr::write_volatile(register as *mut u32, value),
+            None => PortIo.write(address, offset, value),
+        }
+    }
+    unsafe fn read_u16(&self, address: PCIAddress, offset: u16) -> u16 {
+        match self.register(address, offset & !1) {
+            Some(register) => core::ptr::read_volatile(register as *const u16),
+            None => PortIo.read_u16(address, offset),
+        }
+    }
+    unsafe fn read_u8(&self, address: PCIAddress, offset: u16) -> u8 {
+        match self.register(address, offset) {
+            Some(register) => core::ptr::read_volatile(register),
+            None => PortIo.read_u8(address, offset),
+        }
+    }
+    unsafe fn write_u16(&self, address: PCIAddress, offset: u16, value: u16) {
+        match self.register(address, offset & !1) {
+            Some(register) => core::ptr::write_volatile(register as *mut u16, value),
+            None => PortIo.write_u16(address, offset, value),
+        }
+    }
+    unsafe fn write_u8(&self, address: PCIAddress, offset: u16, value: u8) {
+        match self.register(address, offset) {
+            Some(register) => core::ptr::write_volatile(register, value),
+            None => PortIo.write_u8(address, offset, value),
+        }
+    }
+}
+
+static ECAM: OnceCell<Ecam> = OnceCell::uninit();
+static ACCESS: RwLock<&'static dyn ConfigAccess> = RwLock::new(&PortIo);
+
+/// Switches the mechanism that all configuration space accesses go through
+pub fn set_config_access(access: &'static dyn ConfigAccess) {
+    *ACCESS.write() = access;
+}
+
+pub fn config_access() -> &'static dyn ConfigAccess {
+    *ACCESS.read()
+}
+
+/// Uses ECAM when ACPI describes it, otherwise stays on port I/O
+pub fn init() {
+    if let Some(ecam) = Ecam::from_acpi() {
+        let _ = ECAM.try_init_once(|| ecam);
+        if let Ok(ecam) = ECAM.try_get() {
+            set_config_access(ecam);
+        }
+    }
+}
+
+/// Reads a dword of configuration space through the current mechanism
+/// # Safety
+/// - Reading some registers has side effects on the device
+pub unsafe fn read(address: PCIAddress, offset: u16) -> u32 {
+    config_access().read(address, offset)
+}
+
+/// Writes a dword of configuration space through the current mechanism
+/// # Safety
+/// - Writing configuration space reprograms the device
+pub unsafe fn write(address: PCIAddress, offset: u16, value: u32) {
+    config_access().write(address, offset, value)
+}
+
+/// A configuration register of 8, 16 or 32 bits
+pub trait ConfigRegister: Copy {
+    /// # Safety
+    /// - Reading some registers has side effects on the device
+    unsafe fn read_from(access: &dyn ConfigAccess, address: PCIAddress, offset: u16) -> Self;
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    unsafe fn write_to(self, access: &dyn ConfigAccess, address: PCIAddress, offset: u16);
+}
+impl ConfigRegister for u8 {
+    unsafe fn read_from(access: &dyn ConfigAccess, address: PCIAddress, offset: u16) -> Self {
+        access.read_u8(address, offset)
+    }
+    unsafe fn write_to(self, access: &dyn ConfigAccess, address: PCIAddress, offset: u16) {
+        access.write_u8(address, offset, self)
+    }
+}
+impl ConfigRegister for u16 {
+    unsafe fn read_from(access: &dyn ConfigAccess, address: PCIAddress, offset: u16) -> Self {
+        access.read_u16(address, offset)
+    }
+    unsafe fn write_to(self, access: &dyn ConfigAccess, address: PCIAddress, offset: u16) {
+        access.write_u16(address, offset, self)
+    }
+}
+impl ConfigRegister for u32 {
+    unsafe fn read_from(access: &dyn ConfigAccess, address: PCIAddress, offset: u16) -> Self {
+        access.read(address, offset)
+    }
+    unsafe fn write_to(self, access: &dyn ConfigAccess, address: PCIAddress, offset: u16) {
+        access.write(address, offset, self)
+    }
+}
+
+/// Reads a register of the given width through the current mechanism
+/// # Safety
+/// - Reading some registers has side effects on the device
+pub unsafe fn read_register<T: ConfigRegister>(address: PCIAddress, offset: u16) -> T {
+    T::read_from(config_access(), address, offset)
+}
+
+/// Writes a register of the given width through the current mechanism
+/// # Safety
+/// - Writing configuration space reprograms the device
+pub unsafe fn write_register<T: ConfigRegister>(address: PCIAddress, offset: u16, value: T) {
+    value.write_to(config_access(), address, offset)
+}
+
+/// Reads a register, passes it through `f` and writes back the result, which is returned
+///
+/// This isn't atomic, callers that share a function between CPUs have to lock around it.
+/// # Safety
+/// - Writing configuration space reprograms the device
+pub unsafe fn modify_register<T: ConfigRegister>(address: PCIAddress, offset: u16, f: impl FnOnce(T) -> T) -> T {
+    let access = config_access();
+    let value = f(T::read_from(access, address, offset));
+    value.write_to(access, address, offset);
+    value
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_ecam_addressing() {
+    serial_print!("test_ecam_addressing... ");
+    let ecam = Ecam {
+        regions: alloc::vec![EcamRegion { base_address: 0xB000_0000, segment: 0, start_bus: 0, end_bus: 0x3F }],
+    };
+    let base = memory::phys_to_virt(PhysAddr::new(0xB000_0000)).as_u64();
+    let register = ecam.register(PCIAddress::new(0, 1, 2, 3), 0x104).unwrap() as u64;
+    assert_eq!(register - base, (1 << 20) | (2 << 15) | (3 << 12) | 0x104);
+    assert!(ecam.register(PCIAddress::new(0, 0x40, 0, 0), 0).is_none());
+    assert!(ecam.register(PCIAddress::new(1, 0, 0, 0), 0).is_none());
+    assert_eq!(ecam.config_size(0, 0), EXTENDED_CONFIG_SIZE);
+    assert_eq!(ecam.config_size(0, 0x40), LEGACY_CONFIG_SIZE);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_config_write_within_dword() {
+    use core::sync::atomic::{AtomicU32, Ordering};
+    serial_print!("test_config_write_within_dword... ");
+    //a single dword of configuration space, to check the default narrow writes
+    struct Register(AtomicU32);
+    impl ConfigAccess for Register {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+        fn config_size(&self, _segment: u16, _bus: u8) -> u16 {
+            LEGACY_CONFIG_SIZE
+        }
+        unsafe fn read(&self, _address: PCIAddress, _offset: u16) -> u32 {
+            self.0.load(Ordering::Relaxed)
+        }
+        unsafe fn write(&self, _address: PCIAddress, _offset: u16, value: u32) {
+            self.0.store(value, Ordering::Relaxed)
+        }
+    }
+    let register = Register(AtomicU32::new(0x1234_5678));
+    let address = PCIAddress::default();
+    unsafe {
+        register.write_u8(address, 0x11, 0xAB);
+        assert_eq!(register.read(address, 0x10), 0x1234_AB78);
+        register.write_u16(address, 0x12, 0xCDEF);
+        assert_eq!(register.read(address, 0x10), 0xCDEF_AB78);
+        assert_eq!(register.read_u16(address, 0x12), 0xCDEF);
+        assert_eq!(register.read_u8(address, 0x10), 0x78);
+        //writing the command register must not write back the status register's set bits
+        register.write_u16(address, 0x04, 0x0006);
+        assert_eq!(register.read(address, 0x04), 0x0000_0006);
+    }
+    serial_println!("[ok]");
+}
@@ -0,0 +1,135 @@
+use super::{config::PCIAddress, PCIHeader};
+
+pub struct Subsystem {
+    pub vendor: u16,
+    pub device: u16,
+    pub name: &'static str,
+}
+
+pub struct Device {
+    pub id: u16,
+    pub name: &'static str,
+    pub subsystems: &'static [Subsystem],
+}
+
+pub struct Vendor {
+    pub id: u16,
+    pub name: &'static str,
+    pub devices: &'static [Device],
+}
+
+// Generated by build.rs from pci.ids, sorted by ID at every level
+static VENDORS: &[Vendor] = include!(concat!(env!("OUT_DIR"), "/pci_ids.rs"));
+
+pub fn vendor(vendor_id: u16) -> Option<&'static Vendor> {
+    VENDORS
+        .binary_search_by_key(&vendor_id, |vendor| vendor.id)
+        .ok()
+        .map(|index| &VENDORS[index])
+}
+
+pub fn device(vendor_id: u16, device_id: u16) -> Option<&'static Device> {
+    let devices = vendor(vendor_id)?.devices;
+    devices
+        .binary_search_by_key(&device_id, |device| device.id)
+        .ok()
+        .map(|index| &devices[index])
+}
+
+pub fn subsystem(vendor_id: u16, device_id: u16, subvendor_id: u16, subdevice_id: u16) -> Option<&'static Subsystem> {
+    let subsystems = device(vendor_id, device_id)?.subsystems;
+    subsystems
+        .binary_search_by_key(&(subvendor_id, subdevice_id), |subsystem| (subsystem.vendor, subsystem.device))
+        .ok()
+        .map(|index| &subsystems[index])
+}
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    vendor(vendor_id).map(|vendor| vendor.name)
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    device(vendor_id, device_id).map(|device| device.name)
+}
+
+/// Formats a function like a line of `lspci`, the alternate form adds its subsystem on a second line
+pub struct Lspci<'a> {
+    header: &'a PCIHeader,
+    address: PCIAddress,
+}
+impl<'a> Lspci<'a> {
+    pub fn new(header: &'a PCIHeader, address: PCIAddress) -> Self {
+        Lspci { header, address }
+    }
+}
+
+// Vendor and device names with lspci's fallbacks to raw IDs
+fn write_names(f: &mut core::fmt::Formatter<'_>, vendor_id: u16, device_id: u16, name: Option<&str>) -> core::fmt::Result {
+    match (vendor_name(vendor_id), name) {
+        (Some(vendor), Some(device)) => write!(f, "{} {}", vendor, device),
+        (Some(vendor), None) => write!(f, "{} Device {:04x}", vendor, device_id),
+        (None, _) => write!(f, "Device {:04x}:{:04x}", vendor_id, device_id),
+    }
+}
+
+impl core::fmt::Display for Lspci<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let header = self.header;
+        if self.address.segment != 0 {
+            write!(f, "{:04x}:", self.address.segment)?;
+        }
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {}: ",
+            self.address.bus,
+            self.address.device,
+            self.address.function,
+            header.class_code()
+        )?;
+        write_names(f, header.vendor_id, header.device_id, device_name(header.vendor_id, header.device_id))?;
+        if header.revision != 0 {
+            write!(f, " (rev {:02x})", header.revision)?;
+        }
+        //only type 0 headers have a subsystem
+        if f.alternate() && header.header_type & 0x7F == 0 && header.subsystem_vendor_id != 0 {
+            write!(f, "\n\tSubsystem: ")?;
+            let name = subsystem(header.vendor_id, header.device_id, header.subsystem_vendor_id, header.subsystem_id)
+                .map(|subsystem| subsystem.name);
+            write_names(f, header.subsystem_vendor_id, header.subsystem_id, name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_pci_ids() {
+    use alloc::format;
+    serial_print!("test_pci_ids... ");
+    assert_eq!(device_name(0x8086, 0x100E), Some("82540EM Gigabit Ethernet Controller"));
+    assert_eq!(subsystem(0x1AF4, 0x1000, 0x1AF4, 0x0001).map(|subsystem| subsystem.name), Some("Virtio network device"));
+    assert!(device_name(0x8086, 0xFFFF).is_none());
+    //every level has to stay sorted for the binary searches
+    assert!(VENDORS.windows(2).all(|pair| pair[0].id < pair[1].id));
+    assert!(VENDORS.iter().all(|vendor| vendor.devices.windows(2).all(|pair| pair[0].id < pair[1].id)));
+
+    let header = PCIHeader {
+        vendor_id: 0x1AF4,
+        device_id: 0x1000,
+        class: 0x02,
+        subsystem_vendor_id: 0x1AF4,
+        subsystem_id: 0x0001,
+        ..PCIHeader::default()
+    };
+    let address = PCIAddress::new(0, 0, 3, 0);
+    assert_eq!(format!("{}", Lspci::new(&header, address)), "00:03.0 Ethernet controller: Red Hat, Inc. Virtio network device");
+    assert_eq!(
+        format!("{:#}", Lspci::new(&header, address)),
+        "00:03.0 Ethernet controller: Red Hat, Inc. Virtio network device\n\tSubsystem: Red Hat, Inc. Virtio network device"
+    );
+    let unknown = PCIHeader { vendor_id: 0xABCD, device_id: 0x0001, revision: 2, ..PCIHeader::default() };
+    assert_eq!(format!("{}", Lspci::new(&unknown, address)), "00:03.0 Non-VGA unclassified device: Device abcd:0001 (rev 02)");
+    serial_println!("[ok]");
+}
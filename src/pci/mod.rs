@@ -1,12 +1,22 @@
 use core::mem;
 use core::ops::{Deref, DerefMut};
 use core::slice;
-use x86_64::instructions::port::Port;
 
+pub mod config;
+use config::{ConfigRegister, PCIAddress};
+pub mod ids;
 mod pci_class;
-use pci_class::PCIClass;
+pub use pci_class::{PCIClass, PCIClassCode};
 mod pci_bar;
-use pci_bar::PCIBar;
+pub use pci_bar::{PCIBar, PCIBarIter};
+mod bridge;
+pub use bridge::{PCIBridgeHeader, PCICardBusHeader};
+pub mod tree;
+pub mod capability;
+use capability::{Capability, CapabilityIter};
+pub mod msi;
+use msi::MsiError;
+use alloc::vec::Vec;
 
 #[derive(Default, Debug)]
 #[repr(C)]
@@ -18,7 +28,7 @@ pub struct PCIHeader {
     pub revision: u8,
     pub interface: u8,
     pub subclass: u8,
-    pub class: PCIClass,
+    pub class: u8,
     pub cache_line_size: u8,
     pub latency_timer: u8,
     pub header_type: u8,
@@ -36,101 +46,359 @@ pub struct PCIHeader {
     pub max_latency: u8,
 }
 
-impl Deref for PCIHeader {
-    type Target = [u32];
-    fn deref(&self) -> &[u32] {
-        unsafe {
-            slice::from_raw_parts(
-                self as *const PCIHeader as *const u32,
-                mem::size_of::<PCIHeader>() / 4,
-            ) as &[u32]
+// Lets a header be filled and inspected as the dwords of configuration space it was read from
+macro_rules! impl_dwords {
+    ($header:ty) => {
+        impl Deref for $header {
+            type Target = [u32];
+            fn deref(&self) -> &[u32] {
+                unsafe {
+                    slice::from_raw_parts(
+                        self as *const $header as *const u32,
+                        mem::size_of::<$header>() / 4,
+                    ) as &[u32]
+                }
+            }
+        }
+
+        impl DerefMut for $header {
+            fn deref_mut(&mut self) -> &mut [u32] {
+                unsafe {
+                    slice::from_raw_parts_mut(
+                        self as *mut $header as *mut u32,
+                        mem::size_of::<$header>() / 4,
+                    ) as &mut [u32]
+                }
+            }
+        }
+    };
+}
+impl_dwords!(PCIHeader);
+impl_dwords!(PCIBridgeHeader);
+impl_dwords!(PCICardBusHeader);
+
+/// Reads a header from configuration space, None if no function answers at the address
+// if the device does not exist, the host bridge will return all ones on read
+fn read_header<H: Default + DerefMut<Target = [u32]>>(address: PCIAddress) -> Option<H> {
+    unsafe {
+        if config::read(address, 0) != 0xFFFF_FFFF {
+            let mut header = H::default();
+            let dwords = header.deref_mut();
+            dwords.iter_mut().fold(0u16, |offset, dword| {
+                *dword = config::read(address, offset);
+                offset + 4
+            });
+            Some(header)
+        } else {
+            None
         }
     }
 }
 
-impl DerefMut for PCIHeader {
-    fn deref_mut(&mut self) -> &mut [u32] {
-        unsafe {
-            slice::from_raw_parts_mut(
-                self as *mut PCIHeader as *mut u32,
-                mem::size_of::<PCIHeader>() / 4,
-            ) as &mut [u32]
+/// The layout of a function's header after the first 16 bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    General,
+    PCIBridge,
+    CardBusBridge,
+    Unknown(u8),
+}
+impl HeaderType {
+    pub fn bar_count(&self) -> usize {
+        match self {
+            HeaderType::General => 6,
+            HeaderType::PCIBridge => 2,
+            _ => 0,
+        }
+    }
+}
+impl From<u8> for HeaderType {
+    fn from(header_type: u8) -> Self {
+        match header_type & 0x7F {
+            0x00 => HeaderType::General,
+            0x01 => HeaderType::PCIBridge,
+            0x02 => HeaderType::CardBusBridge,
+            layout => HeaderType::Unknown(layout),
         }
     }
 }
 
+impl PCIHeader {
+    /// Reads the header of the function at `address`
+    ///
+    /// Only the fields up to `bist` are valid when the function isn't `HeaderType::General`.
+    pub fn read(address: PCIAddress) -> Option<Self> {
+        read_header(address)
+    }
+    pub fn layout(&self) -> HeaderType {
+        HeaderType::from(self.header_type)
+    }
+    /// Whether `capabilities` points at a list of capabilities, see `PCIFunc::capabilities`
+    pub fn has_capabilities(&self) -> bool {
+        self.status & (1 << 4) != 0
+    }
+    /// Whether function 0 of the device says it has other functions
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type & 0x80 != 0
+    }
+    /// The decoded class, subclass and programming interface
+    pub fn class_code(&self) -> PCIClassCode {
+        PCIClassCode::new(self.class, self.subclass, self.interface)
+    }
+    /// Formats the function like `lspci` does, naming its vendor and device
+    pub fn lspci(&self, address: PCIAddress) -> ids::Lspci<'_> {
+        ids::Lspci::new(self, address)
+    }
+    /// The number of base address registers the header type has
+    pub fn bar_count(&self) -> usize {
+        self.layout().bar_count()
+    }
+    /// The BARs as they are currently programmed, without their sizes
+    pub fn bars(&self) -> PCIBarIter<'_> {
+        PCIBarIter::new(&self.base_address_registers[..self.bar_count()])
+    }
+}
+
 impl core::fmt::Display for PCIHeader {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "{:?}", self.class)?;
-        match self.class {
-            PCIClass::MassStorage => match self.subclass {
-                0x01 => write!(f, " IDE"),
-                0x06 => write!(f, " SATA"),
-                _ => Ok(()),
-            },
-            PCIClass::SerialBus => match self.subclass {
-                0x03 => match self.interface {
-                    0x00 => write!(f, " UHCI"),
-                    0x10 => write!(f, " OHCI"),
-                    0x20 => write!(f, " EHCI"),
-                    0x30 => write!(f, " XHCI"),
-                    _ => Ok(()),
-                },
-                _ => Ok(()),
-            },
-            _ => Ok(()),
-        }?;
-        writeln!(f)?;
+        writeln!(f, "{}", self.class_code())?;
         writeln!(
             f,
             "latency: {}, IRQ:{}",
             self.max_latency, self.interrupt_line
         )?;
-        for (i, base_address_register) in self.base_address_registers.iter().enumerate() {
-            match PCIBar::from(*base_address_register) {
+        for (i, bar) in self.bars() {
+            match bar {
                 PCIBar::None => Ok(()),
-                PCIBar::Memory((address, prefetchable)) => {
-
+                PCIBar::Memory { address, prefetchable, is_64bit, .. } => {
 					write!(f, "BAR[{}]:{:>08X}(memory", i, address)?;
+					if is_64bit {
+						write!(f, ", 64-bit")?;
+					}
 					if prefetchable {
-						write!(f, ", prefetchable)")?;
+						write!(f, ", prefetchable")?;
 					}
 					writeln!(f, ")")?;
 					Ok(())
 				},
-                PCIBar::Port(address) => writeln!(f, "BAR[{}]:{:>04X}(port)", i, address),
+                PCIBar::Port { address, .. } => writeln!(f, "BAR[{}]:{:>04X}(port)", i, address),
             }?;
         }
         Ok(())
     }
 }
 
+/// Offset of the command register in every header type
+pub const COMMAND_OFFSET: u16 = 0x04;
+/// Offset of the header type register, whose top bit marks multifunction devices
+pub const HEADER_TYPE_OFFSET: u16 = 0x0E;
+/// Offset of the first base address register
+pub const BAR_OFFSET: u16 = 0x10;
+
+/// Bits of the command register
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
 pub struct PCIFunc<'pci> {
     pub device: &'pci PCIDevice<'pci>,
     pub num: u8,
 }
 impl<'pci> PCIFunc<'pci> {
     pub fn header(&self) -> Option<PCIHeader> {
-        // if the device does not exist, the host bridge will return all ones on read
-        unsafe {
-            if self.read(0) != 0xFFFF_FFFF {
-                let mut header = PCIHeader::default();
-                let dwords = header.deref_mut();
-                dwords.iter_mut().fold(0usize, |offset, dword| {
-                    *dword = self.read(offset as u8);
-                    offset + 4
-                });
-                Some(header)
+        PCIHeader::read(self.address())
+    }
+    /// The header of a PCI-to-PCI bridge, None for other header types
+    pub fn bridge_header(&self) -> Option<PCIBridgeHeader> {
+        PCIBridgeHeader::read(self.address())
+    }
+    /// The header of a CardBus bridge, None for other header types
+    pub fn cardbus_header(&self) -> Option<PCICardBusHeader> {
+        PCICardBusHeader::read(self.address())
+    }
+    pub fn header_type(&self) -> HeaderType {
+        HeaderType::from(unsafe { self.read_register::<u8>(HEADER_TYPE_OFFSET) })
+    }
+    pub fn address(&self) -> PCIAddress {
+        PCIAddress::new(0, self.device.bus.num, self.device.num, self.num)
+    }
+    /// Reads from the PCI controller for this Function and its device/bus
+    /// # Safety
+    /// - Reading some registers has side effects on the device
+    pub unsafe fn read(&self, offset: u16) -> u32 {
+        self.device.read(self.num, offset)
+    }
+    /// Writes to the PCI controller for this Function and its device/bus
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    pub unsafe fn write(&self, offset: u16, value: u32) {
+        self.device.write(self.num, offset, value)
+    }
+    /// Reads an 8, 16 or 32-bit register of this function
+    /// # Safety
+    /// - Reading some registers has side effects on the device
+    pub unsafe fn read_register<T: ConfigRegister>(&self, offset: u16) -> T {
+        config::read_register(self.address(), offset)
+    }
+    /// Writes an 8, 16 or 32-bit register of this function
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    pub unsafe fn write_register<T: ConfigRegister>(&self, offset: u16, value: T) {
+        config::write_register(self.address(), offset, value)
+    }
+    /// Read-modify-writes an 8, 16 or 32-bit register of this function, returning the new value
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    pub unsafe fn modify_register<T: ConfigRegister>(&self, offset: u16, f: impl FnOnce(T) -> T) -> T {
+        config::modify_register(self.address(), offset, f)
+    }
+
+    pub fn command(&self) -> u16 {
+        unsafe { self.read_register(COMMAND_OFFSET) }
+    }
+    /// Sets or clears the given command register bits
+    /// # Safety
+    /// - Enabling decoding or bus mastering lets the device claim addresses and access memory
+    pub unsafe fn set_command_bits(&self, bits: u16, enabled: bool) {
+        self.modify_register(COMMAND_OFFSET, |command: u16| {
+            if enabled {
+                command | bits
             } else {
-                None
+                command & !bits
+            }
+        });
+    }
+    /// Lets the function issue memory requests, which DMA and MSIs need
+    /// # Safety
+    /// - The device can read and write any memory it is pointed at
+    pub unsafe fn enable_bus_master(&self) {
+        self.set_command_bits(COMMAND_BUS_MASTER, true)
+    }
+    /// # Safety
+    /// - The device can read and write any memory it is pointed at
+    pub unsafe fn set_bus_master(&self, enabled: bool) {
+        self.set_command_bits(COMMAND_BUS_MASTER, enabled)
+    }
+    /// Turns decoding of the function's memory BARs on or off
+    /// # Safety
+    /// - The memory BARs must not overlap anything else once decoding is on
+    pub unsafe fn set_memory_space(&self, enabled: bool) {
+        self.set_command_bits(COMMAND_MEMORY_SPACE, enabled)
+    }
+    /// Turns decoding of the function's I/O BARs on or off
+    /// # Safety
+    /// - The I/O BARs must not overlap other ports once decoding is on
+    pub unsafe fn set_io_space(&self, enabled: bool) {
+        self.set_command_bits(COMMAND_IO_SPACE, enabled)
+    }
+    /// Masks or unmasks the function's legacy INTx interrupt
+    /// # Safety
+    /// - Drivers relying on INTx stop receiving interrupts while it is disabled
+    pub unsafe fn set_interrupt_disable(&self, disabled: bool) {
+        self.set_command_bits(COMMAND_INTERRUPT_DISABLE, disabled)
+    }
+    /// Sizes the BAR at `index` by writing all ones to it and reading back, then restores it
+    ///
+    /// Decoding is turned off while the BAR is being probed.
+    /// # Safety
+    /// - No driver may be using the function's BARs while they are probed
+    pub unsafe fn probe_bar(&self, index: usize) -> PCIBar {
+        assert!(index < 6, "BAR index out of range");
+        let offset = BAR_OFFSET + index as u16 * 4;
+        let original = self.read(offset);
+        let is_64bit = PCIBar::is_64bit_memory(original) && index < 5;
+        let command = self.command();
+        self.write_register(COMMAND_OFFSET, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+        self.write(offset, 0xFFFF_FFFF);
+        let sized = self.read(offset);
+        self.write(offset, original);
+        let (original_high, sized_high) = if is_64bit {
+            let original_high = self.read(offset + 4);
+            self.write(offset + 4, 0xFFFF_FFFF);
+            let sized_high = self.read(offset + 4);
+            self.write(offset + 4, original_high);
+            (original_high, sized_high)
+        } else {
+            (0, 0)
+        };
+
+        self.write_register(COMMAND_OFFSET, command);
+        PCIBar::from_probe(original, original_high, sized, sized_high)
+    }
+    /// Probes every implemented BAR of the function along with its index, skipping the upper halves of 64-bit BARs
+    /// # Safety
+    /// - No driver may be using the function's BARs while they are probed
+    pub unsafe fn bars(&self) -> Vec<(usize, PCIBar)> {
+        let bar_count = self.header_type().bar_count();
+        let mut bars = Vec::new();
+        let mut index = 0;
+        while index < bar_count {
+            let bar = self.probe_bar(index);
+            //a 64-bit BAR's upper half is never its own BAR, implemented or not
+            let skip = if PCIBar::is_64bit_memory(self.read(BAR_OFFSET + index as u16 * 4)) { 2 } else { 1 };
+            if !bar.is_none() {
+                bars.push((index, bar));
             }
+            index += skip;
         }
+        bars
     }
-    /// Reads from the PCI controller for this Function and its device/bus
+    /// Walks the function's capability list
+    pub fn capabilities(&self) -> CapabilityIter {
+        CapabilityIter::new(self.address())
+    }
+    /// Points the function's interrupt at `vector` on the local APIC `apic_id`, through MSI-X or else MSI
+    ///
+    /// Every MSI-X entry gets the same vector. Legacy INTx is disabled and bus mastering enabled,
+    /// since a message is a memory write by the function.
     /// # Safety
-    /// - This method reads from CPU I/O ports
-    pub unsafe fn read(&self, offset: u8) -> u32 {
-        self.device.read(self.num, offset)
+    /// - `vector` must have a handler, such as one from `interrupts::allocate_msi_vector`
+    /// - No driver may be relying on the function's INTx interrupt
+    pub unsafe fn enable_message_interrupt(&self, apic_id: u32, vector: u8) -> Result<(), MsiError> {
+        let mut msi = None;
+        let mut msix = None;
+        for capability in self.capabilities() {
+            match capability {
+                Capability::Msi(capability) => msi = Some(capability),
+                Capability::MsiX(capability) => msix = Some(capability),
+                _ => {}
+            }
+        }
+        if let Some(msix) = msix {
+            //a function must not have MSI and MSI-X enabled at the same time
+            if let Some(msi) = msi {
+                msi.set_enabled(false);
+            }
+            //the table lives in a memory BAR, so decoding has to be on to program it
+            self.set_memory_space(true);
+            //keep every vector masked while the table is filled in
+            msix.set_function_masked(true);
+            msix.set_enabled(true);
+            for entry in 0..msix.table_size() {
+                msix.set_entry_masked(entry, true)?;
+                msix.set_entry(entry, apic_id, vector)?;
+                msix.set_entry_masked(entry, false)?;
+            }
+            msix.set_function_masked(false);
+        } else if let Some(msi) = msi {
+            msi.set_enabled(false);
+            msi.configure(apic_id, vector, 1)?;
+            msi.set_enabled(true);
+        } else {
+            return Err(MsiError::Unsupported);
+        }
+        self.set_interrupt_disable(true);
+        self.enable_bus_master();
+        Ok(())
+    }
+    /// Programs one of the base address registers
+    /// # Safety
+    /// - Decoding should be off while the BAR is changed, and the new address must not overlap anything
+    pub unsafe fn write_bar(&self, index: usize, value: u32) {
+        assert!(index < 6, "BAR index out of range");
+        self.write(BAR_OFFSET + index as u16 * 4, value)
     }
 }
 
@@ -142,29 +410,51 @@ impl<'pci> PCIDevice<'pci> {
     pub fn functions(&'pci self) -> PCIDeviceIter<'pci> {
         PCIDeviceIter::new(self)
     }
+    /// Whether function 0 exists, which every present device has
+    pub fn present(&self) -> bool {
+        unsafe { self.read(0, 0) != 0xFFFF_FFFF }
+    }
+    pub fn is_multifunction(&self) -> bool {
+        let header_type = unsafe { self.read(0, HEADER_TYPE_OFFSET & !3) } >> ((HEADER_TYPE_OFFSET & 3) * 8);
+        header_type & 0x80 != 0
+    }
     /// Reads from the PCI controller for this device and its bus
     /// # Safety
-    /// - This method reads from CPU I/O ports
-    pub unsafe fn read(&self, func: u8, offset: u8) -> u32 {
+    /// - Reading some registers has side effects on the device
+    pub unsafe fn read(&self, func: u8, offset: u16) -> u32 {
         self.bus.read(self.num, func, offset)
     }
+    /// Writes to the PCI controller for this device and its bus
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    pub unsafe fn write(&self, func: u8, offset: u16, value: u32) {
+        self.bus.write(self.num, func, offset, value)
+    }
 }
+/// Iterates over the functions of a device, which is only function 0 unless the device is multifunction
 pub struct PCIDeviceIter<'pci> {
     device: &'pci PCIDevice<'pci>,
     count: u8,
+    functions: u8,
 }
 impl<'pci> PCIDeviceIter<'pci> {
     pub fn new(device: &'pci PCIDevice<'pci>) -> Self {
+        let functions = match device.present() {
+            false => 0,
+            true if device.is_multifunction() => 8,
+            true => 1,
+        };
         Self {
             device,
             count: 0,
+            functions,
         }
     }
 }
 impl<'pci> Iterator for PCIDeviceIter<'pci> {
     type Item = PCIFunc<'pci>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.count < 8 {
+        if self.count < self.functions {
             let func = PCIFunc {
                 device: self.device,
                 num: self.count,
@@ -187,10 +477,16 @@ impl<'pci> PCIBus<'pci> {
     }
     /// Reads from the PCI controller for this bus
     /// # Safety
-    /// - This method reads from CPU I/O ports
-    pub unsafe fn read(&self, device: u8, func: u8, offset: u8) -> u32 {
+    /// - Reading some registers has side effects on the device
+    pub unsafe fn read(&self, device: u8, func: u8, offset: u16) -> u32 {
         self.pci.read(self.num, device, func, offset)
     }
+    /// Writes to the PCI controller for this bus
+    /// # Safety
+    /// - Writing configuration space reprograms the device
+    pub unsafe fn write(&self, device: u8, func: u8, offset: u16, value: u32) {
+        self.pci.write(self.num, device, func, offset, value)
+    }
 }
 
 /// Iterates over devices on a given PCIBus
@@ -230,19 +526,17 @@ impl PCI {
         PCIIter::new(self)
     }
 
-    /// Read from the PCI controller for the given arguments
+    /// Read from the PCI controller for the given arguments, through ECAM when it is available
+    /// # Safety
+    /// - Reading some registers has side effects on the device
+    pub unsafe fn read(&self, bus: u8, device: u8, func: u8, offset: u16) -> u32 {
+        config::read(PCIAddress::new(0, bus, device, func), offset)
+    }
+    /// Write to the PCI controller for the given arguments, through ECAM when it is available
     /// # Safety
-    /// - This method reads from CPU I/O ports
-    pub unsafe fn read(&self, bus: u8, device: u8, func: u8, offset: u8) -> u32 {
-        let address = 0x8000_0000
-            | ((bus as u32) << 16)
-            | ((device as u32) << 11)
-            | ((func as u32) << 8)
-            | ((offset as u32) & 0xFC);
-        let mut port_config_address = Port::new(0xCF8);
-        let mut port_config_data = Port::new(0xCFC);
-        port_config_address.write(address);
-        port_config_data.read()
+    /// - Writing configuration space reprograms the device
+    pub unsafe fn write(&self, bus: u8, device: u8, func: u8, offset: u16, value: u32) {
+        config::write(PCIAddress::new(0, bus, device, func), offset, value)
     }
 }
 
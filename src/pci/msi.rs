@@ -0,0 +1,246 @@
+use super::{capability::CapabilityAddress, config, PCIBar, BAR_OFFSET};
+use crate::memory;
+use x86_64::PhysAddr;
+
+/// Where MSI writes that target a local APIC go, the destination APIC ID sits in bits 12-19
+const MESSAGE_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// Vectors below 0x10 are reserved for exceptions and can't be delivered by a message
+    InvalidVector(u8),
+    /// Only local APIC IDs up to 255 fit in a message address without interrupt remapping
+    DestinationTooHigh(u32),
+    /// MSI can only hand out a power of two of vectors, up to what the function supports
+    InvalidVectorCount { requested: u8, supported: u8 },
+    /// Multiple MSI vectors are told apart by their low bits, so the first one must be aligned to the count
+    MisalignedVector(u8),
+    /// The MSI-X table entry doesn't exist
+    EntryOutOfRange(u16),
+    /// The MSI-X table is said to be behind a BAR that doesn't exist or isn't memory
+    InvalidTableBar(u8),
+    /// The function has neither MSI nor MSI-X
+    Unsupported,
+}
+
+/// The message address that delivers an interrupt to the local APIC `apic_id`, in physical destination mode
+pub fn message_address(apic_id: u32) -> Result<u64, MsiError> {
+    if apic_id > 0xFF {
+        return Err(MsiError::DestinationTooHigh(apic_id));
+    }
+    Ok(MESSAGE_ADDRESS_BASE | (apic_id as u64) << 12)
+}
+
+/// The message data for an edge triggered interrupt with fixed delivery of `vector`
+pub fn message_data(vector: u8) -> Result<u32, MsiError> {
+    if vector < 0x10 {
+        return Err(MsiError::InvalidVector(vector));
+    }
+    Ok(vector as u32)
+}
+
+/// The MSI capability, which has the function write one of up to 32 consecutive vectors to a single address
+#[derive(Debug, Clone, Copy)]
+pub struct Msi(CapabilityAddress);
+impl Msi {
+    const CONTROL: u16 = 0x02;
+    const ADDRESS: u16 = 0x04;
+    const CONTROL_ENABLE: u16 = 1 << 0;
+    const CONTROL_64BIT: u16 = 1 << 7;
+    const CONTROL_PER_VECTOR_MASKING: u16 = 1 << 8;
+
+    pub fn new(address: CapabilityAddress) -> Self {
+        Msi(address)
+    }
+    pub fn address(&self) -> CapabilityAddress {
+        self.0
+    }
+    pub fn control(&self) -> u16 {
+        unsafe { self.0.read(Self::CONTROL) }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.control() & Self::CONTROL_ENABLE != 0
+    }
+    pub fn is_64bit(&self) -> bool {
+        self.control() & Self::CONTROL_64BIT != 0
+    }
+    pub fn has_per_vector_masking(&self) -> bool {
+        self.control() & Self::CONTROL_PER_VECTOR_MASKING != 0
+    }
+    /// The number of vectors the function can use
+    pub fn max_vectors(&self) -> u8 {
+        1 << ((self.control() >> 1) & 0b111).min(5)
+    }
+    // The data, mask and pending registers move up by a dword when the address is 64-bit
+    fn data_offset(&self) -> u16 {
+        if self.is_64bit() { 0x0C } else { 0x08 }
+    }
+    fn mask_offset(&self) -> u16 {
+        self.data_offset() + 4
+    }
+
+    /// Points the function's `count` vectors, starting at `vector`, at the local APIC `apic_id`
+    ///
+    /// This doesn't enable MSI, see `set_enabled`.
+    /// # Safety
+    /// - The vectors must have handlers, and the function must not be sending MSIs while it is reprogrammed
+    pub unsafe fn configure(&self, apic_id: u32, vector: u8, count: u8) -> Result<(), MsiError> {
+        let supported = self.max_vectors();
+        if count == 0 || !count.is_power_of_two() || count > supported {
+            return Err(MsiError::InvalidVectorCount { requested: count, supported });
+        }
+        if vector % count != 0 {
+            return Err(MsiError::MisalignedVector(vector));
+        }
+        let address = message_address(apic_id)?;
+        let data = message_data(vector)?;
+        if vector.checked_add(count - 1).is_none() {
+            return Err(MsiError::InvalidVector(vector));
+        }
+
+        self.0.write(Self::ADDRESS, address as u32);
+        if self.is_64bit() {
+            self.0.write(Self::ADDRESS + 4, (address >> 32) as u32);
+        }
+        self.0.write(self.data_offset(), data as u16);
+        //multiple message enable holds log2 of the number of vectors
+        let enabled_log2 = count.trailing_zeros() as u16;
+        let control = (self.control() & !(0b111 << 4)) | (enabled_log2 << 4);
+        self.0.write(Self::CONTROL, control);
+        Ok(())
+    }
+    /// # Safety
+    /// - The configured vectors must have handlers before MSI is enabled
+    pub unsafe fn set_enabled(&self, enabled: bool) {
+        let control = self.control();
+        let control = if enabled { control | Self::CONTROL_ENABLE } else { control & !Self::CONTROL_ENABLE };
+        self.0.write(Self::CONTROL, control);
+    }
+    /// Masks or unmasks one of the vectors, if the function supports per-vector masking
+    /// # Safety
+    /// - Interrupts raised while a vector is masked are held as pending until it is unmasked
+    pub unsafe fn set_masked(&self, index: u8, masked: bool) -> bool {
+        if !self.has_per_vector_masking() || index >= 32 {
+            return false;
+        }
+        let mask: u32 = self.0.read(self.mask_offset());
+        let mask = if masked { mask | 1 << index } else { mask & !(1 << index) };
+        self.0.write(self.mask_offset(), mask);
+        true
+    }
+}
+
+/// The MSI-X capability, whose table in one of the function's BARs gives each vector its own address and data
+#[derive(Debug, Clone, Copy)]
+pub struct MsiX(CapabilityAddress);
+impl MsiX {
+    const CONTROL: u16 = 0x02;
+    const TABLE: u16 = 0x04;
+    const PENDING_TABLE: u16 = 0x08;
+    const CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+    const CONTROL_ENABLE: u16 = 1 << 15;
+    const ENTRY_SIZE: u64 = 16;
+    const ENTRY_MASKED: u32 = 1 << 0;
+
+    pub fn new(address: CapabilityAddress) -> Self {
+        MsiX(address)
+    }
+    pub fn address(&self) -> CapabilityAddress {
+        self.0
+    }
+    pub fn control(&self) -> u16 {
+        unsafe { self.0.read(Self::CONTROL) }
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.control() & Self::CONTROL_ENABLE != 0
+    }
+    /// The number of entries in the table
+    pub fn table_size(&self) -> u16 {
+        (self.control() & 0x7FF) + 1
+    }
+
+    // A table register holds the BAR index in its low 3 bits and the offset into that BAR above them
+    fn locate(&self, register: u16) -> Result<u64, MsiError> {
+        let table: u32 = unsafe { self.0.read(register) };
+        let bar_index = (table & 0b111) as u8;
+        if bar_index > 5 {
+            return Err(MsiError::InvalidTableBar(bar_index));
+        }
+        let function = self.0.function;
+        let bar_offset = BAR_OFFSET + bar_index as u16 * 4;
+        let registers = unsafe {
+            [config::read(function, bar_offset), if bar_index < 5 { config::read(function, bar_offset + 4) } else { 0 }]
+        };
+        match PCIBar::decode(&registers, 0) {
+            PCIBar::Memory { address, .. } => Ok(address + (table & !0b111) as u64),
+            _ => Err(MsiError::InvalidTableBar(bar_index)),
+        }
+    }
+    /// The physical address of the vector table
+    pub fn table_address(&self) -> Result<u64, MsiError> {
+        self.locate(Self::TABLE)
+    }
+    /// The physical address of the pending bit array
+    pub fn pending_address(&self) -> Result<u64, MsiError> {
+        self.locate(Self::PENDING_TABLE)
+    }
+
+    fn entry(&self, index: u16) -> Result<*mut u32, MsiError> {
+        if index >= self.table_size() {
+            return Err(MsiError::EntryOutOfRange(index));
+        }
+        let physical = self.table_address()? + index as u64 * Self::ENTRY_SIZE;
+        Ok(memory::phys_to_virt(PhysAddr::new(physical)).as_mut_ptr())
+    }
+
+    /// Points a table entry at `vector` on the local APIC `apic_id`, leaving its mask bit alone
+    /// # Safety
+    /// - The function's memory decoding must be on, and the entry should be masked while it is changed
+    pub unsafe fn set_entry(&self, index: u16, apic_id: u32, vector: u8) -> Result<(), MsiError> {
+        let address = message_address(apic_id)?;
+        let data = message_data(vector)?;
+        let entry = self.entry(index)?;
+        core::ptr::write_volatile(entry, address as u32);
+        core::ptr::write_volatile(entry.add(1), (address >> 32) as u32);
+        core::ptr::write_volatile(entry.add(2), data);
+        Ok(())
+    }
+    /// # Safety
+    /// - The function's memory decoding must be on, and an unmasked entry must have a handler for its vector
+    pub unsafe fn set_entry_masked(&self, index: u16, masked: bool) -> Result<(), MsiError> {
+        let control = self.entry(index)?.add(3);
+        let value = core::ptr::read_volatile(control);
+        let value = if masked { value | Self::ENTRY_MASKED } else { value & !Self::ENTRY_MASKED };
+        core::ptr::write_volatile(control, value);
+        Ok(())
+    }
+    /// # Safety
+    /// - Every unmasked entry must have a handler for its vector before MSI-X is enabled
+    pub unsafe fn set_enabled(&self, enabled: bool) {
+        let control = self.control();
+        let control = if enabled { control | Self::CONTROL_ENABLE } else { control & !Self::CONTROL_ENABLE };
+        self.0.write(Self::CONTROL, control);
+    }
+    /// Masks every vector of the function at once, without touching the entries
+    /// # Safety
+    /// - Interrupts raised while the function is masked are held as pending until it is unmasked
+    pub unsafe fn set_function_masked(&self, masked: bool) {
+        let control = self.control();
+        let control = if masked { control | Self::CONTROL_FUNCTION_MASK } else { control & !Self::CONTROL_FUNCTION_MASK };
+        self.0.write(Self::CONTROL, control);
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_msi_message() {
+    serial_print!("test_msi_message... ");
+    assert_eq!(message_address(0), Ok(0xFEE0_0000));
+    assert_eq!(message_address(3), Ok(0xFEE0_3000));
+    assert_eq!(message_address(0x100), Err(MsiError::DestinationTooHigh(0x100)));
+    assert_eq!(message_data(0xE0), Ok(0xE0));
+    assert_eq!(message_data(0x0E), Err(MsiError::InvalidVector(0x0E)));
+    serial_println!("[ok]");
+}
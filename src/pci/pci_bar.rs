@@ -1,19 +1,158 @@
-#[derive(Debug)]
+/// A decoded base address register, sizes are 0 when the BAR was decoded without probing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PCIBar {
     None,
-    Memory((u32, bool)),
-    Port(u16),
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// Whether the BAR takes up the next register as well, for the upper half of the address
+        is_64bit: bool,
+    },
+    Port {
+        address: u16,
+        size: u32,
+    },
 }
 
-impl From<u32> for PCIBar {
-    fn from(base_address_register: u32) -> Self {
-        if base_address_register & 0xFFFF_FFFC == 0 {
-            PCIBar::None
-        } else if base_address_register & 1 == 0 {
-			let prefetchable = base_address_register & 0b1000 == 0b1000;
-            PCIBar::Memory((base_address_register & 0xFFFF_FFF0, prefetchable))
+impl PCIBar {
+    /// Whether the raw register is the lower half of a 64-bit memory BAR
+    pub fn is_64bit_memory(register: u32) -> bool {
+        register & 1 == 0 && (register >> 1) & 0b11 == 0b10
+    }
+
+    /// Decodes the BAR at `index`, taking the upper half of a 64-bit BAR from the following register
+    pub fn decode(registers: &[u32], index: usize) -> PCIBar {
+        let register = registers[index];
+        if register & 1 == 1 {
+            let address = (register & 0xFFFC) as u16;
+            if address == 0 {
+                PCIBar::None
+            } else {
+                PCIBar::Port { address, size: 0 }
+            }
+        } else {
+            let is_64bit = Self::is_64bit_memory(register);
+            let high = if is_64bit {
+                registers.get(index + 1).copied().unwrap_or(0)
+            } else {
+                0
+            };
+            let address = ((high as u64) << 32) | (register & 0xFFFF_FFF0) as u64;
+            if address == 0 {
+                PCIBar::None
+            } else {
+                PCIBar::Memory {
+                    address,
+                    size: 0,
+                    prefetchable: register & 0b1000 == 0b1000,
+                    is_64bit,
+                }
+            }
+        }
+    }
+
+    /// Decodes a BAR from its original value and what reads back after writing all ones
+    ///
+    /// `original_high` and `sized_high` are the upper register of a 64-bit BAR, and ignored otherwise.
+    pub fn from_probe(original: u32, original_high: u32, sized: u32, sized_high: u32) -> PCIBar {
+        if sized == 0 && sized_high == 0 {
+            //no writable address bits, so the BAR isn't implemented
+            return PCIBar::None;
+        }
+        if original & 1 == 1 {
+            //the upper 16 bits of an I/O BAR may be hardwired to zero
+            let mask = (sized & 0xFFFF_FFFC) | 0xFFFF_0000;
+            PCIBar::Port {
+                address: (original & 0xFFFC) as u16,
+                size: (!mask).wrapping_add(1),
+            }
         } else {
-            PCIBar::Port((base_address_register & 0xFFFC) as u16)
+            let is_64bit = Self::is_64bit_memory(original);
+            let (original_high, sized_high) = if is_64bit {
+                (original_high, sized_high)
+            } else {
+                (0, 0xFFFF_FFFF)
+            };
+            let mask = ((sized_high as u64) << 32) | (sized & 0xFFFF_FFF0) as u64;
+            PCIBar::Memory {
+                address: ((original_high as u64) << 32) | (original & 0xFFFF_FFF0) as u64,
+                size: (!mask).wrapping_add(1),
+                prefetchable: original & 0b1000 == 0b1000,
+                is_64bit,
+            }
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        *self == PCIBar::None
+    }
+    pub fn address(&self) -> u64 {
+        match *self {
+            PCIBar::None => 0,
+            PCIBar::Memory { address, .. } => address,
+            PCIBar::Port { address, .. } => address as u64,
+        }
+    }
+    pub fn size(&self) -> u64 {
+        match *self {
+            PCIBar::None => 0,
+            PCIBar::Memory { size, .. } => size,
+            PCIBar::Port { size, .. } => size as u64,
         }
     }
+    /// How many registers the BAR occupies
+    pub fn register_count(&self) -> usize {
+        match self {
+            PCIBar::Memory { is_64bit: true, .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+/// Iterates over the BARs in a set of registers along with their indices, skipping upper halves and empty BARs
+pub struct PCIBarIter<'a> {
+    registers: &'a [u32],
+    index: usize,
+}
+impl<'a> PCIBarIter<'a> {
+    pub fn new(registers: &'a [u32]) -> Self {
+        Self { registers, index: 0 }
+    }
+}
+impl<'a> Iterator for PCIBarIter<'a> {
+    type Item = (usize, PCIBar);
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.registers.len() {
+            let index = self.index;
+            //the upper half of a 64-bit BAR is skipped even when the BAR is empty
+            self.index += if PCIBar::is_64bit_memory(self.registers[index]) { 2 } else { 1 };
+            let bar = PCIBar::decode(self.registers, index);
+            if !bar.is_none() {
+                return Some((index, bar));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_bar_decoding() {
+    serial_print!("test_bar_decoding... ");
+    let registers = [0xFEB0_000C, 0x0000_0001, 0x0000_C001, 0xFE00_0000, 0, 0];
+    let mut bars = PCIBarIter::new(&registers);
+    assert_eq!(bars.next(), Some((0, PCIBar::Memory { address: 0x1_FEB0_0000, size: 0, prefetchable: true, is_64bit: true })));
+    assert_eq!(bars.next(), Some((2, PCIBar::Port { address: 0xC000, size: 0 })));
+    assert_eq!(bars.next(), Some((3, PCIBar::Memory { address: 0xFE00_0000, size: 0, prefetchable: false, is_64bit: false })));
+    assert_eq!(bars.next(), None);
+
+    //a 64-bit 16KiB BAR, a 32-bit 4KiB BAR, a 32 port BAR with hardwired upper bits and an unimplemented one
+    assert_eq!(PCIBar::from_probe(0xFEB0_000C, 0x1, 0xFFFF_C00C, 0xFFFF_FFFF).size(), 0x4000);
+    assert_eq!(PCIBar::from_probe(0xFE00_0000, 0, 0xFFFF_F000, 0).size(), 0x1000);
+    assert_eq!(PCIBar::from_probe(0xC001, 0, 0xFFE1, 0).size(), 32);
+    assert!(PCIBar::from_probe(0, 0, 0, 0).is_none());
+    serial_println!("[ok]");
 }
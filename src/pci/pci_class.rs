@@ -1,5 +1,5 @@
-#[derive(Debug, Copy, Clone)]
-#[repr(u8)]
+/// The base class of a PCI function, from the PCI Code and ID Assignment specification
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum PCIClass {
 	Unclassified,
 	MassStorage,
@@ -21,8 +21,10 @@ pub enum PCIClass {
 	SignalProcessing,
 	ProcessingAccelerator,
 	NonEssential,
-	CoProcessor = 0x40,
-	Unassigned = 0xFF,
+	CoProcessor,
+	Unassigned,
+	/// A class code the specification doesn't assign
+	Unknown(u8),
 }
 impl From<u8> for PCIClass {
 	fn from(class: u8) -> Self {
@@ -48,10 +50,354 @@ impl From<u8> for PCIClass {
 			0x12 => PCIClass::ProcessingAccelerator,
 			0x13 => PCIClass::NonEssential,
 			0x40 => PCIClass::CoProcessor,
-			_ => panic!("Unknown PCI class: {}", class),
+			0xFF => PCIClass::Unassigned,
+			_ => PCIClass::Unknown(class),
+		}
+	}
+}
+impl From<PCIClass> for u8 {
+	fn from(class: PCIClass) -> Self {
+		match class {
+			PCIClass::Unclassified => 0x00,
+			PCIClass::MassStorage => 0x01,
+			PCIClass::Network => 0x02,
+			PCIClass::Display => 0x03,
+			PCIClass::Multimedia => 0x04,
+			PCIClass::Memory => 0x05,
+			PCIClass::BridgeDevice => 0x06,
+			PCIClass::Simple => 0x07,
+			PCIClass::BasePeripheral => 0x08,
+			PCIClass::InputDevice => 0x09,
+			PCIClass::Dock => 0x0A,
+			PCIClass::Processor => 0x0B,
+			PCIClass::SerialBus => 0x0C,
+			PCIClass::Wireless => 0x0D,
+			PCIClass::IntelligentController => 0x0E,
+			PCIClass::Satellite => 0x0F,
+			PCIClass::Encryption => 0x10,
+			PCIClass::SignalProcessing => 0x11,
+			PCIClass::ProcessingAccelerator => 0x12,
+			PCIClass::NonEssential => 0x13,
+			PCIClass::CoProcessor => 0x40,
+			PCIClass::Unassigned => 0xFF,
+			PCIClass::Unknown(class) => class,
 		}
 	}
 }
 impl Default for PCIClass {
 	fn default() -> Self {PCIClass::Unclassified}
-}
\ No newline at end of file
+}
+
+impl PCIClass {
+	/// The name the specification gives the class, None for unknown classes
+	pub fn name(&self) -> Option<&'static str> {
+		let name = match self {
+			PCIClass::Unclassified => "Unclassified device",
+			PCIClass::MassStorage => "Mass storage controller",
+			PCIClass::Network => "Network controller",
+			PCIClass::Display => "Display controller",
+			PCIClass::Multimedia => "Multimedia controller",
+			PCIClass::Memory => "Memory controller",
+			PCIClass::BridgeDevice => "Bridge",
+			PCIClass::Simple => "Communication controller",
+			PCIClass::BasePeripheral => "Generic system peripheral",
+			PCIClass::InputDevice => "Input device controller",
+			PCIClass::Dock => "Docking station",
+			PCIClass::Processor => "Processor",
+			PCIClass::SerialBus => "Serial bus controller",
+			PCIClass::Wireless => "Wireless controller",
+			PCIClass::IntelligentController => "Intelligent controller",
+			PCIClass::Satellite => "Satellite communications controller",
+			PCIClass::Encryption => "Encryption controller",
+			PCIClass::SignalProcessing => "Signal processing controller",
+			PCIClass::ProcessingAccelerator => "Processing accelerators",
+			PCIClass::NonEssential => "Non-Essential Instrumentation",
+			PCIClass::CoProcessor => "Coprocessor",
+			PCIClass::Unassigned => "Unassigned class",
+			PCIClass::Unknown(_) => return None,
+		};
+		Some(name)
+	}
+
+	/// The name of a subclass of this class
+	pub fn subclass_name(&self, subclass: u8) -> Option<&'static str> {
+		let name = match (u8::from(*self), subclass) {
+			(0x00, 0x00) => "Non-VGA unclassified device",
+			(0x00, 0x01) => "VGA compatible unclassified device",
+			(0x00, 0x05) => "Image coprocessor",
+
+			(0x01, 0x00) => "SCSI storage controller",
+			(0x01, 0x01) => "IDE interface",
+			(0x01, 0x02) => "Floppy disk controller",
+			(0x01, 0x03) => "IPI bus controller",
+			(0x01, 0x04) => "RAID bus controller",
+			(0x01, 0x05) => "ATA controller",
+			(0x01, 0x06) => "SATA controller",
+			(0x01, 0x07) => "Serial Attached SCSI controller",
+			(0x01, 0x08) => "Non-Volatile memory controller",
+			(0x01, 0x09) => "Universal Flash Storage controller",
+			(0x01, 0x80) => "Mass storage controller",
+
+			(0x02, 0x00) => "Ethernet controller",
+			(0x02, 0x01) => "Token ring network controller",
+			(0x02, 0x02) => "FDDI network controller",
+			(0x02, 0x03) => "ATM network controller",
+			(0x02, 0x04) => "ISDN controller",
+			(0x02, 0x05) => "WorldFip controller",
+			(0x02, 0x06) => "PICMG controller",
+			(0x02, 0x07) => "Infiniband controller",
+			(0x02, 0x08) => "Fabric controller",
+			(0x02, 0x80) => "Network controller",
+
+			(0x03, 0x00) => "VGA compatible controller",
+			(0x03, 0x01) => "XGA compatible controller",
+			(0x03, 0x02) => "3D controller",
+			(0x03, 0x80) => "Display controller",
+
+			(0x04, 0x00) => "Multimedia video controller",
+			(0x04, 0x01) => "Multimedia audio controller",
+			(0x04, 0x02) => "Computer telephony device",
+			(0x04, 0x03) => "Audio device",
+			(0x04, 0x80) => "Multimedia controller",
+
+			(0x05, 0x00) => "RAM memory",
+			(0x05, 0x01) => "FLASH memory",
+			(0x05, 0x02) => "CXL",
+			(0x05, 0x80) => "Memory controller",
+
+			(0x06, 0x00) => "Host bridge",
+			(0x06, 0x01) => "ISA bridge",
+			(0x06, 0x02) => "EISA bridge",
+			(0x06, 0x03) => "MicroChannel bridge",
+			(0x06, 0x04) => "PCI bridge",
+			(0x06, 0x05) => "PCMCIA bridge",
+			(0x06, 0x06) => "NuBus bridge",
+			(0x06, 0x07) => "CardBus bridge",
+			(0x06, 0x08) => "RACEway bridge",
+			(0x06, 0x09) => "Semi-transparent PCI-to-PCI bridge",
+			(0x06, 0x0A) => "InfiniBand to PCI host bridge",
+			(0x06, 0x80) => "Bridge",
+
+			(0x07, 0x00) => "Serial controller",
+			(0x07, 0x01) => "Parallel controller",
+			(0x07, 0x02) => "Multiport serial controller",
+			(0x07, 0x03) => "Modem",
+			(0x07, 0x04) => "GPIB controller",
+			(0x07, 0x05) => "Smart Card controller",
+			(0x07, 0x80) => "Communication controller",
+
+			(0x08, 0x00) => "PIC",
+			(0x08, 0x01) => "DMA controller",
+			(0x08, 0x02) => "Timer",
+			(0x08, 0x03) => "RTC",
+			(0x08, 0x04) => "PCI Hot-plug controller",
+			(0x08, 0x05) => "SD Host controller",
+			(0x08, 0x06) => "IOMMU",
+			(0x08, 0x07) => "Root Complex Event Collector",
+			(0x08, 0x80) => "System peripheral",
+			(0x08, 0x99) => "Timing Card",
+
+			(0x09, 0x00) => "Keyboard controller",
+			(0x09, 0x01) => "Digitizer Pen",
+			(0x09, 0x02) => "Mouse controller",
+			(0x09, 0x03) => "Scanner controller",
+			(0x09, 0x04) => "Gameport controller",
+			(0x09, 0x80) => "Input device controller",
+
+			(0x0A, 0x00) => "Generic Docking Station",
+			(0x0A, 0x80) => "Docking Station",
+
+			(0x0B, 0x00) => "386",
+			(0x0B, 0x01) => "486",
+			(0x0B, 0x02) => "Pentium",
+			(0x0B, 0x10) => "Alpha",
+			(0x0B, 0x20) => "Power PC",
+			(0x0B, 0x30) => "MIPS",
+			(0x0B, 0x40) => "Co-processor",
+
+			(0x0C, 0x00) => "FireWire (IEEE 1394)",
+			(0x0C, 0x01) => "ACCESS Bus",
+			(0x0C, 0x02) => "SSA",
+			(0x0C, 0x03) => "USB controller",
+			(0x0C, 0x04) => "Fibre Channel",
+			(0x0C, 0x05) => "SMBus",
+			(0x0C, 0x06) => "InfiniBand",
+			(0x0C, 0x07) => "IPMI Interface",
+			(0x0C, 0x08) => "SERCOS interface",
+			(0x0C, 0x09) => "CANBUS",
+			(0x0C, 0x0A) => "MIPI I3C",
+			(0x0C, 0x80) => "Serial bus controller",
+
+			(0x0D, 0x00) => "IRDA controller",
+			(0x0D, 0x01) => "Consumer IR controller",
+			(0x0D, 0x10) => "RF controller",
+			(0x0D, 0x11) => "Bluetooth",
+			(0x0D, 0x12) => "Broadband",
+			(0x0D, 0x20) => "802.1a controller",
+			(0x0D, 0x21) => "802.1b controller",
+			(0x0D, 0x80) => "Wireless controller",
+
+			(0x0E, 0x00) => "I2O",
+
+			(0x0F, 0x01) => "Satellite TV controller",
+			(0x0F, 0x02) => "Satellite audio communication controller",
+			(0x0F, 0x03) => "Satellite voice communication controller",
+			(0x0F, 0x04) => "Satellite data communication controller",
+
+			(0x10, 0x00) => "Network and computing encryption device",
+			(0x10, 0x10) => "Entertainment encryption device",
+			(0x10, 0x80) => "Encryption controller",
+
+			(0x11, 0x00) => "DPIO module",
+			(0x11, 0x01) => "Performance counters",
+			(0x11, 0x10) => "Communication synchronizer",
+			(0x11, 0x20) => "Signal processing management",
+			(0x11, 0x80) => "Signal processing controller",
+
+			(0x12, 0x00) => "Processing accelerators",
+			(0x12, 0x01) => "SNIA Smart Data Accelerator Interface (SDXI) controller",
+			_ => return None,
+		};
+		Some(name)
+	}
+
+	/// The name of a programming interface of one of this class's subclasses
+	pub fn interface_name(&self, subclass: u8, interface: u8) -> Option<&'static str> {
+		let name = match (u8::from(*self), subclass, interface) {
+			(0x01, 0x01, 0x00) => "ISA Compatibility mode-only controller",
+			(0x01, 0x01, 0x05) => "PCI native mode-only controller",
+			(0x01, 0x01, 0x0A) => "ISA Compatibility mode controller, supports both channels switched to PCI native mode",
+			(0x01, 0x01, 0x0F) => "PCI native mode controller, supports both channels switched to ISA compatibility mode",
+			(0x01, 0x01, 0x80) => "ISA Compatibility mode-only controller, supports bus mastering",
+			(0x01, 0x01, 0x85) => "PCI native mode-only controller, supports bus mastering",
+			(0x01, 0x01, 0x8A) => "ISA Compatibility mode controller, supports both channels switched to PCI native mode, supports bus mastering",
+			(0x01, 0x01, 0x8F) => "PCI native mode controller, supports both channels switched to ISA compatibility mode, supports bus mastering",
+			(0x01, 0x05, 0x20) => "ADMA single stepping",
+			(0x01, 0x05, 0x30) => "ADMA continuous operation",
+			(0x01, 0x06, 0x00) => "Vendor specific",
+			(0x01, 0x06, 0x01) => "AHCI 1.0",
+			(0x01, 0x06, 0x02) => "Serial Storage Bus",
+			(0x01, 0x07, 0x01) => "Serial Storage Bus",
+			(0x01, 0x08, 0x01) => "NVMHCI",
+			(0x01, 0x08, 0x02) => "NVM Express",
+			(0x01, 0x09, 0x00) => "Vendor specific",
+			(0x01, 0x09, 0x01) => "UFSHCI",
+
+			(0x03, 0x00, 0x00) => "VGA controller",
+			(0x03, 0x00, 0x01) => "8514 controller",
+
+			(0x05, 0x02, 0x00) => "CXL Memory Device - vendor specific",
+			(0x05, 0x02, 0x10) => "CXL Memory Device (CXL 2.x)",
+
+			(0x06, 0x04, 0x00) => "Normal decode",
+			(0x06, 0x04, 0x01) => "Subtractive decode",
+			(0x06, 0x08, 0x00) => "Transparent mode",
+			(0x06, 0x08, 0x01) => "Endpoint mode",
+			(0x06, 0x09, 0x40) => "Primary bus towards host CPU",
+			(0x06, 0x09, 0x80) => "Secondary bus towards host CPU",
+
+			(0x07, 0x00, 0x00) => "8250",
+			(0x07, 0x00, 0x01) => "16450",
+			(0x07, 0x00, 0x02) => "16550",
+			(0x07, 0x00, 0x03) => "16650",
+			(0x07, 0x00, 0x04) => "16750",
+			(0x07, 0x00, 0x05) => "16850",
+			(0x07, 0x00, 0x06) => "16950",
+			(0x07, 0x01, 0x00) => "SPP",
+			(0x07, 0x01, 0x01) => "BiDir",
+			(0x07, 0x01, 0x02) => "ECP",
+			(0x07, 0x01, 0x03) => "IEEE1284",
+			(0x07, 0x01, 0xFE) => "IEEE1284 Target",
+			(0x07, 0x03, 0x00) => "Generic",
+			(0x07, 0x03, 0x01) => "Hayes/16450",
+			(0x07, 0x03, 0x02) => "Hayes/16550",
+			(0x07, 0x03, 0x03) => "Hayes/16650",
+			(0x07, 0x03, 0x04) => "Hayes/16750",
+
+			(0x08, 0x00, 0x00) => "8259",
+			(0x08, 0x00, 0x01) => "ISA PIC",
+			(0x08, 0x00, 0x02) => "EISA PIC",
+			(0x08, 0x00, 0x10) => "IO-APIC",
+			(0x08, 0x00, 0x20) => "IO(X)-APIC",
+			(0x08, 0x01, 0x00) => "8237",
+			(0x08, 0x01, 0x01) => "ISA DMA",
+			(0x08, 0x01, 0x02) => "EISA DMA",
+			(0x08, 0x02, 0x00) => "8254",
+			(0x08, 0x02, 0x01) => "ISA Timer",
+			(0x08, 0x02, 0x02) => "EISA Timers",
+			(0x08, 0x02, 0x03) => "HPET",
+			(0x08, 0x03, 0x00) => "Generic",
+			(0x08, 0x03, 0x01) => "ISA RTC",
+
+			(0x09, 0x04, 0x00) => "Generic",
+			(0x09, 0x04, 0x10) => "Extended",
+
+			(0x0C, 0x00, 0x00) => "Generic",
+			(0x0C, 0x00, 0x10) => "OHCI",
+			(0x0C, 0x03, 0x00) => "UHCI",
+			(0x0C, 0x03, 0x10) => "OHCI",
+			(0x0C, 0x03, 0x20) => "EHCI",
+			(0x0C, 0x03, 0x30) => "XHCI",
+			(0x0C, 0x03, 0x40) => "USB4 Host Interface",
+			(0x0C, 0x03, 0x80) => "Unspecified",
+			(0x0C, 0x03, 0xFE) => "USB Device",
+			(0x0C, 0x07, 0x00) => "SMIC",
+			(0x0C, 0x07, 0x01) => "KCS",
+			(0x0C, 0x07, 0x02) => "BT (Block Transfer)",
+			_ => return None,
+		};
+		Some(name)
+	}
+}
+
+/// The class, subclass and programming interface of a function together
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PCIClassCode {
+	pub class: PCIClass,
+	pub subclass: u8,
+	pub interface: u8,
+}
+impl PCIClassCode {
+	pub fn new(class: u8, subclass: u8, interface: u8) -> Self {
+		PCIClassCode {class: PCIClass::from(class), subclass, interface}
+	}
+	pub fn subclass_name(&self) -> Option<&'static str> {
+		self.class.subclass_name(self.subclass)
+	}
+	pub fn interface_name(&self) -> Option<&'static str> {
+		self.class.interface_name(self.subclass, self.interface)
+	}
+}
+/// Names the subclass the way lspci does, falling back to the class and then to the raw codes
+impl core::fmt::Display for PCIClassCode {
+	fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+		match (self.subclass_name(), self.class.name()) {
+			(Some(subclass), _) => write!(f, "{}", subclass)?,
+			(None, Some(class)) => write!(f, "{} [{:02X}{:02X}]", class, u8::from(self.class), self.subclass)?,
+			(None, None) => write!(f, "Class [{:02X}{:02X}]", u8::from(self.class), self.subclass)?,
+		}
+		match self.interface_name() {
+			Some(interface) => write!(f, " ({})", interface),
+			None if self.interface != 0 => write!(f, " (prog-if {:02X})", self.interface),
+			None => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_pci_class_decoding() {
+	use alloc::string::ToString;
+	serial_print!("test_pci_class_decoding... ");
+	for class in 0..=255u8 {
+		assert_eq!(u8::from(PCIClass::from(class)), class);
+	}
+	assert_eq!(PCIClass::from(0x14), PCIClass::Unknown(0x14));
+	assert_eq!(PCIClassCode::new(0x01, 0x06, 0x01).to_string(), "SATA controller (AHCI 1.0)");
+	assert_eq!(PCIClassCode::new(0x0C, 0x03, 0x30).to_string(), "USB controller (XHCI)");
+	assert_eq!(PCIClassCode::new(0x02, 0x42, 0x00).to_string(), "Network controller [0242]");
+	assert_eq!(PCIClassCode::new(0x14, 0x00, 0x01).to_string(), "Class [1400] (prog-if 01)");
+	serial_println!("[ok]");
+}
@@ -0,0 +1,156 @@
+use super::{config::PCIAddress, HeaderType, PCIBridgeHeader, PCICardBusHeader, PCIHeader};
+use alloc::vec::Vec;
+
+/// A function found while walking the buses, with links to the bridge it sits behind and the functions behind it
+pub struct PCINode {
+    pub address: PCIAddress,
+    pub header: PCIHeader,
+    pub bridge: Option<PCIBridgeHeader>,
+    pub cardbus: Option<PCICardBusHeader>,
+    /// Index of the bridge this function is behind, None for functions on a root bus
+    pub parent: Option<usize>,
+    /// Indices of the functions on the bus behind this bridge
+    pub children: Vec<usize>,
+}
+impl PCINode {
+    /// The bus this function forwards to, if it is a bridge
+    pub fn secondary_bus(&self) -> Option<u8> {
+        match (&self.bridge, &self.cardbus) {
+            (Some(bridge), _) => Some(bridge.secondary_bus),
+            (None, Some(cardbus)) => Some(cardbus.cardbus_bus),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Every function reachable from the host bridges, found by following bridges instead of probing every bus
+#[derive(Default)]
+pub struct DeviceTree {
+    nodes: Vec<PCINode>,
+    roots: Vec<usize>,
+}
+
+impl DeviceTree {
+    /// Walks the buses behind the host bridges at 00:00.x
+    pub fn scan() -> Self {
+        let mut tree = DeviceTree::default();
+        let mut scanned = [false; 256];
+        let host = match PCIHeader::read(PCIAddress::new(0, 0, 0, 0)) {
+            Some(host) => host,
+            None => return tree,
+        };
+        if host.is_multifunction() {
+            //each function of a multifunction host bridge is the host controller for the bus of the same number
+            for function in 0..8 {
+                if PCIHeader::read(PCIAddress::new(0, 0, 0, function)).is_some() {
+                    tree.scan_bus(function, None, &mut scanned);
+                }
+            }
+        } else {
+            tree.scan_bus(0, None, &mut scanned);
+        }
+        tree
+    }
+
+    fn scan_bus(&mut self, bus: u8, parent: Option<usize>, scanned: &mut [bool; 256]) {
+        //misprogrammed bridges can make buses loop back on themselves
+        if scanned[bus as usize] {
+            return;
+        }
+        scanned[bus as usize] = true;
+        for device in 0..32 {
+            let first = match PCIHeader::read(PCIAddress::new(0, bus, device, 0)) {
+                Some(header) => header,
+                None => continue,
+            };
+            let functions = if first.is_multifunction() { 8 } else { 1 };
+            self.add(PCIAddress::new(0, bus, device, 0), first, parent, scanned);
+            for function in 1..functions {
+                let address = PCIAddress::new(0, bus, device, function);
+                if let Some(header) = PCIHeader::read(address) {
+                    self.add(address, header, parent, scanned);
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, address: PCIAddress, header: PCIHeader, parent: Option<usize>, scanned: &mut [bool; 256]) {
+        let (bridge, cardbus) = match header.layout() {
+            HeaderType::PCIBridge => (PCIBridgeHeader::read(address), None),
+            HeaderType::CardBusBridge => (None, PCICardBusHeader::read(address)),
+            _ => (None, None),
+        };
+        let index = self.nodes.len();
+        self.nodes.push(PCINode { address, header, bridge, cardbus, parent, children: Vec::new() });
+        match parent {
+            Some(parent) => self.nodes[parent].children.push(index),
+            None => self.roots.push(index),
+        }
+        //bridges that firmware left unconfigured have a secondary bus of 0 and nothing behind them yet
+        if let Some(secondary) = self.nodes[index].secondary_bus().filter(|bus| *bus > address.bus) {
+            self.scan_bus(secondary, Some(index), scanned);
+        }
+    }
+
+    pub fn nodes(&self) -> &[PCINode] {
+        &self.nodes
+    }
+    /// Indices of the functions on the root buses
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+    pub fn node(&self, index: usize) -> &PCINode {
+        &self.nodes[index]
+    }
+    pub fn parent(&self, index: usize) -> Option<&PCINode> {
+        self.nodes[index].parent.map(|parent| &self.nodes[parent])
+    }
+    pub fn children(&self, index: usize) -> impl Iterator<Item = &PCINode> + '_ {
+        self.nodes[index].children.iter().map(move |child| &self.nodes[*child])
+    }
+    pub fn find(&self, address: PCIAddress) -> Option<&PCINode> {
+        self.nodes.iter().find(|node| node.address == address)
+    }
+    /// The number of bridges between a function and its root bus
+    pub fn depth(&self, index: usize) -> usize {
+        let mut depth = 0;
+        let mut node = &self.nodes[index];
+        while let Some(parent) = node.parent {
+            depth += 1;
+            node = &self.nodes[parent];
+        }
+        depth
+    }
+    /// Every function in depth-first order, bridges before the functions behind them
+    pub fn iter(&self) -> DeviceTreeIter<'_> {
+        DeviceTreeIter { tree: self, stack: self.roots.iter().rev().copied().collect() }
+    }
+}
+
+/// Depth-first iterator over a DeviceTree, yielding each node's index along with it
+pub struct DeviceTreeIter<'a> {
+    tree: &'a DeviceTree,
+    stack: Vec<usize>,
+}
+impl<'a> Iterator for DeviceTreeIter<'a> {
+    type Item = (usize, &'a PCINode);
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = self.stack.pop()?;
+        let node = &self.tree.nodes[index];
+        self.stack.extend(node.children.iter().rev());
+        Some((index, node))
+    }
+}
+
+/// Lists the functions like `lspci`, indenting the ones behind bridges
+impl core::fmt::Display for DeviceTree {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for (index, node) in self.iter() {
+            for _ in 0..self.depth(index) {
+                write!(f, "  ")?;
+            }
+            writeln!(f, "{}", node.header.lspci(node.address))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+use crate::time::{self, Calendar, CalendarError, Cmos, RawRtcTime, RtcRegisters, SystemTime, RTC_STATUS_A, RTC_STATUS_B};
+use alloc::vec::Vec;
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::{interrupts, port::Port};
+
+// Alarm registers, a value of 0xC0 or above matches any time
+const RTC_SECONDS_ALARM: u8 = 0x01;
+const RTC_MINUTES_ALARM: u8 = 0x03;
+const RTC_HOURS_ALARM: u8 = 0x05;
+const RTC_STATUS_C: u8 = 0x0C;
+const ALARM_ANY: u8 = 0xC0;
+
+// Status register A: the low four bits select the periodic rate
+const STATUS_A_RATE_MASK: u8 = 0x0F;
+
+// Status register B interrupt enables
+const STATUS_B_PERIODIC: u8 = 0x40;
+const STATUS_B_ALARM: u8 = 0x20;
+const STATUS_B_UPDATE_ENDED: u8 = 0x10;
+
+// Status register C flags, reading the register clears them and acknowledges the interrupt
+const STATUS_C_PERIODIC: u8 = 0x40;
+const STATUS_C_ALARM: u8 = 0x20;
+const STATUS_C_UPDATE_ENDED: u8 = 0x10;
+
+// The RTC's time base, periodic rates are this divided by a power of two
+const BASE_FREQUENCY: u32 = 32768;
+// Rates 1 and 2 don't work on every chipset, 3 gives 8192 Hz and 15 gives 2 Hz
+const FASTEST_RATE: u8 = 3;
+const SLOWEST_RATE: u8 = 15;
+
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+// Periodic interrupts per second, 0 while disabled
+static PERIODIC_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+static PERIODIC: Event = Event::new(0);
+static ALARM: Event = Event::new(0);
+static UPDATE_ENDED: Event = Event::new(STATUS_B_UPDATE_ENDED);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+	/// The periodic interrupt only runs at powers of two from 2 to 8192 Hz
+	UnsupportedFrequency(u32),
+	/// A field of the alarm is out of range
+	InvalidAlarm,
+}
+
+/// A time of day for the alarm interrupt, fields left as None match any value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Alarm {
+	pub hour: Option<u8>,
+	pub minute: Option<u8>,
+	pub second: Option<u8>,
+}
+impl Alarm {
+	pub fn daily(hour: u8, minute: u8, second: u8) -> Self {
+		Alarm {hour: Some(hour), minute: Some(minute), second: Some(second)}
+	}
+	fn is_valid(&self) -> bool {
+		self.hour.map_or(true, |hour| hour < 24) && self.minute.map_or(true, |minute| minute < 60) && self.second.map_or(true, |second| second < 60)
+	}
+}
+
+/// Unmasks IRQ8 and discards any interrupt the firmware left pending
+///
+/// `century_register` is the CMOS index the FADT gives for the century, or 0 if there is none.
+pub fn init(century_register: u8) {
+	CENTURY_REGISTER.store(century_register, Ordering::Relaxed);
+	Cmos.read_register(RTC_STATUS_C);
+	interrupts::without_interrupts(|| {
+		let mut master_mask = Port::<u8>::new(0x21);
+		let mut slave_mask = Port::<u8>::new(0xA1);
+		unsafe {
+			//IRQ8 is the first input of the slave PIC, which cascades into IRQ2 of the master
+			let mask = master_mask.read();
+			master_mask.write(mask & !(1 << 2));
+			let mask = slave_mask.read();
+			slave_mask.write(mask & !1);
+		}
+	});
+}
+
+fn update_status_b(set: u8, clear: u8) {
+	//the lock inside Cmos only covers single accesses, so the read-modify-write needs interrupts off
+	interrupts::without_interrupts(|| {
+		let status_b = Cmos.read_register(RTC_STATUS_B);
+		Cmos.write_register(RTC_STATUS_B, (status_b | set) & !clear);
+	});
+}
+
+/// Raises IRQ8 `frequency` times a second, which must be a power of two from 2 to 8192
+pub fn enable_periodic(frequency: u32) -> Result<(), RtcError> {
+	if !frequency.is_power_of_two() || frequency < BASE_FREQUENCY >> (SLOWEST_RATE - 1) || frequency > BASE_FREQUENCY >> (FASTEST_RATE - 1) {
+		return Err(RtcError::UnsupportedFrequency(frequency));
+	}
+	//frequency = 32768 >> (rate - 1)
+	let rate = (BASE_FREQUENCY / frequency).trailing_zeros() as u8 + 1;
+	interrupts::without_interrupts(|| {
+		let status_a = Cmos.read_register(RTC_STATUS_A);
+		Cmos.write_register(RTC_STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+	});
+	PERIODIC_FREQUENCY.store(frequency, Ordering::Relaxed);
+	update_status_b(STATUS_B_PERIODIC, 0);
+	Ok(())
+}
+
+pub fn disable_periodic() {
+	update_status_b(0, STATUS_B_PERIODIC);
+	PERIODIC_FREQUENCY.store(0, Ordering::Relaxed);
+}
+
+/// Periodic interrupts per second, or 0 if the periodic interrupt is off
+pub fn periodic_frequency() -> u32 {
+	PERIODIC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Number of periodic interrupts taken so far
+pub fn ticks() -> u64 {
+	PERIODIC.count()
+}
+
+/// Resolves at the next periodic interrupt
+pub fn next_tick() -> Wait {
+	PERIODIC.wait()
+}
+
+/// Programs the alarm and enables its interrupt, the RTC's current hour and number format are used
+pub fn set_alarm(alarm: Alarm) -> Result<(), RtcError> {
+	if !alarm.is_valid() {
+		return Err(RtcError::InvalidAlarm);
+	}
+	interrupts::without_interrupts(|| {
+		let status_b = Cmos.read_register(RTC_STATUS_B);
+		let encode = |value: Option<u8>| value.map_or(ALARM_ANY, |value| time::encode_rtc_value(value, status_b));
+		Cmos.write_register(RTC_SECONDS_ALARM, encode(alarm.second));
+		Cmos.write_register(RTC_MINUTES_ALARM, encode(alarm.minute));
+		Cmos.write_register(RTC_HOURS_ALARM, alarm.hour.map_or(ALARM_ANY, |hour| time::encode_rtc_hour(hour, status_b)));
+	});
+	update_status_b(STATUS_B_ALARM, 0);
+	Ok(())
+}
+
+pub fn clear_alarm() {
+	update_status_b(0, STATUS_B_ALARM);
+}
+
+/// Resolves when the alarm next goes off
+pub fn alarm() -> Wait {
+	ALARM.wait()
+}
+
+/// Raises IRQ8 each time the RTC finishes its once-a-second update
+///
+/// The interrupt is turned off again after the next update, `update_ended` turns it on while tasks wait for it.
+pub fn enable_update_interrupt() {
+	update_status_b(STATUS_B_UPDATE_ENDED, 0);
+}
+
+pub fn disable_update_interrupt() {
+	update_status_b(0, STATUS_B_UPDATE_ENDED);
+}
+
+/// Resolves after the next once-a-second update, the update-ended interrupt is only on while tasks are waiting
+pub fn update_ended() -> Wait {
+	UPDATE_ENDED.wait()
+}
+
+/// Reads the RTC right after an update, when the registers are guaranteed stable for almost a second
+///
+/// Unlike `time::get_current_time` this never polls the update-in-progress flag.
+pub async fn read_time() -> Calendar {
+	update_ended().await;
+	RawRtcTime::read(&mut Cmos, CENTURY_REGISTER.load(Ordering::Relaxed)).decode()
+}
+
+/// Sets the hardware clock and moves the wall clock to match
+pub fn set_time(calendar: &Calendar) -> Result<(), CalendarError> {
+	time::set_current_time(CENTURY_REGISTER.load(Ordering::Relaxed), calendar)?;
+	time::set_system_time(SystemTime::from_calendar(calendar));
+	Ok(())
+}
+
+/// Corrects drift in the wall clock by setting it from the RTC on the edge of a second
+pub async fn sync_system_time() {
+	let calendar = read_time().await;
+	time::set_system_time(SystemTime::from_calendar(&calendar));
+}
+
+/// Called by the IRQ8 handler
+/// # Safety
+/// Must not block or allocate memory on the heap
+pub(crate) fn handle_interrupt() {
+	let flags = Cmos.read_register(RTC_STATUS_C);
+	if flags & STATUS_C_PERIODIC != 0 {
+		PERIODIC.signal();
+	}
+	if flags & STATUS_C_ALARM != 0 {
+		ALARM.signal();
+	}
+	if flags & STATUS_C_UPDATE_ENDED != 0 {
+		UPDATE_ENDED.signal();
+	}
+}
+
+// Counts occurrences of one kind of RTC interrupt and wakes every task waiting for the next one
+struct Event {
+	count: AtomicU64,
+	// Each waker with the count it is waiting to see change
+	// Locked with interrupts disabled, so the handler never spins on a lock its own CPU holds
+	wakers: spin::Mutex<Vec<(u64, Waker)>>,
+	// Status register B enable bit that is turned on while tasks wait and off once they are woken, 0 if callers manage it
+	on_demand: u8,
+}
+impl Event {
+	const fn new(on_demand: u8) -> Self {
+		Event {
+			count: AtomicU64::new(0),
+			wakers: spin::Mutex::new(Vec::new()),
+			on_demand,
+		}
+	}
+	fn count(&self) -> u64 {
+		self.count.load(Ordering::Acquire)
+	}
+	fn signal(&self) {
+		let wakers = self.wakers.lock();
+		let count = self.count.fetch_add(1, Ordering::AcqRel);
+		//the wakers are only woken by reference, dropping one could free its task in interrupt context
+		for (_, waker) in wakers.iter().filter(|(start, _)| *start == count) {
+			waker.wake_by_ref();
+		}
+		//every waiting task has just been woken, one that waits again turns the interrupt back on under this lock
+		if self.on_demand != 0 {
+			update_status_b(0, self.on_demand);
+		}
+	}
+	fn wait(&'static self) -> Wait {
+		Wait {event: self, start: self.count()}
+	}
+}
+
+/// A future that resolves at the next occurrence of an RTC interrupt
+pub struct Wait {
+	event: &'static Event,
+	start: u64,
+}
+impl Future for Wait {
+	type Output = ();
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+		let event = self.event;
+		if event.count() != self.start {
+			return Poll::Ready(());
+		}
+		interrupts::without_interrupts(|| {
+			let mut wakers = event.wakers.lock();
+			//wakers the handler has already woken are dropped here, in task context
+			let count = event.count();
+			wakers.retain(|(start, _)| *start == count);
+			//checked again under the lock, a signal after this point will see our waker
+			if count != self.start {
+				return Poll::Ready(());
+			}
+			if !wakers.iter().any(|(_, waker)| waker.will_wake(cx.waker())) {
+				wakers.push((count, cx.waker().clone()));
+			}
+			if event.on_demand != 0 {
+				update_status_b(event.on_demand, 0);
+			}
+			Poll::Pending
+		})
+	}
+}
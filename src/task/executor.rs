@@ -1,66 +1,225 @@
-use super::{Task, TaskId};
-use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc, task::Wake};
-use core::task::{Waker, Context, Poll};
-use crossbeam_queue::ArrayQueue;
+use super::{Task, join::{self, JoinHandle}, timer};
+use crate::{apic, interrupts::InterruptIndex};
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, task::Wake, vec::Vec};
+use core::{future::Future, pin::Pin, ptr, task::{Waker, Context, Poll}};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
 
+/// The most CPUs that can run an executor, CPUs are indexed in the order they call `register_cpu`
+pub const MAX_CPUS: usize = 16;
+// Local APIC IDs are 8 bits wide, and needn't be contiguous
+const MAX_APIC_IDS: usize = 256;
+
+// A task's future, shared between its Task, the queues and every waker that refers to it
+pub(super) struct TaskCell {
+	// None once the task has completed
+	future: spin::Mutex<Option<Pin<Box<dyn Future<Output = ()> + Send>>>>,
+	// Set while the task sits in a wake or run queue, so that repeated wakes are coalesced
+	scheduled: AtomicBool,
+	// The CPU that last polled the task, wakes are queued there
+	cpu: AtomicUsize,
+	// The next task in a WakeQueue, only valid while `scheduled` is set
+	next: AtomicPtr<TaskCell>,
+}
+impl TaskCell {
+	pub(super) fn new(future: Pin<Box<dyn Future<Output = ()> + Send>>) -> Arc<Self> {
+		Arc::new(TaskCell {
+			future: spin::Mutex::new(Some(future)),
+			scheduled: AtomicBool::new(false),
+			cpu: AtomicUsize::new(0),
+			next: AtomicPtr::new(ptr::null_mut()),
+		})
+	}
+}
+impl Wake for TaskCell {
+	fn wake(self: Arc<Self>){
+		schedule(self);
+	}
+
+	fn wake_by_ref(self: &Arc<Self>) {
+		schedule(self.clone());
+	}
+}
+
+// The queues each CPU's executor works from
+struct CpuQueues {
+	// Set once an executor has been created for this CPU
+	online: AtomicBool,
+	// Set while the executor is halted, a wake from another CPU must send an IPI
+	idle: AtomicBool,
+	// Woken and newly spawned tasks, safe to push to from interrupt handlers
+	wake_queue: WakeQueue,
+	// Tasks ready to be polled, other CPUs steal from the back
+	run_queue: spin::Mutex<VecDeque<Arc<TaskCell>>>,
+}
+impl CpuQueues {
+	fn new() -> Self {
+		CpuQueues {
+			online: AtomicBool::new(false),
+			idle: AtomicBool::new(false),
+			wake_queue: WakeQueue::new(),
+			run_queue: spin::Mutex::new(VecDeque::new()),
+		}
+	}
+}
+
+lazy_static! {
+	static ref CPUS: Vec<CpuQueues> = (0..MAX_CPUS).map(|_| CpuQueues::new()).collect();
+}
+
+// The CPU index of each local APIC ID plus one, zero until that CPU registers
+static CPU_INDICES: [AtomicUsize; MAX_APIC_IDS] = [AtomicUsize::new(0); MAX_APIC_IDS];
+// The local APIC ID of each registered CPU index, for sending IPIs
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [AtomicU32::new(0); MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Gives the calling CPU the next free CPU index, returning it
+///
+/// Every CPU must call this once its local APIC is initialized, before it creates an executor.
+/// Returns None if the CPU's APIC ID is out of range or MAX_CPUS CPUs are already registered.
+/// The boot CPU must call this before enabling interrupts, as the first call allocates every CPU's queues.
+pub fn register_cpu() -> Option<usize> {
+	//a wake from an interrupt handler must never be the first touch, lazy_static would allocate there
+	lazy_static::initialize(&CPUS);
+	let apic_id = apic::id() as usize;
+	let slot = CPU_INDICES.get(apic_id)?;
+	if let Some(cpu) = slot.load(Ordering::Acquire).checked_sub(1) {
+		return Some(cpu);
+	}
+	let cpu = CPU_COUNT.fetch_add(1, Ordering::Relaxed);
+	if cpu >= MAX_CPUS {
+		CPU_COUNT.fetch_sub(1, Ordering::Relaxed);
+		return None;
+	}
+	APIC_IDS[cpu].store(apic_id as u32, Ordering::Relaxed);
+	slot.store(cpu + 1, Ordering::Release);
+	Some(cpu)
+}
+
+/// Returns the index of the calling CPU, None if it hasn't been registered
+pub fn current_cpu() -> Option<usize> {
+	CPU_INDICES.get(apic::id() as usize)?.load(Ordering::Acquire).checked_sub(1)
+}
+
+fn send_wakeup(cpu: usize) {
+	apic::send_ipi(APIC_IDS[cpu].load(Ordering::Relaxed), InterruptIndex::Wakeup.as_u8());
+}
+
+// Queues the task on the CPU that last ran it, waking that CPU if it is halted
+fn schedule(cell: Arc<TaskCell>) {
+	if cell.scheduled.swap(true, Ordering::AcqRel) {
+		return;
+	}
+	//a cell only ever holds indices checked by Executor::for_cpu, but a bad one must not panic in an interrupt handler
+	let cpu = cell.cpu.load(Ordering::Relaxed);
+	let cpu = if cpu < CPUS.len() { cpu } else { 0 };
+	let queues = &CPUS[cpu];
+	queues.wake_queue.push(cell);
+	if Some(cpu) != current_cpu() && queues.idle.load(Ordering::SeqCst) {
+		send_wakeup(cpu);
+	}
+}
+
+/// Runs tasks on one CPU, stealing from the other CPUs' run queues when it runs out of work
 pub struct Executor {
-	// The tasks which are ready to execute
-	task_queue: VecDeque<Task>,
-	// Stores tasks that are still waiting on completion (returned Poll::Pending)
-	waiting_tasks: BTreeMap<TaskId, Task>,
-	// A queue of TaskIds that are ready to be processed, wakers will push the id to this queue
-	wake_queue: Arc<ArrayQueue<TaskId>>,
-	// A place to keep wakers for tasks so we can reuse them
-	waker_cache: BTreeMap<TaskId, Waker>,
+	cpu: usize,
 }
 
 impl Executor {
+	/// Creates the executor for the calling CPU, which must have called `register_cpu`
 	pub fn new() -> Self {
-		Executor {
-			task_queue: VecDeque::new(),
-			waiting_tasks: BTreeMap::new(),
-			wake_queue: Arc::new(ArrayQueue::new(100)),
-			waker_cache: BTreeMap::new(),
+		Self::for_cpu(current_cpu().expect("the calling CPU hasn't been registered"))
+	}
+	/// Creates the executor for `cpu`, which must be the CPU that will call `run`
+	pub fn for_cpu(cpu: usize) -> Self {
+		assert!(cpu < MAX_CPUS, "CPU index {} exceeds MAX_CPUS", cpu);
+		if CPUS[cpu].online.swap(true, Ordering::AcqRel) {
+			panic!("an executor already exists for CPU {}", cpu);
 		}
+		Executor {cpu}
 	}
 	pub fn spawn(&mut self, task: Task){
-		self.task_queue.push_back(task)
+		self.spawner().spawn_task(task);
 	}
-	#[allow(clippy::map_entry)]
-	fn run_ready_tasks(&mut self){
-		while let Some(mut task) = self.task_queue.pop_front() {
-			let task_id = task.id;
-			if !self.waker_cache.contains_key(&task_id) {
-				self.waker_cache.insert(task_id, self.create_waker(task_id));
+	/// Returns a handle that can spawn tasks onto this executor while it is running
+	pub fn spawner(&self) -> Spawner {
+		Spawner {cpu: self.cpu}
+	}
+	fn queues(&self) -> &'static CpuQueues {
+		&CPUS[self.cpu]
+	}
+	fn next_task(&self) -> Option<Arc<TaskCell>> {
+		let task = self.queues().run_queue.lock().pop_front();
+		task.or_else(|| self.steal())
+	}
+	// Takes half of the first non-empty run queue found on another CPU
+	fn steal(&self) -> Option<Arc<TaskCell>> {
+		for offset in 1..MAX_CPUS {
+			let victim = &CPUS[(self.cpu + offset) % MAX_CPUS];
+			if !victim.online.load(Ordering::Relaxed) {
+				continue;
 			}
-			let waker = self.waker_cache.get(&task_id).expect("should exist");
-			let mut context = Context::from_waker(waker);
-			match task.poll(&mut context) {
-				Poll::Ready(()) => {
-					//task is already done
-					self.waker_cache.remove(&task_id);
-				}
-				Poll::Pending => {
-					if self.waiting_tasks.insert(task_id, task).is_some() {
-						panic!("task with same ID already in waiting_tasks");
-					}
+			let mut stolen = match victim.run_queue.try_lock() {
+				Some(mut run_queue) => {
+					let count = (run_queue.len() + 1) / 2;
+					let at = run_queue.len() - count;
+					run_queue.split_off(at)
 				}
+				None => continue,
+			};
+			if let Some(task) = stolen.pop_front() {
+				self.queues().run_queue.lock().extend(stolen);
+				return Some(task);
 			}
 		}
+		None
 	}
-	fn create_waker(&self, task_id: TaskId) -> Waker {
-		Waker::from(Arc::new(TaskWaker {
-			task_id,
-			wake_queue: self.wake_queue.clone(),
-		}))
+	fn run_ready_tasks(&mut self){
+		while let Some(cell) = self.next_task() {
+			cell.cpu.store(self.cpu, Ordering::Relaxed);
+			// clear the flag before polling, so a wake during the poll queues the task again
+			cell.scheduled.store(false, Ordering::Release);
+			let mut slot = cell.future.lock();
+			if let Some(future) = slot.as_mut() {
+				let waker = Waker::from(cell.clone());
+				let mut context = Context::from_waker(&waker);
+				if let Poll::Ready(()) = future.as_mut().poll(&mut context) {
+					//task is done, drop it even if wakers still refer to it
+					*slot = None;
+				}
+			}
+		}
 	}
 	fn wake_tasks(&mut self){
-		while let Ok(task_id) = self.wake_queue.pop() {
-			if let Some(task) = self.waiting_tasks.remove(&task_id) {
-				self.task_queue.push_back(task);
+		timer::wake_expired();
+		let woken = self.queues().wake_queue.take_all();
+		if woken.is_empty() {
+			return;
+		}
+		let backlog = {
+			let mut run_queue = self.queues().run_queue.lock();
+			run_queue.extend(woken);
+			run_queue.len()
+		};
+		if backlog > 1 {
+			self.wake_idle_cpu();
+		}
+	}
+	// Sends an IPI to one halted CPU so that it comes and steals some of our work
+	fn wake_idle_cpu(&self) {
+		for (cpu, queues) in CPUS.iter().enumerate() {
+			if cpu != self.cpu && queues.online.load(Ordering::Relaxed) && queues.idle.load(Ordering::SeqCst) {
+				send_wakeup(cpu);
+				return;
 			}
 		}
 	}
+	// Whether another CPU has tasks queued that we could steal
+	fn work_available(&self) -> bool {
+		CPUS.iter().enumerate().any(|(cpu, queues)| {
+			cpu != self.cpu && queues.online.load(Ordering::Relaxed) && queues.run_queue.try_lock().map_or(false, |run_queue| run_queue.len() > 1)
+		})
+	}
 	pub fn run(&mut self) -> ! {
 		loop {
 			self.wake_tasks();
@@ -71,38 +230,97 @@ impl Executor {
 	fn sleep_if_idle(&self) {
 		use x86_64::instructions::interrupts::{self, enable_interrupts_and_hlt};
 
-		if !self.wake_queue.is_empty() {
+		let queues = self.queues();
+		if !queues.wake_queue.is_empty() {
 			return;
 		}
 
 		interrupts::disable();
-		if self.wake_queue.is_empty() {
+		//once idle is visible, other CPUs send an IPI instead of relying on us to look at the queue
+		queues.idle.store(true, Ordering::SeqCst);
+		//in tickless mode nothing else would wake us for the next timer
+		if queues.wake_queue.is_empty() && !self.work_available() && timer::arm_idle_wakeup() {
 			enable_interrupts_and_hlt();
 		}
 		else {
 			interrupts::enable();
 		}
+		queues.idle.store(false, Ordering::SeqCst);
 	}
 }
 impl Default for Executor {
 	fn default() -> Self {Self::new()}
 }
 
-struct TaskWaker {
-	task_id: TaskId,
-	wake_queue: Arc<ArrayQueue<TaskId>>,
+/// A handle for spawning tasks onto an `Executor`, including from inside running tasks
+///
+/// `spawn_task` only links an existing `Task` into a lock-free queue, so it is safe in interrupt handlers.
+/// `spawn` allocates the task on the heap, and must not be called where the interrupted code could hold the heap lock.
+#[derive(Clone, Copy)]
+pub struct Spawner {
+	cpu: usize,
 }
-impl TaskWaker {
-	fn wake_task(&self) {
-		self.wake_queue.push(self.task_id).expect("wake_queue full");
+impl Spawner {
+	/// Spawns `future` and returns a handle that resolves to its output
+	pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+	where
+		F: Future + Send + 'static,
+		F::Output: Send + 'static,
+	{
+		let (joinable, handle) = join::joinable(future);
+		self.spawn_task(Task::new(joinable));
+		handle
 	}
-}
-impl Wake for TaskWaker {
-	fn wake(self: Arc<Self>){
-		self.wake_task();
+	/// Spawns an already constructed task without a way to join it, without allocating
+	pub fn spawn_task(&self, task: Task) {
+		task.cell.cpu.store(self.cpu, Ordering::Relaxed);
+		schedule(task.cell);
 	}
+}
 
-	fn wake_by_ref(self: &Arc<Self>) {
-		self.wake_task();
+/// An intrusive, lock-free stack of woken tasks
+///
+/// Each task links itself in at most once, so the queue can never fill up and pushing never allocates.
+/// Any CPU or interrupt handler may push; only the owning executor takes from it, and always takes everything at once.
+struct WakeQueue {
+	head: AtomicPtr<TaskCell>,
+}
+impl WakeQueue {
+	fn new() -> Self {
+		WakeQueue {
+			head: AtomicPtr::new(ptr::null_mut()),
+		}
 	}
-}
\ No newline at end of file
+	fn push(&self, cell: Arc<TaskCell>) {
+		// the queue owns a strong reference until take_all hands it back
+		let node = Arc::into_raw(cell) as *mut TaskCell;
+		let mut head = self.head.load(Ordering::Relaxed);
+		loop {
+			unsafe {(*node).next.store(head, Ordering::Relaxed)};
+			match self.head.compare_exchange_weak(head, node, Ordering::SeqCst, Ordering::Relaxed) {
+				Ok(_) => return,
+				Err(current) => head = current,
+			}
+		}
+	}
+	/// Unlinks every queued task, returning them in the order they were woken
+	fn take_all(&self) -> Vec<Arc<TaskCell>> {
+		let mut node = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+		let mut cells = Vec::new();
+		while !node.is_null() {
+			let cell = unsafe {Arc::from_raw(node)};
+			node = cell.next.load(Ordering::Relaxed);
+			cells.push(cell);
+		}
+		cells.reverse();
+		cells
+	}
+	fn is_empty(&self) -> bool {
+		self.head.load(Ordering::SeqCst).is_null()
+	}
+}
+impl Drop for WakeQueue {
+	fn drop(&mut self) {
+		self.take_all();
+	}
+}
@@ -0,0 +1,133 @@
+use alloc::{boxed::Box, sync::Arc};
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+use spin::Mutex;
+
+/// The reason a `JoinHandle` could not yield its task's output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+	/// The task was aborted before it completed
+	Cancelled,
+}
+
+// State shared between a spawned task and its JoinHandle
+struct JoinState<T> {
+	// The task's output, taken by the JoinHandle once it is polled
+	output: Option<T>,
+	finished: bool,
+	cancelled: bool,
+	// Wakes whoever is awaiting the JoinHandle
+	join_waker: Option<Waker>,
+	// Wakes the task itself so that it can observe a cancellation
+	task_waker: Option<Waker>,
+}
+
+/// Wraps a future so that its output is handed to a `JoinHandle`
+pub(super) struct Joinable<F: Future> {
+	future: Pin<Box<F>>,
+	state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+/// Creates the task side and the handle side of a joinable future
+pub(super) fn joinable<F: Future>(future: F) -> (Joinable<F>, JoinHandle<F::Output>) {
+	let state = Arc::new(Mutex::new(JoinState {
+		output: None,
+		finished: false,
+		cancelled: false,
+		join_waker: None,
+		task_waker: None,
+	}));
+	let task = Joinable {
+		future: Box::pin(future),
+		state: state.clone(),
+	};
+	let handle = JoinHandle {
+		state,
+		detached: false,
+	};
+	(task, handle)
+}
+
+impl<F: Future> Future for Joinable<F> {
+	type Output = ();
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+		{
+			let mut state = self.state.lock();
+			if state.cancelled {
+				//dropping the task drops the wrapped future with it
+				return Poll::Ready(());
+			}
+			state.task_waker = Some(cx.waker().clone());
+		}
+		match self.future.as_mut().poll(cx) {
+			Poll::Ready(output) => {
+				let join_waker = {
+					let mut state = self.state.lock();
+					state.output = Some(output);
+					state.finished = true;
+					state.task_waker = None;
+					state.join_waker.take()
+				};
+				if let Some(waker) = join_waker {
+					waker.wake();
+				}
+				Poll::Ready(())
+			}
+			Poll::Pending => Poll::Pending,
+		}
+	}
+}
+
+/// A future that resolves to the output of a spawned task
+///
+/// Dropping the handle aborts the task, use `detach` to let it run to completion unobserved.
+pub struct JoinHandle<T> {
+	state: Arc<Mutex<JoinState<T>>>,
+	detached: bool,
+}
+impl<T> JoinHandle<T> {
+	/// Cancels the task; it is dropped the next time the executor polls it
+	pub fn abort(&self) {
+		let task_waker = {
+			let mut state = self.state.lock();
+			if state.finished || state.cancelled {
+				return;
+			}
+			state.cancelled = true;
+			state.task_waker.take()
+		};
+		if let Some(waker) = task_waker {
+			waker.wake();
+		}
+	}
+	/// Lets the task keep running after the handle is dropped
+	pub fn detach(mut self) {
+		self.detached = true;
+	}
+	pub fn is_finished(&self) -> bool {
+		self.state.lock().finished
+	}
+}
+impl<T> Future for JoinHandle<T> {
+	type Output = Result<T, JoinError>;
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		let mut state = self.state.lock();
+		if let Some(output) = state.output.take() {
+			return Poll::Ready(Ok(output));
+		}
+		if state.finished {
+			panic!("JoinHandle polled after its output was taken");
+		}
+		if state.cancelled {
+			return Poll::Ready(Err(JoinError::Cancelled));
+		}
+		state.join_waker = Some(cx.waker().clone());
+		Poll::Pending
+	}
+}
+impl<T> Drop for JoinHandle<T> {
+	fn drop(&mut self) {
+		if !self.detached {
+			self.abort();
+		}
+	}
+}
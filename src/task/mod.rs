@@ -1,32 +1,23 @@
-use core::{future::Future, pin::Pin, task::{Context, Poll}};
-use core::sync::atomic::{AtomicU64, Ordering};
-use alloc::boxed::Box;
+use core::future::Future;
+use alloc::{boxed::Box, sync::Arc};
 
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod sync;
+pub mod timer;
 
+/// A top-level future run by the executor
+///
+/// Tasks may be stolen by another CPU between polls, so the future must be `Send`.
+/// Everything the executor needs is allocated here, so spawning a `Task` that already exists never touches the heap.
 pub struct Task {
-	id: TaskId,
-	future: Pin<Box<dyn Future<Output = ()>>>,
+	cell: Arc<executor::TaskCell>,
 }
 impl Task {
-	pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+	pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
 		Task {
-			id: TaskId::new(),
-			future: Box::pin(future),
+			cell: executor::TaskCell::new(Box::pin(future)),
 		}
 	}
-
-	fn poll(&mut self, context: &mut Context) -> Poll<()> {
-		self.future.as_mut().poll(context)
-	}
 }
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
-impl TaskId {
-	fn new() -> Self {
-		static NEXT_ID: AtomicU64 = AtomicU64::new(0);
-		TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
-	}
-}
\ No newline at end of file
@@ -0,0 +1,202 @@
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::task::{Context, Poll, Waker};
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures_util::future::poll_fn;
+
+/// Returned by `Sender::send` when there are no receivers, handing back the value
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+	/// Every sender is gone and the receiver has seen every value
+	Closed,
+	/// The receiver fell behind and this many values were overwritten before it saw them
+	Lagged(u64),
+}
+
+struct State<T> {
+	// The most recent values, the front one has sequence number `next_seq - buffer.len()`
+	buffer: VecDeque<T>,
+	capacity: usize,
+	next_seq: u64,
+	senders: usize,
+	receivers: usize,
+	// Keyed by receiver id, so polling repeatedly doesn't grow the list
+	wakers: Vec<(u64, Waker)>,
+}
+impl<T> State<T> {
+	fn oldest_seq(&self) -> u64 {
+		self.next_seq - self.buffer.len() as u64
+	}
+}
+
+/// Creates a channel where every receiver sees every value sent after it subscribed
+///
+/// Only the last `capacity` values are kept; receivers that fall further behind get `RecvError::Lagged`.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+	assert!(capacity > 0, "broadcast channel capacity must be non-zero");
+	let shared = Arc::new(spin::Mutex::new(State {
+		buffer: VecDeque::with_capacity(capacity),
+		capacity,
+		next_seq: 0,
+		senders: 1,
+		receivers: 1,
+		wakers: Vec::new(),
+	}));
+	let receiver = Receiver {
+		shared: shared.clone(),
+		id: next_receiver_id(),
+		next: 0,
+	};
+	(Sender {shared}, receiver)
+}
+
+fn next_receiver_id() -> u64 {
+	static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+	NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub struct Sender<T> {
+	shared: Arc<spin::Mutex<State<T>>>,
+}
+impl<T: Clone> Sender<T> {
+	/// Sends the value to every receiver, returning how many there are
+	pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+		let (receivers, wakers) = {
+			let mut state = self.shared.lock();
+			if state.receivers == 0 {
+				return Err(SendError(value));
+			}
+			if state.buffer.len() == state.capacity {
+				state.buffer.pop_front();
+			}
+			state.buffer.push_back(value);
+			state.next_seq += 1;
+			(state.receivers, core::mem::take(&mut state.wakers))
+		};
+		for (_, waker) in wakers {
+			waker.wake();
+		}
+		Ok(receivers)
+	}
+	/// Creates a receiver that sees values sent from now on
+	pub fn subscribe(&self) -> Receiver<T> {
+		let mut state = self.shared.lock();
+		state.receivers += 1;
+		Receiver {
+			shared: self.shared.clone(),
+			id: next_receiver_id(),
+			next: state.next_seq,
+		}
+	}
+	pub fn receiver_count(&self) -> usize {
+		self.shared.lock().receivers
+	}
+}
+impl<T> Clone for Sender<T> {
+	fn clone(&self) -> Self {
+		self.shared.lock().senders += 1;
+		Sender {shared: self.shared.clone()}
+	}
+}
+impl<T> Drop for Sender<T> {
+	fn drop(&mut self) {
+		let wakers = {
+			let mut state = self.shared.lock();
+			state.senders -= 1;
+			if state.senders == 0 {
+				core::mem::take(&mut state.wakers)
+			}
+			else {
+				Vec::new()
+			}
+		};
+		for (_, waker) in wakers {
+			waker.wake();
+		}
+	}
+}
+
+pub struct Receiver<T> {
+	shared: Arc<spin::Mutex<State<T>>>,
+	id: u64,
+	// Sequence number of the next value this receiver will see
+	next: u64,
+}
+impl<T: Clone> Receiver<T> {
+	/// Waits for the next value
+	pub async fn recv(&mut self) -> Result<T, RecvError> {
+		poll_fn(|cx| self.poll_recv(cx)).await
+	}
+	pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Result<T, RecvError>> {
+		let mut state = self.shared.lock();
+		let oldest = state.oldest_seq();
+		if self.next < oldest {
+			let missed = oldest - self.next;
+			self.next = oldest;
+			return Poll::Ready(Err(RecvError::Lagged(missed)));
+		}
+		if self.next < state.next_seq {
+			let value = state.buffer[(self.next - oldest) as usize].clone();
+			self.next += 1;
+			return Poll::Ready(Ok(value));
+		}
+		if state.senders == 0 {
+			return Poll::Ready(Err(RecvError::Closed));
+		}
+		let id = self.id;
+		match state.wakers.iter_mut().find(|(waker_id, _)| *waker_id == id) {
+			Some((_, waker)) => *waker = cx.waker().clone(),
+			None => state.wakers.push((id, cx.waker().clone())),
+		}
+		Poll::Pending
+	}
+}
+impl<T> Clone for Receiver<T> {
+	/// The clone starts at the same position as this receiver
+	fn clone(&self) -> Self {
+		self.shared.lock().receivers += 1;
+		Receiver {
+			shared: self.shared.clone(),
+			id: next_receiver_id(),
+			next: self.next,
+		}
+	}
+}
+impl<T> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		let mut state = self.shared.lock();
+		state.receivers -= 1;
+		let id = self.id;
+		state.wakers.retain(|(waker_id, _)| *waker_id != id);
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_broadcast_lagging() {
+	use futures_util::task::noop_waker_ref;
+
+	serial_print!("test_broadcast_lagging... ");
+	let mut cx = Context::from_waker(noop_waker_ref());
+	let (sender, mut fast) = channel(2);
+	let mut slow = sender.subscribe();
+	assert_eq!(sender.send(1), Ok(2));
+	assert_eq!(fast.poll_recv(&mut cx), Poll::Ready(Ok(1)));
+	assert_eq!(sender.send(2), Ok(2));
+	assert_eq!(sender.send(3), Ok(2));
+	//the slow receiver missed the value that was pushed out, then carries on from the oldest one kept
+	assert_eq!(slow.poll_recv(&mut cx), Poll::Ready(Err(RecvError::Lagged(1))));
+	assert_eq!(slow.poll_recv(&mut cx), Poll::Ready(Ok(2)));
+	assert_eq!(slow.poll_recv(&mut cx), Poll::Ready(Ok(3)));
+	assert!(slow.poll_recv(&mut cx).is_pending());
+	//a lagging receiver doesn't hold the others back
+	assert_eq!(fast.poll_recv(&mut cx), Poll::Ready(Ok(2)));
+	assert_eq!(fast.poll_recv(&mut cx), Poll::Ready(Ok(3)));
+	drop(sender);
+	assert_eq!(fast.poll_recv(&mut cx), Poll::Ready(Err(RecvError::Closed)));
+	serial_println!("[ok]");
+}
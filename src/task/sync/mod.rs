@@ -0,0 +1,13 @@
+// Waker-based synchronization primitives for tasks running on the executor
+// Waiting on any of these yields to the executor instead of spinning, so they must only be used from async code
+
+pub mod broadcast;
+pub mod mpsc;
+mod mutex;
+pub mod oneshot;
+mod rwlock;
+mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, AcquireError, Semaphore, SemaphorePermit, TryAcquireError};
@@ -0,0 +1,201 @@
+use super::semaphore::{Semaphore, TryAcquireError};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{pin::Pin, task::{Context, Poll, Waker}};
+use futures_util::{future::poll_fn, stream::Stream};
+
+/// Returned by `Sender::send` when the receiver is gone, handing back the value
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+	Full(T),
+	Closed(T),
+}
+
+struct State<T> {
+	queue: VecDeque<T>,
+	senders: usize,
+	receiver_alive: bool,
+	receiver_waker: Option<Waker>,
+}
+
+struct Shared<T> {
+	// One permit per free slot in the queue, closed once the receiver is dropped
+	capacity: Semaphore,
+	state: spin::Mutex<State<T>>,
+}
+
+/// Creates a bounded multi-producer, single-consumer channel
+///
+/// Senders wait for a free slot once `capacity` values are queued.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+	assert!(capacity > 0, "mpsc channel capacity must be non-zero");
+	let shared = Arc::new(Shared {
+		capacity: Semaphore::new(capacity),
+		state: spin::Mutex::new(State {
+			queue: VecDeque::with_capacity(capacity),
+			senders: 1,
+			receiver_alive: true,
+			receiver_waker: None,
+		}),
+	});
+	(Sender {shared: shared.clone()}, Receiver {shared})
+}
+
+pub struct Sender<T> {
+	shared: Arc<Shared<T>>,
+}
+impl<T> Sender<T> {
+	/// Waits for a free slot and queues the value
+	pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+		match self.shared.capacity.acquire().await {
+			Ok(permit) => permit.forget(),
+			Err(_) => return Err(SendError(value)),
+		}
+		self.push(value).map_err(SendError)
+	}
+	/// Queues the value only if there is a free slot right now
+	pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+		match self.shared.capacity.try_acquire() {
+			Ok(permit) => permit.forget(),
+			Err(TryAcquireError::NoPermits) => return Err(TrySendError::Full(value)),
+			Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(value)),
+		}
+		self.push(value).map_err(TrySendError::Closed)
+	}
+	pub fn is_closed(&self) -> bool {
+		!self.shared.state.lock().receiver_alive
+	}
+	// Pushes a value for which a slot has already been acquired
+	fn push(&self, value: T) -> Result<(), T> {
+		let waker = {
+			let mut state = self.shared.state.lock();
+			if !state.receiver_alive {
+				return Err(value);
+			}
+			state.queue.push_back(value);
+			state.receiver_waker.take()
+		};
+		if let Some(waker) = waker {
+			waker.wake();
+		}
+		Ok(())
+	}
+}
+impl<T> Clone for Sender<T> {
+	fn clone(&self) -> Self {
+		self.shared.state.lock().senders += 1;
+		Sender {shared: self.shared.clone()}
+	}
+}
+impl<T> Drop for Sender<T> {
+	fn drop(&mut self) {
+		let waker = {
+			let mut state = self.shared.state.lock();
+			state.senders -= 1;
+			if state.senders == 0 {
+				state.receiver_waker.take()
+			}
+			else {
+				None
+			}
+		};
+		if let Some(waker) = waker {
+			waker.wake();
+		}
+	}
+}
+
+pub struct Receiver<T> {
+	shared: Arc<Shared<T>>,
+}
+impl<T> Receiver<T> {
+	/// Waits for the next value, returning None once every sender is gone and the queue is empty
+	pub async fn recv(&mut self) -> Option<T> {
+		poll_fn(|cx| self.poll_recv(cx)).await
+	}
+	pub fn try_recv(&mut self) -> Option<T> {
+		let value = self.shared.state.lock().queue.pop_front();
+		if value.is_some() {
+			self.shared.capacity.add_permits(1);
+		}
+		value
+	}
+	pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+		let mut state = self.shared.state.lock();
+		if let Some(value) = state.queue.pop_front() {
+			drop(state);
+			self.shared.capacity.add_permits(1);
+			Poll::Ready(Some(value))
+		}
+		else if state.senders == 0 {
+			Poll::Ready(None)
+		}
+		else {
+			state.receiver_waker = Some(cx.waker().clone());
+			Poll::Pending
+		}
+	}
+}
+impl<T> Stream for Receiver<T> {
+	type Item = T;
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>> {
+		self.poll_recv(cx)
+	}
+}
+impl<T> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		let queued = {
+			let mut state = self.shared.state.lock();
+			state.receiver_alive = false;
+			core::mem::take(&mut state.queue)
+		};
+		//wake senders waiting for a slot so they can see the channel is closed
+		self.shared.capacity.close();
+		drop(queued);
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_mpsc_backpressure() {
+	use alloc::boxed::Box;
+	use core::future::Future;
+	use futures_util::task::noop_waker_ref;
+
+	serial_print!("test_mpsc_backpressure... ");
+	let mut cx = Context::from_waker(noop_waker_ref());
+	let (sender, mut receiver) = channel(2);
+	assert_eq!(sender.try_send(1), Ok(()));
+	assert_eq!(sender.try_send(2), Ok(()));
+	assert_eq!(sender.try_send(3), Err(TrySendError::Full(3)));
+	let mut blocked = Box::pin(sender.send(3));
+	assert!(blocked.as_mut().poll(&mut cx).is_pending());
+	//receiving frees a slot, which goes to the waiting send
+	assert_eq!(receiver.try_recv(), Some(1));
+	assert_eq!(blocked.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+	drop(blocked);
+	assert_eq!(receiver.try_recv(), Some(2));
+	assert_eq!(receiver.try_recv(), Some(3));
+	assert_eq!(receiver.try_recv(), None);
+	//once the last sender is gone the queue drains and then the stream ends
+	assert_eq!(sender.try_send(4), Ok(()));
+	drop(sender);
+	assert_eq!(receiver.poll_recv(&mut cx), Poll::Ready(Some(4)));
+	assert_eq!(receiver.poll_recv(&mut cx), Poll::Ready(None));
+
+	//dropping the receiver fails sends that are waiting for a slot
+	let (sender, receiver) = channel(1);
+	assert_eq!(sender.try_send(1), Ok(()));
+	let mut blocked = Box::pin(sender.send(2));
+	assert!(blocked.as_mut().poll(&mut cx).is_pending());
+	drop(receiver);
+	assert_eq!(blocked.as_mut().poll(&mut cx), Poll::Ready(Err(SendError(2))));
+	drop(blocked);
+	assert!(sender.is_closed());
+	assert_eq!(sender.try_send(3), Err(TrySendError::Closed(3)));
+	serial_println!("[ok]");
+}
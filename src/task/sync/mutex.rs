@@ -0,0 +1,93 @@
+use super::semaphore::{Semaphore, SemaphorePermit};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+/// An async mutex, waiting tasks yield to the executor instead of spinning
+///
+/// Unlike `spin::Mutex` this must not be locked from interrupt handlers, since they cannot await.
+pub struct Mutex<T: ?Sized> {
+	semaphore: Semaphore,
+	data: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+	pub const fn new(data: T) -> Self {
+		Mutex {
+			semaphore: Semaphore::new(1),
+			data: UnsafeCell::new(data),
+		}
+	}
+	pub fn into_inner(self) -> T {
+		self.data.into_inner()
+	}
+}
+impl<T: ?Sized> Mutex<T> {
+	/// Waits until the lock is free, waiters are served in FIFO order
+	pub async fn lock(&self) -> MutexGuard<'_, T> {
+		let permit = self.semaphore.acquire().await.expect("mutex semaphore is never closed");
+		MutexGuard {
+			mutex: self,
+			_permit: permit,
+		}
+	}
+	pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+		self.semaphore.try_acquire().ok().map(|permit| MutexGuard {
+			mutex: self,
+			_permit: permit,
+		})
+	}
+	pub fn get_mut(&mut self) -> &mut T {
+		unsafe {&mut *self.data.get()}
+	}
+}
+impl<T: Default> Default for Mutex<T> {
+	fn default() -> Self {Self::new(T::default())}
+}
+
+/// Gives access to the data of a locked `Mutex`, unlocking it when dropped
+pub struct MutexGuard<'a, T: ?Sized> {
+	mutex: &'a Mutex<T>,
+	_permit: SemaphorePermit<'a>,
+}
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		unsafe {&*self.mutex.data.get()}
+	}
+}
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe {&mut *self.mutex.data.get()}
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_mutex_handoff() {
+	use alloc::boxed::Box;
+	use core::{future::Future, task::{Context, Poll}};
+	use futures_util::task::noop_waker_ref;
+
+	serial_print!("test_mutex_handoff... ");
+	let mut cx = Context::from_waker(noop_waker_ref());
+	let mutex = Mutex::new(0);
+	let mut guard = mutex.try_lock().expect("mutex should be free");
+	let mut waiting = Box::pin(mutex.lock());
+	assert!(waiting.as_mut().poll(&mut cx).is_pending());
+	assert!(mutex.try_lock().is_none());
+	*guard += 1;
+	drop(guard);
+	//unlocking hands the lock straight to the waiting task, not to whoever asks next
+	assert!(mutex.try_lock().is_none());
+	match waiting.as_mut().poll(&mut cx) {
+		Poll::Ready(mut guard) => *guard += 1,
+		Poll::Pending => panic!("the waiting task should have been handed the lock"),
+	}
+	drop(waiting);
+	assert_eq!(mutex.into_inner(), 2);
+	serial_println!("[ok]");
+}
@@ -0,0 +1,118 @@
+use alloc::sync::Arc;
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+
+/// Returned by a `Receiver` whose `Sender` was dropped without sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct Inner<T> {
+	value: Option<T>,
+	sender_alive: bool,
+	receiver_alive: bool,
+	receiver_waker: Option<Waker>,
+}
+
+/// Creates a channel that carries exactly one value
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+	let inner = Arc::new(spin::Mutex::new(Inner {
+		value: None,
+		sender_alive: true,
+		receiver_alive: true,
+		receiver_waker: None,
+	}));
+	(Sender {inner: inner.clone()}, Receiver {inner})
+}
+
+pub struct Sender<T> {
+	inner: Arc<spin::Mutex<Inner<T>>>,
+}
+impl<T> Sender<T> {
+	/// Sends the value, handing it back if the receiver is gone
+	pub fn send(self, value: T) -> Result<(), T> {
+		let waker = {
+			let mut inner = self.inner.lock();
+			if !inner.receiver_alive {
+				return Err(value);
+			}
+			inner.value = Some(value);
+			inner.receiver_waker.take()
+		};
+		if let Some(waker) = waker {
+			waker.wake();
+		}
+		Ok(())
+	}
+	pub fn is_closed(&self) -> bool {
+		!self.inner.lock().receiver_alive
+	}
+}
+impl<T> Drop for Sender<T> {
+	fn drop(&mut self) {
+		let waker = {
+			let mut inner = self.inner.lock();
+			inner.sender_alive = false;
+			inner.receiver_waker.take()
+		};
+		if let Some(waker) = waker {
+			waker.wake();
+		}
+	}
+}
+
+/// A future resolving to the sent value
+pub struct Receiver<T> {
+	inner: Arc<spin::Mutex<Inner<T>>>,
+}
+impl<T> Receiver<T> {
+	/// Takes the value if it has already been sent
+	pub fn try_recv(&mut self) -> Option<T> {
+		self.inner.lock().value.take()
+	}
+}
+impl<T> Future for Receiver<T> {
+	type Output = Result<T, RecvError>;
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		let mut inner = self.inner.lock();
+		if let Some(value) = inner.value.take() {
+			Poll::Ready(Ok(value))
+		}
+		else if !inner.sender_alive {
+			Poll::Ready(Err(RecvError))
+		}
+		else {
+			inner.receiver_waker = Some(cx.waker().clone());
+			Poll::Pending
+		}
+	}
+}
+impl<T> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		self.inner.lock().receiver_alive = false;
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_oneshot_sender_dropped() {
+	use futures_util::task::noop_waker_ref;
+
+	serial_print!("test_oneshot_sender_dropped... ");
+	let mut cx = Context::from_waker(noop_waker_ref());
+	let (sender, mut receiver) = channel::<u32>();
+	assert!(Pin::new(&mut receiver).poll(&mut cx).is_pending());
+	drop(sender);
+	assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Ready(Err(RecvError)));
+
+	//a value sent before the sender is dropped is still delivered
+	let (sender, mut receiver) = channel();
+	assert_eq!(sender.send(7), Ok(()));
+	assert_eq!(Pin::new(&mut receiver).poll(&mut cx), Poll::Ready(Ok(7)));
+
+	let (sender, receiver) = channel();
+	drop(receiver);
+	assert!(sender.is_closed());
+	assert_eq!(sender.send(3), Err(3));
+	serial_println!("[ok]");
+}
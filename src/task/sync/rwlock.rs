@@ -0,0 +1,131 @@
+use super::semaphore::{Semaphore, SemaphorePermit};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+// A reader takes one permit and a writer takes all of them
+const MAX_READERS: usize = 1 << 16;
+
+/// An async reader-writer lock
+///
+/// Waiters are served in FIFO order, so a waiting writer holds back readers that arrive after it.
+pub struct RwLock<T: ?Sized> {
+	semaphore: Semaphore,
+	data: UnsafeCell<T>,
+}
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+	pub const fn new(data: T) -> Self {
+		RwLock {
+			semaphore: Semaphore::new(MAX_READERS),
+			data: UnsafeCell::new(data),
+		}
+	}
+	pub fn into_inner(self) -> T {
+		self.data.into_inner()
+	}
+}
+impl<T: ?Sized> RwLock<T> {
+	pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+		let permit = self.semaphore.acquire().await.expect("rwlock semaphore is never closed");
+		RwLockReadGuard {
+			lock: self,
+			_permit: permit,
+		}
+	}
+	pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+		let permit = self.semaphore.acquire_many(MAX_READERS).await.expect("rwlock semaphore is never closed");
+		RwLockWriteGuard {
+			lock: self,
+			_permit: permit,
+		}
+	}
+	pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+		self.semaphore.try_acquire().ok().map(|permit| RwLockReadGuard {
+			lock: self,
+			_permit: permit,
+		})
+	}
+	pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+		self.semaphore.try_acquire_many(MAX_READERS).ok().map(|permit| RwLockWriteGuard {
+			lock: self,
+			_permit: permit,
+		})
+	}
+	pub fn get_mut(&mut self) -> &mut T {
+		unsafe {&mut *self.data.get()}
+	}
+}
+impl<T: Default> Default for RwLock<T> {
+	fn default() -> Self {Self::new(T::default())}
+}
+
+/// Shared access to the data of an `RwLock`
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+	lock: &'a RwLock<T>,
+	_permit: SemaphorePermit<'a>,
+}
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		unsafe {&*self.lock.data.get()}
+	}
+}
+
+/// Exclusive access to the data of an `RwLock`
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+	lock: &'a RwLock<T>,
+	_permit: SemaphorePermit<'a>,
+}
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		unsafe {&*self.lock.data.get()}
+	}
+}
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		unsafe {&mut *self.lock.data.get()}
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_rwlock_writer_preference() {
+	use alloc::boxed::Box;
+	use core::{future::Future, task::{Context, Poll}};
+	use futures_util::task::noop_waker_ref;
+
+	serial_print!("test_rwlock_writer_preference... ");
+	let mut cx = Context::from_waker(noop_waker_ref());
+	let lock = RwLock::new(0);
+	let reader = lock.try_read().expect("lock should be free");
+	let mut writer = Box::pin(lock.write());
+	assert!(writer.as_mut().poll(&mut cx).is_pending());
+	//readers that arrive after a waiting writer queue behind it
+	assert!(lock.try_read().is_none());
+	let mut late_reader = Box::pin(lock.read());
+	assert!(late_reader.as_mut().poll(&mut cx).is_pending());
+	drop(reader);
+	match writer.as_mut().poll(&mut cx) {
+		Poll::Ready(mut guard) => *guard = 1,
+		Poll::Pending => panic!("the writer should have been handed the lock"),
+	}
+	match late_reader.as_mut().poll(&mut cx) {
+		Poll::Ready(guard) => assert_eq!(*guard, 1),
+		Poll::Pending => panic!("the reader should follow the writer"),
+	}
+
+	//each reader takes one of MAX_READERS permits
+	let others = lock.semaphore.try_acquire_many(MAX_READERS - 1).expect("lock should be free");
+	let last = lock.try_read().expect("one reader should still fit");
+	assert!(lock.try_read().is_none());
+	assert!(lock.try_write().is_none());
+	drop(last);
+	drop(others);
+	assert!(lock.try_write().is_some());
+	serial_println!("[ok]");
+}
@@ -0,0 +1,240 @@
+use alloc::{sync::Arc, vec::Vec};
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Returned when acquiring from a semaphore that has been closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AcquireError;
+
+/// Returned by `Semaphore::try_acquire` when the permits are not immediately available
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+	Closed,
+	NoPermits,
+}
+
+// A task waiting for permits, shared between the waiter list and its Acquire future
+struct Waiter {
+	needed: usize,
+	// Set once the permits have been handed to this waiter
+	granted: AtomicBool,
+	waker: spin::Mutex<Option<Waker>>,
+}
+
+struct State {
+	permits: usize,
+	closed: bool,
+	// Waiters in the order they arrived, permits are always handed out front first
+	waiters: Vec<Arc<Waiter>>,
+}
+
+/// A fair, waker-based counting semaphore
+///
+/// Waiters are served in FIFO order, so a large request is never starved by a stream of small ones.
+pub struct Semaphore {
+	state: spin::Mutex<State>,
+}
+impl Semaphore {
+	pub const fn new(permits: usize) -> Self {
+		Semaphore {
+			state: spin::Mutex::new(State {
+				permits,
+				closed: false,
+				waiters: Vec::new(),
+			}),
+		}
+	}
+	pub fn available_permits(&self) -> usize {
+		self.state.lock().permits
+	}
+	/// Waits for a single permit
+	pub fn acquire(&self) -> Acquire<'_> {
+		self.acquire_many(1)
+	}
+	/// Waits until `count` permits can be taken at once
+	pub fn acquire_many(&self, count: usize) -> Acquire<'_> {
+		Acquire {
+			semaphore: self,
+			count,
+			waiter: None,
+		}
+	}
+	pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+		self.try_acquire_many(1)
+	}
+	pub fn try_acquire_many(&self, count: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+		let mut state = self.state.lock();
+		if state.closed {
+			Err(TryAcquireError::Closed)
+		}
+		else if state.waiters.is_empty() && state.permits >= count {
+			state.permits -= count;
+			Ok(SemaphorePermit {
+				semaphore: self,
+				count,
+			})
+		}
+		else {
+			Err(TryAcquireError::NoPermits)
+		}
+	}
+	/// Returns `count` permits to the semaphore, waking waiters that can now proceed
+	pub fn add_permits(&self, count: usize) {
+		let wakers = {
+			let mut state = self.state.lock();
+			state.permits += count;
+			Self::grant(&mut state)
+		};
+		for waker in wakers {
+			waker.wake();
+		}
+	}
+	/// Fails every pending and future acquire; permits already handed out stay valid
+	pub fn close(&self) {
+		let waiters = {
+			let mut state = self.state.lock();
+			state.closed = true;
+			core::mem::take(&mut state.waiters)
+		};
+		for waiter in waiters {
+			if let Some(waker) = waiter.waker.lock().take() {
+				waker.wake();
+			}
+		}
+	}
+	pub fn is_closed(&self) -> bool {
+		self.state.lock().closed
+	}
+	// Hands permits to waiters at the front of the queue, returning the wakers to call once the lock is dropped
+	fn grant(state: &mut State) -> Vec<Waker> {
+		let mut wakers = Vec::new();
+		let mut granted = 0;
+		for waiter in state.waiters.iter() {
+			if waiter.needed > state.permits {
+				break;
+			}
+			state.permits -= waiter.needed;
+			waiter.granted.store(true, Ordering::Release);
+			if let Some(waker) = waiter.waker.lock().take() {
+				wakers.push(waker);
+			}
+			granted += 1;
+		}
+		state.waiters.drain(..granted);
+		wakers
+	}
+}
+
+/// A future that resolves once the requested permits have been acquired
+pub struct Acquire<'a> {
+	semaphore: &'a Semaphore,
+	count: usize,
+	waiter: Option<Arc<Waiter>>,
+}
+impl<'a> Future for Acquire<'a> {
+	type Output = Result<SemaphorePermit<'a>, AcquireError>;
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		let semaphore = self.semaphore;
+		let count = self.count;
+		let mut state = semaphore.state.lock();
+		match self.waiter.clone() {
+			Some(waiter) => {
+				if waiter.granted.load(Ordering::Acquire) {
+					self.waiter = None;
+					return Poll::Ready(Ok(SemaphorePermit {semaphore, count}));
+				}
+				if state.closed {
+					self.waiter = None;
+					return Poll::Ready(Err(AcquireError));
+				}
+				*waiter.waker.lock() = Some(cx.waker().clone());
+				Poll::Pending
+			}
+			None => {
+				if state.closed {
+					return Poll::Ready(Err(AcquireError));
+				}
+				if state.waiters.is_empty() && state.permits >= count {
+					state.permits -= count;
+					return Poll::Ready(Ok(SemaphorePermit {semaphore, count}));
+				}
+				let waiter = Arc::new(Waiter {
+					needed: count,
+					granted: AtomicBool::new(false),
+					waker: spin::Mutex::new(Some(cx.waker().clone())),
+				});
+				state.waiters.push(waiter.clone());
+				drop(state);
+				self.waiter = Some(waiter);
+				Poll::Pending
+			}
+		}
+	}
+}
+impl<'a> Drop for Acquire<'a> {
+	fn drop(&mut self) {
+		if let Some(waiter) = self.waiter.take() {
+			let wakers = {
+				let mut state = self.semaphore.state.lock();
+				if waiter.granted.load(Ordering::Acquire) {
+					//the permits were handed over but never observed, give them back
+					state.permits += waiter.needed;
+				}
+				else {
+					state.waiters.retain(|other| !Arc::ptr_eq(other, &waiter));
+				}
+				//removing a large waiter from the front may let the ones behind it proceed
+				Semaphore::grant(&mut state)
+			};
+			for waker in wakers {
+				waker.wake();
+			}
+		}
+	}
+}
+
+/// Permits held from a `Semaphore`, released when dropped
+pub struct SemaphorePermit<'a> {
+	semaphore: &'a Semaphore,
+	count: usize,
+}
+impl<'a> SemaphorePermit<'a> {
+	/// Keeps the permits taken without returning them to the semaphore
+	pub fn forget(mut self) {
+		self.count = 0;
+	}
+}
+impl<'a> Drop for SemaphorePermit<'a> {
+	fn drop(&mut self) {
+		if self.count > 0 {
+			self.semaphore.add_permits(self.count);
+		}
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_semaphore_fifo() {
+	use futures_util::task::noop_waker_ref;
+
+	serial_print!("test_semaphore_fifo... ");
+	let mut cx = Context::from_waker(noop_waker_ref());
+	let semaphore = Semaphore::new(2);
+	let permit = semaphore.try_acquire().expect("permit should be free");
+	let mut large = semaphore.acquire_many(2);
+	let mut small = semaphore.acquire();
+	assert!(Pin::new(&mut large).poll(&mut cx).is_pending());
+	//a permit is free, but the small request queued behind the large one
+	assert!(Pin::new(&mut small).poll(&mut cx).is_pending());
+	drop(permit);
+	let large_permit = match Pin::new(&mut large).poll(&mut cx) {
+		Poll::Ready(Ok(permit)) => permit,
+		_ => panic!("large request should have been granted"),
+	};
+	assert!(Pin::new(&mut small).poll(&mut cx).is_pending());
+	drop(large_permit);
+	assert!(matches!(Pin::new(&mut small).poll(&mut cx), Poll::Ready(Ok(_))));
+	serial_println!("[ok]");
+}
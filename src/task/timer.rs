@@ -0,0 +1,320 @@
+use crate::timer;
+use alloc::vec::Vec;
+use core::{future::Future, pin::Pin, task::{Context, Poll, Waker}, time::Duration};
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures_util::stream::Stream;
+use spin::Mutex;
+
+const WHEEL_SLOTS: usize = 256;
+
+struct TimerEntry {
+	id: u64,
+	deadline: u64,
+	waker: Waker,
+}
+
+/// Buckets timers by `deadline % WHEEL_SLOTS`, so that each tick only has to look at one slot
+struct TimerWheel {
+	slots: [Vec<TimerEntry>; WHEEL_SLOTS],
+	// The last tick whose slot has been processed
+	processed: u64,
+}
+impl TimerWheel {
+	const fn new() -> Self {
+		TimerWheel {
+			slots: [Vec::new(); WHEEL_SLOTS],
+			processed: 0,
+		}
+	}
+	fn slot(deadline: u64) -> usize {
+		(deadline % WHEEL_SLOTS as u64) as usize
+	}
+	fn register(&mut self, id: u64, deadline: u64, waker: &Waker) {
+		if deadline <= self.processed {
+			//another CPU processed the deadline's slot since the caller checked the ticks, it would otherwise wait a whole rotation
+			waker.wake_by_ref();
+			return;
+		}
+		let slot = &mut self.slots[Self::slot(deadline)];
+		if let Some(entry) = slot.iter_mut().find(|entry| entry.id == id) {
+			if !entry.waker.will_wake(waker) {
+				entry.waker = waker.clone();
+			}
+		}
+		else {
+			slot.push(TimerEntry {
+				id,
+				deadline,
+				waker: waker.clone(),
+			});
+		}
+	}
+	fn cancel(&mut self, id: u64, deadline: u64) {
+		let slot = &mut self.slots[Self::slot(deadline)];
+		if let Some(index) = slot.iter().position(|entry| entry.id == id) {
+			slot.swap_remove(index);
+		}
+	}
+	fn wake_slot(&mut self, slot: usize, now: u64) {
+		let entries = &mut self.slots[slot];
+		let mut i = 0;
+		while i < entries.len() {
+			if entries[i].deadline <= now {
+				entries.swap_remove(i).waker.wake();
+			}
+			else {
+				i += 1;
+			}
+		}
+	}
+	fn wake_expired(&mut self, now: u64) {
+		if now <= self.processed {
+			return;
+		}
+		if now - self.processed >= WHEEL_SLOTS as u64 {
+			//we fell behind a full rotation, every slot may hold something expired
+			for slot in 0..WHEEL_SLOTS {
+				self.wake_slot(slot, now);
+			}
+		}
+		else {
+			for tick in self.processed + 1..=now {
+				self.wake_slot(Self::slot(tick), now);
+			}
+		}
+		self.processed = now;
+	}
+	fn next_deadline(&self) -> Option<u64> {
+		self.slots.iter().flat_map(|slot| slot.iter()).map(|entry| entry.deadline).min()
+	}
+}
+
+// Only ever locked from task context; the timer interrupt just advances the tick counter
+static WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel::new());
+
+/// Wakes every timer whose deadline has passed, called by the executor on every loop
+pub(crate) fn wake_expired() {
+	WHEEL.lock().wake_expired(timer::ticks());
+}
+
+/// Returns the earliest tick at which a registered timer expires
+pub fn next_deadline() -> Option<u64> {
+	WHEEL.lock().next_deadline()
+}
+
+/// Arms the calling CPU's wakeup timer for the next deadline before it halts
+///
+/// Returns false if a timer has already expired, so the CPU must not halt.
+pub(crate) fn arm_idle_wakeup() -> bool {
+	timer::arm_wakeup(next_deadline())
+}
+
+/// Converts a duration to timer ticks, rounding up so that timers never fire early
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+	let freq = timer::frequency() as u128;
+	assert!(freq != 0, "timer frequency must be set before using timers");
+	let ticks = (duration.as_nanos() * freq + 999_999_999) / 1_000_000_000;
+	ticks as u64
+}
+
+fn next_timer_id() -> u64 {
+	static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+	NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A future that completes once the tick counter reaches its deadline
+pub struct Sleep {
+	id: u64,
+	deadline: u64,
+	registered: bool,
+}
+impl Sleep {
+	fn until(deadline: u64) -> Self {
+		Sleep {
+			id: next_timer_id(),
+			deadline,
+			registered: false,
+		}
+	}
+	pub fn deadline(&self) -> u64 {
+		self.deadline
+	}
+	/// Moves the deadline, the sleep can be polled again afterwards
+	pub fn reset(&mut self, deadline: u64) {
+		self.cancel();
+		self.deadline = deadline;
+	}
+	fn cancel(&mut self) {
+		if self.registered {
+			WHEEL.lock().cancel(self.id, self.deadline);
+			self.registered = false;
+		}
+	}
+}
+impl Future for Sleep {
+	type Output = ();
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+		if timer::ticks() >= self.deadline {
+			self.cancel();
+			return Poll::Ready(());
+		}
+		WHEEL.lock().register(self.id, self.deadline, cx.waker());
+		self.registered = true;
+		Poll::Pending
+	}
+}
+impl Drop for Sleep {
+	fn drop(&mut self) {
+		self.cancel();
+	}
+}
+
+/// Completes after at least `duration` has passed
+pub fn sleep(duration: Duration) -> Sleep {
+	Sleep::until(timer::ticks() + duration_to_ticks(duration))
+}
+
+/// Completes once the tick counter reaches `deadline`
+pub fn sleep_until(deadline: u64) -> Sleep {
+	Sleep::until(deadline)
+}
+
+/// A stream that yields the current tick once every period
+///
+/// If the consumer falls behind, missed periods are skipped rather than yielded in a burst.
+pub struct Interval {
+	period: u64,
+	sleep: Sleep,
+}
+impl Interval {
+	/// Waits for the next period to elapse
+	pub async fn tick(&mut self) -> u64 {
+		futures_util::StreamExt::next(self).await.expect("Interval never ends")
+	}
+}
+impl Stream for Interval {
+	type Item = u64;
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+		match Pin::new(&mut self.sleep).poll(cx) {
+			Poll::Ready(()) => {
+				let now = timer::ticks();
+				let mut next = self.sleep.deadline() + self.period;
+				if next <= now {
+					next = now + self.period;
+				}
+				self.sleep.reset(next);
+				Poll::Ready(Some(now))
+			}
+			Poll::Pending => Poll::Pending,
+		}
+	}
+}
+
+/// Creates an interval whose first tick completes immediately
+pub fn interval(period: Duration) -> Interval {
+	let period = duration_to_ticks(period).max(1);
+	Interval {
+		period,
+		sleep: Sleep::until(timer::ticks()),
+	}
+}
+
+/// The error returned when a `Timeout` elapses before its future completes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Wraps a future, resolving to `Err(Elapsed)` if it does not complete in time
+pub struct Timeout<F: Future> {
+	future: F,
+	sleep: Sleep,
+}
+impl<F: Future> Future for Timeout<F> {
+	type Output = Result<F::Output, Elapsed>;
+	fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+		// Safety: `future` is structurally pinned and never moved out of `self`, `sleep` is Unpin
+		let this = unsafe { self.get_unchecked_mut() };
+		let future = unsafe { Pin::new_unchecked(&mut this.future) };
+		if let Poll::Ready(output) = future.poll(cx) {
+			return Poll::Ready(Ok(output));
+		}
+		match Pin::new(&mut this.sleep).poll(cx) {
+			Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+			Poll::Pending => Poll::Pending,
+		}
+	}
+}
+
+/// Runs `future` for at most `duration`
+pub fn timeout<F: Future>(future: F, duration: Duration) -> Timeout<F> {
+	Timeout {
+		future,
+		sleep: sleep(duration),
+	}
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_duration_to_ticks_rounds_up() {
+	serial_print!("test_duration_to_ticks_rounds_up... ");
+	let freq = timer::frequency() as u64;
+	assert_eq!(duration_to_ticks(Duration::from_secs(1)), freq);
+	assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+	assert_eq!(duration_to_ticks(Duration::from_secs(0)), 0);
+	serial_println!("[ok]");
+}
+
+#[cfg(test)]
+struct WakeCounter(core::sync::atomic::AtomicUsize);
+#[cfg(test)]
+impl alloc::task::Wake for WakeCounter {
+	fn wake(self: alloc::sync::Arc<Self>) {
+		self.0.fetch_add(1, Ordering::Relaxed);
+	}
+}
+
+#[test_case]
+fn test_timer_wheel() {
+	serial_print!("test_timer_wheel... ");
+	use alloc::sync::Arc;
+	use core::sync::atomic::AtomicUsize;
+	let counter = Arc::new(WakeCounter(AtomicUsize::new(0)));
+	let waker = Waker::from(counter.clone());
+	let wakes = || counter.0.load(Ordering::Relaxed);
+	let mut wheel = TimerWheel::new();
+	wheel.wake_expired(10);
+
+	//register then expire, registering again only updates the entry
+	wheel.register(1, 15, &waker);
+	wheel.register(1, 15, &waker);
+	assert_eq!(wheel.next_deadline(), Some(15));
+	wheel.wake_expired(14);
+	assert_eq!(wakes(), 0);
+	wheel.wake_expired(15);
+	assert_eq!(wakes(), 1);
+	assert_eq!(wheel.next_deadline(), None);
+
+	//a deadline the wheel has already processed wakes at once instead of waiting a rotation
+	wheel.wake_expired(20);
+	wheel.register(2, 18, &waker);
+	wheel.register(3, 20, &waker);
+	assert_eq!(wakes(), 3);
+	assert_eq!(wheel.next_deadline(), None);
+
+	//cancel
+	wheel.register(4, 30, &waker);
+	wheel.cancel(4, 30);
+	wheel.wake_expired(40);
+	assert_eq!(wakes(), 3);
+	assert_eq!(wheel.next_deadline(), None);
+
+	//falling a full rotation behind catches up on every slot
+	wheel.register(5, 41, &waker);
+	wheel.register(6, 290, &waker);
+	wheel.register(7, 1000, &waker);
+	wheel.wake_expired(400);
+	assert_eq!(wakes(), 5);
+	assert_eq!(wheel.next_deadline(), Some(1000));
+	serial_println!("[ok]");
+}
@@ -1,3 +1,11 @@
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use core::convert::TryFrom;
+use spin::RwLock;
+use x86_64::instructions::port::Port;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Calendar {
 	pub year: u32,
 	pub month: u32,
@@ -12,87 +20,609 @@ impl core::fmt::Display for Calendar {
 	}
 }
 
-fn get_update_in_progress_flag() -> bool {
-	use x86_64::instructions::port::{Port, PortReadOnly};
-	let mut rtc_address_port = Port::<u8>::new(0x70);
-	let mut rtc_data_port = PortReadOnly::<u8>::new(0x71);
-	unsafe {
-		let nmi_bit: u8 = rtc_address_port.read() & 0x80 as u8;
-		rtc_address_port.write(nmi_bit | (0x0A as u8));
-		rtc_data_port.read() & 0x80 == 0x80
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weekday {
+	Sunday,
+	Monday,
+	Tuesday,
+	Wednesday,
+	Thursday,
+	Friday,
+	Saturday,
+}
+
+/// The field of a `Calendar` that is out of range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalendarError {
+	Year,
+	Month,
+	Day,
+	Hour,
+	Minute,
+	Second,
+}
+
+impl Calendar {
+	/// Checks that every field is in range, including the number of days in the month
+	pub fn validate(&self) -> Result<(), CalendarError> {
+		if self.year < 1970 {
+			Err(CalendarError::Year)
+		}
+		else if self.month < 1 || self.month > 12 {
+			Err(CalendarError::Month)
+		}
+		else if self.day < 1 || self.day > days_in_month(self.year, self.month) {
+			Err(CalendarError::Day)
+		}
+		else if self.hour > 23 {
+			Err(CalendarError::Hour)
+		}
+		else if self.minute > 59 {
+			Err(CalendarError::Minute)
+		}
+		else if self.second > 59 {
+			Err(CalendarError::Second)
+		}
+		else {
+			Ok(())
+		}
+	}
+	pub fn is_valid(&self) -> bool {
+		self.validate().is_ok()
+	}
+	pub fn day_of_week(&self) -> Weekday {
+		//1970-01-01 was a Thursday
+		match (days_from_civil(self.year as i64, self.month, self.day) + 4).rem_euclid(7) {
+			0 => Weekday::Sunday,
+			1 => Weekday::Monday,
+			2 => Weekday::Tuesday,
+			3 => Weekday::Wednesday,
+			4 => Weekday::Thursday,
+			5 => Weekday::Friday,
+			_ => Weekday::Saturday,
+		}
+	}
+	/// Seconds since the Unix epoch, interpreting the calendar as UTC
+	pub fn to_unix_timestamp(&self) -> Result<u64, CalendarError> {
+		self.validate()?;
+		let days = days_from_civil(self.year as i64, self.month, self.day) as u64;
+		Ok(days * 86400 + (self.hour * 3600 + self.minute * 60 + self.second) as u64)
+	}
+	/// The UTC calendar date of a Unix timestamp
+	pub fn from_unix_timestamp(timestamp: u64) -> Calendar {
+		let (year, month, day) = civil_from_days((timestamp / 86400) as i64);
+		let seconds_of_day = (timestamp % 86400) as u32;
+		Calendar {
+			year: year as u32,
+			month,
+			day,
+			hour: seconds_of_day / 3600,
+			minute: seconds_of_day / 60 % 60,
+			second: seconds_of_day % 60,
+		}
 	}
 }
 
-fn get_rtc_register(reg: u8) -> u8 {
-	use x86_64::instructions::port::{Port, PortReadOnly};
-	let mut rtc_address_port = Port::<u8>::new(0x70);
-	let mut rtc_data_port = PortReadOnly::new(0x71);
-	unsafe {
-		let nmi_bit: u8 = rtc_address_port.read() & 0x80;
-		rtc_address_port.write(nmi_bit | reg);
-		rtc_data_port.read()
+fn is_leap_year(year: u32) -> bool {
+	(year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+	match month {
+		2 if is_leap_year(year) => 29,
+		2 => 28,
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
 	}
 }
 
-pub fn get_current_time(century_register: u8) -> Calendar {
-	while get_update_in_progress_flag(){}
-	let mut second = get_rtc_register(0x00) as u32;
-	let mut minute = get_rtc_register(0x02) as u32;
-	let mut hour = get_rtc_register(0x04) as u32;
-	let mut day = get_rtc_register(0x07) as u32;
-	let mut month = get_rtc_register(0x08) as u32;
-	let mut year = get_rtc_register(0x09) as u32;
-	let mut century = if century_register != 0 {get_rtc_register(century_register)} else {0} as u32;
+// CMOS register indices
+const RTC_SECONDS: u8 = 0x00;
+const RTC_MINUTES: u8 = 0x02;
+const RTC_HOURS: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+pub(crate) const RTC_STATUS_A: u8 = 0x0A;
+pub(crate) const RTC_STATUS_B: u8 = 0x0B;
+
+// Status register B bits
+// Halts updates while the time is being set
+const STATUS_B_SET: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0b10;
+const STATUS_B_BINARY: u8 = 0b100;
+// Set on the hour register for PM times in 12-hour mode
+const HOUR_PM: u8 = 0x80;
+
+// With no century register, two-digit years below this are taken to be in the 2000s
+const CENTURY_PIVOT: u32 = 70;
+
+/// Raw access to the registers of a real-time clock
+///
+/// Decoding goes through this trait so that every register format can be tested without the hardware.
+pub trait RtcRegisters {
+	fn read_register(&mut self, reg: u8) -> u8;
+	fn write_register(&mut self, reg: u8, value: u8);
+	fn update_in_progress(&mut self) -> bool {
+		self.read_register(RTC_STATUS_A) & 0x80 == 0x80
+	}
+}
+
+// Selecting a register and accessing it must not be split by the RTC interrupt handler or another CPU
+static CMOS_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+/// The CMOS real-time clock behind ports 0x70 and 0x71
+pub struct Cmos;
+impl Cmos {
+	fn access<T>(reg: u8, f: impl FnOnce(&mut Port<u8>) -> T) -> T {
+		x86_64::instructions::interrupts::without_interrupts(|| {
+			let _lock = CMOS_LOCK.lock();
+			let mut rtc_address_port = Port::<u8>::new(0x70);
+			let mut rtc_data_port = Port::<u8>::new(0x71);
+			unsafe {
+				let nmi_bit: u8 = rtc_address_port.read() & 0x80;
+				rtc_address_port.write(nmi_bit | reg);
+			}
+			f(&mut rtc_data_port)
+		})
+	}
+}
+impl RtcRegisters for Cmos {
+	fn read_register(&mut self, reg: u8) -> u8 {
+		Cmos::access(reg, |data| unsafe {data.read()})
+	}
+	fn write_register(&mut self, reg: u8, value: u8) {
+		Cmos::access(reg, |data| unsafe {data.write(value)})
+	}
+}
+
+/// Register values exactly as the RTC reports them, before any BCD or 12-hour conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawRtcTime {
+	pub second: u8,
+	pub minute: u8,
+	pub hour: u8,
+	pub day: u8,
+	pub month: u8,
+	pub year: u8,
+	/// None when the FADT doesn't name a century register
+	pub century: Option<u8>,
+	pub status_b: u8,
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+	(value & 0x0F) + (value >> 4) * 10
+}
 
+fn binary_to_bcd(value: u8) -> u8 {
+	((value / 10) << 4) | (value % 10)
+}
+
+/// Encodes a minute, second, day, month or two-digit year in the format selected by status register B
+pub(crate) fn encode_rtc_value(value: u8, status_b: u8) -> u8 {
+	if status_b & STATUS_B_BINARY != 0 {value} else {binary_to_bcd(value)}
+}
+
+/// Encodes a 24-hour clock hour, converting it to 12-hour time if status register B asks for it
+pub(crate) fn encode_rtc_hour(hour: u8, status_b: u8) -> u8 {
+	if status_b & STATUS_B_24_HOUR != 0 {
+		return encode_rtc_value(hour, status_b);
+	}
+	let pm = if hour >= 12 {HOUR_PM} else {0};
+	let hour = match hour % 12 {
+		0 => 12,
+		hour => hour,
+	};
+	encode_rtc_value(hour, status_b) | pm
+}
+
+impl RawRtcTime {
+	/// Reads every time register once
+	pub fn read<R: RtcRegisters>(rtc: &mut R, century_register: u8) -> Self {
+		RawRtcTime {
+			second: rtc.read_register(RTC_SECONDS),
+			minute: rtc.read_register(RTC_MINUTES),
+			hour: rtc.read_register(RTC_HOURS),
+			day: rtc.read_register(RTC_DAY),
+			month: rtc.read_register(RTC_MONTH),
+			year: rtc.read_register(RTC_YEAR),
+			century: if century_register != 0 {Some(rtc.read_register(century_register))} else {None},
+			status_b: rtc.read_register(RTC_STATUS_B),
+		}
+	}
+	/// Encodes the calendar in the RTC's format, the inverse of `decode`
+	///
+	/// Without a century register only the years 1970 to 2069 can be represented.
+	pub fn encode(calendar: &Calendar, status_b: u8, has_century: bool) -> Result<Self, CalendarError> {
+		calendar.validate()?;
+		if calendar.year > 9999 || (!has_century && calendar.year >= 1900 + CENTURY_PIVOT + 100) {
+			return Err(CalendarError::Year);
+		}
+		Ok(RawRtcTime {
+			second: encode_rtc_value(calendar.second as u8, status_b),
+			minute: encode_rtc_value(calendar.minute as u8, status_b),
+			hour: encode_rtc_hour(calendar.hour as u8, status_b),
+			day: encode_rtc_value(calendar.day as u8, status_b),
+			month: encode_rtc_value(calendar.month as u8, status_b),
+			year: encode_rtc_value((calendar.year % 100) as u8, status_b),
+			century: if has_century {Some(encode_rtc_value((calendar.year / 100) as u8, status_b))} else {None},
+			status_b,
+		})
+	}
+	/// Writes every time register, holding off updates so the clock can't tick halfway through
+	pub fn write<R: RtcRegisters>(&self, rtc: &mut R, century_register: u8) {
+		rtc.write_register(RTC_STATUS_B, self.status_b | STATUS_B_SET);
+		rtc.write_register(RTC_SECONDS, self.second);
+		rtc.write_register(RTC_MINUTES, self.minute);
+		rtc.write_register(RTC_HOURS, self.hour);
+		rtc.write_register(RTC_DAY, self.day);
+		rtc.write_register(RTC_MONTH, self.month);
+		rtc.write_register(RTC_YEAR, self.year);
+		if let (Some(century), true) = (self.century, century_register != 0) {
+			rtc.write_register(century_register, century);
+		}
+		rtc.write_register(RTC_STATUS_B, self.status_b & !STATUS_B_SET);
+	}
+	/// Converts BCD and 12-hour values and expands the year to four digits
+	pub fn decode(&self) -> Calendar {
+		let binary = self.status_b & STATUS_B_BINARY != 0;
+		let convert = |value: u8| if binary {value} else {bcd_to_binary(value)};
+
+		let pm = self.hour & HOUR_PM != 0;
+		let mut hour = convert(self.hour & !HOUR_PM) as u32;
+		if self.status_b & STATUS_B_24_HOUR == 0 {
+			//12-hour clock: 12 AM is midnight and 12 PM is noon
+			hour %= 12;
+			if pm {
+				hour += 12;
+			}
+		}
+
+		let mut year = convert(self.year) as u32;
+		match self.century {
+			Some(century) => year += convert(century) as u32 * 100,
+			None if year < CENTURY_PIVOT => year += 2000,
+			None => year += 1900,
+		}
+
+		Calendar {
+			second: convert(self.second) as u32,
+			minute: convert(self.minute) as u32,
+			hour,
+			day: convert(self.day) as u32,
+			month: convert(self.month) as u32,
+			year,
+		}
+	}
+}
+
+/// Reads the RTC until two consecutive reads agree, so that an update can't tear the result
+pub fn read_calendar<R: RtcRegisters>(rtc: &mut R, century_register: u8) -> Calendar {
+	while rtc.update_in_progress() {}
+	let mut raw = RawRtcTime::read(rtc, century_register);
 	loop {
-		let last_second = second;
-		let last_minute = minute;
-		let last_hour = hour;
-		let last_day = day;
-		let last_month = month;
-		let last_year = year;
-		let last_century = century;
-		while get_update_in_progress_flag() {}
-		second = get_rtc_register(0x00) as u32;
-		minute = get_rtc_register(0x02) as u32;
-		hour = get_rtc_register(0x04) as u32;
-		day = get_rtc_register(0x07) as u32;
-		month = get_rtc_register(0x08) as u32;
-		year = get_rtc_register(0x09) as u32;
-		century = if century_register != 0 {get_rtc_register(century_register)} else {0} as u32;
-		if last_second == second && last_minute == minute && last_hour == hour && last_day == day && last_month == month && last_year == year && last_century == century {break};
-	}
-
-	//todo: get the century register
-
-	let status_register = get_rtc_register(0x0B);
-	if status_register & 0b100 == 0b000 {
-		//convert BCD to binary values
-		second = (second & 0x0F) + ((second / 16) * 10);
-		minute = (minute & 0x0F) + ((minute / 16) * 10);
-		hour = ((hour & 0x0F) + ((hour / 16) * 10)) | (hour & 0x80);
-		day = (day & 0x0F) + ((day / 16) * 10);
-		month = (month & 0x0F) + ((month / 16) * 10);
-		year = (year & 0x0F) + ((year / 16) * 10);
-		if century_register != 0 {
-			century = (century & 0x0F) + ((century / 16) * 10);
-		}
-	}
-	if (status_register & 0b10) == 0 && (hour & 0x80) == 0x80 {
-		//convert 12 hour to 24 hour clock
-		hour = 99;
-	}
-
-	if century_register != 0 {
-		year += century * 100;
-	}
-	//get the full year, the RTC stores only the last two digits (thanks, Y2K bug)
-	Calendar {
-		second,
-		minute,
-		hour,
-		day,
-		month,
-		year
-	}
-}
\ No newline at end of file
+		let last = raw;
+		while rtc.update_in_progress() {}
+		raw = RawRtcTime::read(rtc, century_register);
+		if raw == last {
+			break;
+		}
+	}
+	raw.decode()
+}
+
+/// Sets the RTC to `calendar`, keeping whichever BCD/binary and 12/24-hour format it is already in
+pub fn write_calendar<R: RtcRegisters>(rtc: &mut R, century_register: u8, calendar: &Calendar) -> Result<(), CalendarError> {
+	let status_b = rtc.read_register(RTC_STATUS_B);
+	RawRtcTime::encode(calendar, status_b, century_register != 0)?.write(rtc, century_register);
+	Ok(())
+}
+
+pub fn get_current_time(century_register: u8) -> Calendar {
+	read_calendar(&mut Cmos, century_register)
+}
+
+pub fn set_current_time(century_register: u8, calendar: &Calendar) -> Result<(), CalendarError> {
+	write_calendar(&mut Cmos, century_register, calendar)
+}
+
+/// A monotonic counter that the kernel can keep time with
+pub trait ClockSource: Sync {
+	fn name(&self) -> &'static str;
+	/// Nanoseconds since some fixed point, this must never go backwards
+	fn nanos(&self) -> u64;
+	/// Whether the source only advances while PIT interrupts are delivered
+	fn is_pit(&self) -> bool {
+		false
+	}
+}
+
+/// Counts PIT interrupts, only as precise as the programmed timer frequency
+struct PitClock;
+impl ClockSource for PitClock {
+	fn name(&self) -> &'static str {
+		"pit"
+	}
+	fn nanos(&self) -> u64 {
+		let freq = crate::timer::frequency() as u128;
+		if freq == 0 {
+			return 0;
+		}
+		(crate::timer::ticks() as u128 * 1_000_000_000 / freq) as u64
+	}
+	fn is_pit(&self) -> bool {
+		true
+	}
+}
+
+struct Clock {
+	source: &'static dyn ClockSource,
+	// Value of Instant::now() when `source` was selected
+	offset: u64,
+	// Reading of `source` when it was selected
+	start: u64,
+}
+
+static CLOCK: RwLock<Clock> = RwLock::new(Clock {
+	source: &PitClock,
+	offset: 0,
+	start: 0,
+});
+// The largest value Instant::now() has returned, so that switching sources can't make time go backwards
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+// Unix time in nanoseconds at the point where Instant::now() was 0
+static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Switches the clock that `Instant::now` is based on, time carries on from where the old source left off
+pub fn set_clocksource(source: &'static dyn ClockSource) {
+	let now = Instant::now().0;
+	let mut clock = CLOCK.write();
+	clock.source = source;
+	clock.offset = now;
+	clock.start = source.nanos();
+}
+
+pub fn clocksource_name() -> &'static str {
+	CLOCK.read().source.name()
+}
+
+/// Whether time is kept by counting PIT interrupts, in which case they must not be masked
+pub fn clocksource_is_pit() -> bool {
+	CLOCK.read().source.is_pit()
+}
+
+/// Picks the best available clocksource and anchors wall-clock time to the RTC reading taken at boot
+pub fn init(boot_time: &Calendar) {
+	if crate::tsc::is_invariant() && crate::tsc::frequency() != 0 {
+		set_clocksource(&crate::tsc::TscClock);
+	}
+	else if let Some(hpet) = crate::hpet::get() {
+		set_clocksource(hpet);
+	}
+	let boot_unix_nanos = SystemTime::from_calendar(boot_time).unix_nanos;
+	BOOT_UNIX_NANOS.store(boot_unix_nanos.saturating_sub(Instant::now().0), Ordering::Relaxed);
+}
+
+/// Moves the wall clock so that `SystemTime::now` reads `now`, the monotonic clock is unaffected
+pub fn set_system_time(now: SystemTime) {
+	BOOT_UNIX_NANOS.store(now.unix_nanos.saturating_sub(Instant::now().0), Ordering::Relaxed);
+}
+
+/// A point on the monotonic clock, with nanosecond resolution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+impl Instant {
+	pub fn now() -> Self {
+		let nanos = {
+			let clock = CLOCK.read();
+			clock.offset + clock.source.nanos().wrapping_sub(clock.start)
+		};
+		let mut last = LAST_NANOS.load(Ordering::Relaxed);
+		loop {
+			if nanos <= last {
+				return Instant(last);
+			}
+			match LAST_NANOS.compare_exchange_weak(last, nanos, Ordering::Relaxed, Ordering::Relaxed) {
+				Ok(_) => return Instant(nanos),
+				Err(current) => last = current,
+			}
+		}
+	}
+	/// Nanoseconds since the clock started counting
+	pub fn as_nanos(&self) -> u64 {
+		self.0
+	}
+	/// Returns zero if `earlier` is actually later than `self`
+	pub fn duration_since(&self, earlier: Instant) -> Duration {
+		Duration::from_nanos(self.0.saturating_sub(earlier.0))
+	}
+	pub fn elapsed(&self) -> Duration {
+		Instant::now().duration_since(*self)
+	}
+	pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+		let nanos = u64::try_from(duration.as_nanos()).ok()?;
+		self.0.checked_add(nanos).map(Instant)
+	}
+	pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+		let nanos = u64::try_from(duration.as_nanos()).ok()?;
+		self.0.checked_sub(nanos).map(Instant)
+	}
+}
+impl Add<Duration> for Instant {
+	type Output = Instant;
+	fn add(self, duration: Duration) -> Instant {
+		self.checked_add(duration).expect("overflow when adding duration to instant")
+	}
+}
+impl Sub<Duration> for Instant {
+	type Output = Instant;
+	fn sub(self, duration: Duration) -> Instant {
+		self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+	}
+}
+impl Sub<Instant> for Instant {
+	type Output = Duration;
+	fn sub(self, earlier: Instant) -> Duration {
+		self.duration_since(earlier)
+	}
+}
+
+/// Wall-clock time, stored as nanoseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime {
+	unix_nanos: u64,
+}
+pub const UNIX_EPOCH: SystemTime = SystemTime {unix_nanos: 0};
+
+impl SystemTime {
+	/// The RTC time at boot plus the monotonic time elapsed since
+	pub fn now() -> Self {
+		SystemTime {
+			unix_nanos: BOOT_UNIX_NANOS.load(Ordering::Relaxed) + Instant::now().0,
+		}
+	}
+	pub fn from_unix_timestamp(seconds: u64) -> Self {
+		SystemTime {
+			unix_nanos: seconds * 1_000_000_000,
+		}
+	}
+	/// Whole seconds since the Unix epoch
+	pub fn unix_timestamp(&self) -> u64 {
+		self.unix_nanos / 1_000_000_000
+	}
+	pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+		self.unix_nanos.checked_sub(earlier.unix_nanos).map(Duration::from_nanos)
+	}
+	/// Interprets the calendar date as UTC, invalid dates are clamped to the epoch
+	pub fn from_calendar(calendar: &Calendar) -> Self {
+		SystemTime::from_unix_timestamp(calendar.to_unix_timestamp().unwrap_or(0))
+	}
+	/// Converts to a UTC calendar date
+	pub fn to_calendar(&self) -> Calendar {
+		Calendar::from_unix_timestamp(self.unix_timestamp())
+	}
+}
+impl Add<Duration> for SystemTime {
+	type Output = SystemTime;
+	fn add(self, duration: Duration) -> SystemTime {
+		SystemTime {
+			unix_nanos: self.unix_nanos + duration.as_nanos() as u64,
+		}
+	}
+}
+impl core::fmt::Display for SystemTime {
+	fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+		write!(f, "{}", self.to_calendar())
+	}
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+	let year = if month <= 2 {year - 1} else {year};
+	let era = (if year >= 0 {year} else {year - 399}) / 400;
+	let year_of_era = year - era * 400;
+	let month = month as i64;
+	let day_of_year = (153 * (if month > 2 {month - 3} else {month + 9}) + 2) / 5 + day as i64 - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	era * 146_097 + day_of_era - 719_468
+}
+
+// The inverse of days_from_civil, returns (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+	let days = days + 719_468;
+	let era = (if days >= 0 {days} else {days - 146_096}) / 146_097;
+	let day_of_era = days - era * 146_097;
+	let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+	let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+	let mp = (5 * day_of_year + 2) / 153;
+	let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+	let month = (if mp < 10 {mp + 3} else {mp - 9}) as u32;
+	let year = year_of_era + era * 400 + if month <= 2 {1} else {0};
+	(year, month, day)
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[cfg(test)]
+struct MockRtc {
+	registers: [u8; 0x40],
+}
+#[cfg(test)]
+impl MockRtc {
+	fn new(status_b: u8, values: [u8; 6], century: u8) -> Self {
+		let mut registers = [0; 0x40];
+		registers[RTC_SECONDS as usize] = values[0];
+		registers[RTC_MINUTES as usize] = values[1];
+		registers[RTC_HOURS as usize] = values[2];
+		registers[RTC_DAY as usize] = values[3];
+		registers[RTC_MONTH as usize] = values[4];
+		registers[RTC_YEAR as usize] = values[5];
+		registers[RTC_STATUS_B as usize] = status_b;
+		registers[0x32] = century;
+		MockRtc {registers}
+	}
+}
+#[cfg(test)]
+impl RtcRegisters for MockRtc {
+	fn read_register(&mut self, reg: u8) -> u8 {
+		self.registers[reg as usize]
+	}
+	fn write_register(&mut self, reg: u8, value: u8) {
+		self.registers[reg as usize] = value;
+	}
+}
+
+#[test_case]
+fn test_rtc_decoding() {
+	serial_print!("test_rtc_decoding... ");
+	let expected = Calendar {year: 2021, month: 3, day: 14, hour: 15, minute: 9, second: 26};
+	//BCD, 24-hour, century register
+	let mut rtc = MockRtc::new(0, [0x26, 0x09, 0x15, 0x14, 0x03, 0x21], 0x20);
+	assert_eq!(read_calendar(&mut rtc, 0x32), expected);
+	//binary, 24-hour, no century register
+	let mut rtc = MockRtc::new(STATUS_B_BINARY | STATUS_B_24_HOUR, [26, 9, 15, 14, 3, 21], 0);
+	assert_eq!(read_calendar(&mut rtc, 0), expected);
+	//BCD, 12-hour, 3 PM
+	let mut rtc = MockRtc::new(0, [0x26, 0x09, HOUR_PM | 0x03, 0x14, 0x03, 0x21], 0x20);
+	assert_eq!(read_calendar(&mut rtc, 0x32), expected);
+	//binary, 12-hour, 12 AM is midnight
+	let mut rtc = MockRtc::new(STATUS_B_BINARY, [0, 0, 12, 1, 1, 99], 0);
+	assert_eq!(read_calendar(&mut rtc, 0), Calendar {year: 1999, month: 1, day: 1, hour: 0, minute: 0, second: 0});
+	//binary, 12-hour, 12 PM is noon
+	let mut rtc = MockRtc::new(STATUS_B_BINARY, [0, 0, HOUR_PM | 12, 1, 1, 99], 19);
+	assert_eq!(read_calendar(&mut rtc, 0x32).hour, 12);
+	serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_calendar_conversions() {
+	serial_print!("test_calendar_conversions... ");
+	let leap_day = Calendar {year: 2020, month: 2, day: 29, hour: 23, minute: 59, second: 59};
+	assert_eq!(leap_day.to_unix_timestamp(), Ok(1_583_020_799));
+	assert_eq!(Calendar::from_unix_timestamp(1_583_020_799), leap_day);
+	assert_eq!(leap_day.day_of_week(), Weekday::Saturday);
+	assert_eq!(Calendar::from_unix_timestamp(0).day_of_week(), Weekday::Thursday);
+	assert_eq!(Calendar {day: 29, ..Calendar::from_unix_timestamp(0)}.validate(), Ok(()));
+	assert_eq!(Calendar {year: 2019, ..leap_day}.validate(), Err(CalendarError::Day));
+	assert_eq!(Calendar {hour: 24, ..leap_day}.validate(), Err(CalendarError::Hour));
+	assert_eq!(Calendar {month: 13, ..leap_day}.validate(), Err(CalendarError::Month));
+	serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_rtc_write_round_trip() {
+	serial_print!("test_rtc_write_round_trip... ");
+	let calendar = Calendar {year: 2024, month: 12, day: 31, hour: 0, minute: 30, second: 5};
+	for &status_b in &[0, STATUS_B_BINARY, STATUS_B_24_HOUR, STATUS_B_BINARY | STATUS_B_24_HOUR] {
+		for &century_register in &[0, 0x32] {
+			let mut rtc = MockRtc::new(status_b, [0; 6], 0);
+			write_calendar(&mut rtc, century_register, &calendar).unwrap();
+			assert_eq!(rtc.read_register(RTC_STATUS_B), status_b);
+			assert_eq!(read_calendar(&mut rtc, century_register), calendar);
+		}
+	}
+	let mut rtc = MockRtc::new(0, [0; 6], 0);
+	assert_eq!(write_calendar(&mut rtc, 0, &Calendar {year: 2070, ..calendar}), Err(CalendarError::Year));
+	serial_println!("[ok]");
+}
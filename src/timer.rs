@@ -1,14 +1,130 @@
 use x86_64::instructions::port::Port;
-use crate::println;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+use crate::{apic_timer, println, time};
+
+const PIT_FREQUENCY: u32 = 1_193_182;
+
+// Number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// The frequency the PIT was actually programmed to, 0 until set_interrupt_freq is called
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+// Set once the PIT interrupt is masked and ticks are derived from the clocksource instead
+static TICKLESS: AtomicBool = AtomicBool::new(false);
+
 ///Set the hardware timer to interrupt every `freq` Hz
 pub fn set_interrupt_freq(freq: u32) {
 	let mut command_port = Port::new(0x43);
 	let mut port = Port::new(0x40);
-	let divisor = 1_193_182 / freq;
-	println!("Setting timer divisor to {} ({}Hz)", divisor as u16, (1_193_182 / divisor as u32) as u16);
+	let divisor = PIT_FREQUENCY / freq;
+	println!("Setting timer divisor to {} ({}Hz)", divisor as u16, (PIT_FREQUENCY / divisor as u32) as u16);
+	FREQUENCY.store(PIT_FREQUENCY / divisor, Ordering::Relaxed);
 	unsafe {
 		command_port.write(0x36u8);
 		port.write((divisor & 0xFF) as u8);
 		port.write((divisor >> 8) as u8);
 	}
-}
\ No newline at end of file
+}
+
+/// Busy-waits for `ms` milliseconds on PIT channel 2, channel 0 keeps driving the timer interrupt
+///
+/// Used to calibrate other timers when there is no HPET.
+pub fn pit_delay(ms: u64) {
+	let mut speaker_port = Port::<u8>::new(0x61);
+	let mut command_port = Port::<u8>::new(0x43);
+	let mut channel2_port = Port::<u8>::new(0x42);
+	let count = PIT_FREQUENCY as u64 * ms / 1000;
+	assert!(count <= 0xFFFF, "PIT delays are limited to about 54ms");
+	unsafe {
+		//raise the channel 2 gate, with the speaker output disabled
+		let speaker = speaker_port.read();
+		speaker_port.write((speaker & !0b10) | 0b01);
+		//channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+		command_port.write(0b1011_0000);
+		channel2_port.write((count & 0xFF) as u8);
+		channel2_port.write((count >> 8) as u8);
+		//bit 5 is channel 2's output, which goes high once the count reaches zero
+		while speaker_port.read() & 0x20 == 0 {}
+		speaker_port.write(speaker);
+	}
+}
+
+/// Called by the timer interrupt handler
+/// # Safety
+/// Must not block or allocate memory on the heap
+pub(crate) fn tick() {
+	TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts since boot, this never goes backwards
+///
+/// Once tickless, this is the number of ticks that would have happened at the same frequency.
+pub fn ticks() -> u64 {
+	if !TICKLESS.load(Ordering::Relaxed) {
+		return TICKS.load(Ordering::Relaxed);
+	}
+	let derived = (time::Instant::now().as_nanos() as u128 * frequency() as u128 / 1_000_000_000) as u64;
+	let mut last = TICKS.load(Ordering::Relaxed);
+	loop {
+		if derived <= last {
+			return last;
+		}
+		match TICKS.compare_exchange_weak(last, derived, Ordering::Relaxed, Ordering::Relaxed) {
+			Ok(_) => return derived,
+			Err(current) => last = current,
+		}
+	}
+}
+
+/// Returns the tick frequency in Hz, or 0 if the timer has not been programmed yet
+pub fn frequency() -> u32 {
+	FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Masks the PIT interrupt, so that idle CPUs only wake up when a timer is due
+///
+/// Requires a calibrated local APIC timer and a clocksource other than the PIT, returns whether it was enabled.
+pub fn enable_tickless() -> bool {
+	if frequency() == 0 || apic_timer::frequency() == 0 || time::clocksource_is_pit() {
+		return false;
+	}
+	TICKLESS.store(true, Ordering::Relaxed);
+	x86_64::instructions::interrupts::without_interrupts(|| {
+		let mut master_mask = Port::<u8>::new(0x21);
+		unsafe {
+			let mask = master_mask.read();
+			master_mask.write(mask | 1);
+		}
+	});
+	true
+}
+
+pub fn is_tickless() -> bool {
+	TICKLESS.load(Ordering::Relaxed)
+}
+
+/// Programs the calling CPU's local APIC timer to fire at tick `deadline`, or stops it for None
+///
+/// Returns false if the deadline has already passed, in which case the CPU must not halt.
+/// Does nothing while the PIT is still ticking, since its next interrupt will wake the CPU anyway.
+pub(crate) fn arm_wakeup(deadline: Option<u64>) -> bool {
+	if !is_tickless() {
+		return true;
+	}
+	let deadline = match deadline {
+		Some(deadline) => deadline,
+		None => {
+			apic_timer::stop();
+			return true;
+		}
+	};
+	//the first nanosecond at which ticks() reaches the deadline
+	let freq = frequency() as u128;
+	let deadline_nanos = ((deadline as u128 * 1_000_000_000 + freq - 1) / freq) as u64;
+	let now = time::Instant::now().as_nanos();
+	if deadline_nanos <= now {
+		return false;
+	}
+	apic_timer::wake_after(Duration::from_nanos(deadline_nanos - now));
+	true
+}
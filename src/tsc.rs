@@ -0,0 +1,116 @@
+use crate::{hpet, println, time::ClockSource, timer};
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// TSC ticks per second, 0 until `init` has run
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+// How long the PIT and HPET calibrations measure for
+const CALIBRATION_MS: u64 = 10;
+
+/// Where the TSC frequency came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrequencySource {
+	/// CPUID leaf 0x15, the crystal clock ratio
+	CpuidCrystal,
+	/// CPUID leaf 0x16, the processor base frequency
+	CpuidBase,
+	Hpet,
+	Pit,
+}
+
+/// Reads the time stamp counter
+pub fn read() -> u64 {
+	unsafe {_rdtsc()}
+}
+
+/// TSC ticks per second, or 0 if the TSC has not been calibrated
+pub fn frequency() -> u64 {
+	FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Whether the TSC ticks at a constant rate in every P-, C- and T-state
+pub fn is_invariant() -> bool {
+	INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Converts a number of TSC ticks to nanoseconds, returns 0 before calibration
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+	let freq = frequency();
+	if freq == 0 {
+		return 0;
+	}
+	(cycles as u128 * 1_000_000_000 / freq as u128) as u64
+}
+
+/// Nanoseconds since the TSC was reset, which is usually when the CPU was powered on
+pub fn nanos() -> u64 {
+	cycles_to_nanos(read())
+}
+
+/// Determines the TSC frequency and whether it is invariant
+pub fn init() {
+	INVARIANT.store(detect_invariant(), Ordering::Relaxed);
+	let (freq, source) = match frequency_from_cpuid() {
+		Some(found) => found,
+		None => match hpet::get() {
+			Some(hpet) => (calibrate_with_hpet(hpet), FrequencySource::Hpet),
+			None => (calibrate_with_pit(), FrequencySource::Pit),
+		},
+	};
+	FREQUENCY.store(freq, Ordering::Relaxed);
+	println!("TSC: {} Hz from {:?}, invariant: {}", freq, source, is_invariant());
+}
+
+fn detect_invariant() -> bool {
+	let max_extended_leaf = unsafe {__cpuid(0x8000_0000)}.eax;
+	if max_extended_leaf < 0x8000_0007 {
+		return false;
+	}
+	unsafe {__cpuid(0x8000_0007)}.edx & (1 << 8) != 0
+}
+
+fn frequency_from_cpuid() -> Option<(u64, FrequencySource)> {
+	let max_leaf = unsafe {__cpuid(0)}.eax;
+	if max_leaf >= 0x15 {
+		let leaf = unsafe {__cpuid_count(0x15, 0)};
+		//eax is the denominator and ebx the numerator of the TSC/crystal ratio, ecx the crystal frequency
+		if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+			let freq = leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64;
+			return Some((freq, FrequencySource::CpuidCrystal));
+		}
+	}
+	if max_leaf >= 0x16 {
+		let base_mhz = unsafe {__cpuid_count(0x16, 0)}.eax & 0xFFFF;
+		if base_mhz != 0 {
+			return Some((base_mhz as u64 * 1_000_000, FrequencySource::CpuidBase));
+		}
+	}
+	None
+}
+
+fn calibrate_with_hpet(hpet: &hpet::Hpet) -> u64 {
+	let tsc_start = read();
+	let elapsed_ns = hpet.delay(CALIBRATION_MS * 1_000_000);
+	let tsc_end = read();
+	((tsc_end - tsc_start) as u128 * 1_000_000_000 / elapsed_ns as u128) as u64
+}
+
+fn calibrate_with_pit() -> u64 {
+	let tsc_start = read();
+	timer::pit_delay(CALIBRATION_MS);
+	let tsc_end = read();
+	(tsc_end - tsc_start) * 1000 / CALIBRATION_MS
+}
+
+/// The invariant TSC as a clocksource, it is the cheapest to read
+pub struct TscClock;
+impl ClockSource for TscClock {
+	fn name(&self) -> &'static str {
+		"tsc"
+	}
+	fn nanos(&self) -> u64 {
+		nanos()
+	}
+}